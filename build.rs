@@ -24,119 +24,4634 @@ extern crate md5;
 extern crate curl;
 extern crate bzip2;
 extern crate tar;
+extern crate cc;
+extern crate serde_json;
+extern crate zip;
+extern crate zstd;
+extern crate object;
+extern crate toml;
 
-use curl::easy::Easy;
+use curl::easy::{Easy, HttpVersion};
 use bzip2::read::BzDecoder;
 use tar::Archive;
+use object::read::archive::ArchiveFile;
+use object::read::coff::ImportFile;
+use object::read::{ReadCache, ReadCacheRange, ReadRef};
+use object::{elf, Architecture, BinaryFormat, FileKind, Object, ObjectSection, RelocationFlags, SectionFlags};
 
 use std::env::var;
 use std::path::*;
 use std::fs::{self, File};
-use std::io::*;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::process::Command;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
 
 
 // Use `conda search --json --platform 'win-64' mkl-static`
 // to query the metadata of conda package (includes MD5 sum).
 
-#[cfg(target_os = "linux")]
-mod mkl {
-    pub const LIB_PATH: &'static str = "lib";
+const CHANNEL: &str = "https://conda.anaconda.org/intel";
+const DEFAULT_VERSION: &str = "2019.1";
 
-    pub const DLS: &[(&'static str, &'static str, &'static str)] = &[
-        ("mkl-static-2019.1-intel_144.tar.bz2", 
-         "https://conda.anaconda.org/intel/linux-64/mkl-static-2019.1-intel_144.tar.bz2",
-         "37e3a60ff2643cf40b5cf9d2c183588c")
+/// Layout of the mkl-static packages for a platform
+#[derive(Debug)]
+pub struct Platform {
+    /// `target_os` of the platform
+    pub name: &'static str,
+    /// Directory of the packages in the channel
+    pub subdir: &'static str,
+    pub lib_path: &'static str,
+    pub include_path: &'static str,
+    /// (version, build string, MD5 sum) of mkl-static packages
+    pub builds: &'static [(&'static str, &'static str, &'static str)],
+    // Libraries are placed as `lib_path/{lib_prefix}{name}.{lib_ext}` in the archive,
+    // and extracted into `lib_path()`
+    pub lib_prefix: &'static str,
+    pub lib_ext: &'static str,
+}
+
+pub const PLATFORMS: &[Platform] = &[
+    Platform {
+        name: "linux",
+        subdir: "linux-64",
+        lib_path: "lib",
+        include_path: "include",
+        builds: &[("2019.1", "intel_144", "37e3a60ff2643cf40b5cf9d2c183588c")],
+        lib_prefix: "lib",
+        lib_ext: "a",
+    },
+    Platform {
+        name: "macos",
+        subdir: "osx-64",
+        lib_path: "lib",
+        include_path: "include",
+        builds: &[("2019.1", "intel_144", "74a186a5e325146c7de7e1e1c8fc3bc3")],
+        lib_prefix: "lib",
+        lib_ext: "a",
+    },
+    Platform {
+        name: "windows",
+        subdir: "win-64",
+        lib_path: "Library\\lib",
+        include_path: "Library\\include",
+        builds: &[("2019.1", "intel_144", "0b65a55b6bcda83392e9defff8e1edbe")],
+        lib_prefix: "",
+        lib_ext: "lib",
+    },
+];
+
+impl Platform {
+    /// Find the platform by `target_os`, e.g. `linux`, or by the subdir, e.g. `linux-64`
+    pub fn find(name: &str) -> Option<&'static Platform> {
+        PLATFORMS.iter().find(|p| p.name == name || p.subdir == name)
+    }
+}
+
+// Subdirs of conda channels accepted by MKL_CONDA_SUBDIR
+const CONDA_SUBDIRS: &[&str] = &[
+    "linux-64",
+    "linux-aarch64",
+    "linux-ppc64le",
+    "osx-64",
+    "osx-arm64",
+    "win-64",
+    "noarch",
+];
+
+/// Base URL of the channel, i.e. MKL_MIRROR_BASE if set, e.g. `https://mirror.example.com/conda/intel`
+pub fn channel() -> &'static str {
+    static BASE: OnceLock<String> = OnceLock::new();
+    BASE.get_or_init(|| mirror_base().map_or_else(|| CHANNEL.to_string(), |base| base.trim_end_matches('/').to_string()))
+}
+
+/// MKL_MIRROR_BASE, a mirror of the channel in the same layout
+fn mirror_base() -> Option<String> {
+    env_var("MKL_MIRROR_BASE").filter(|base| !base.is_empty())
+}
+
+/// Whether the TLS certificate of `uri` is not verified, i.e. MKL_INSECURE_TLS for a URI under MKL_MIRROR_BASE
+///
+/// It never applies to the default channel, even if MKL_MIRROR_BASE is the same.
+pub fn insecure_tls(uri: &str, mirror_base: Option<&str>, insecure: bool) -> bool {
+    let mirror = match mirror_base {
+        Some(base) if insecure => base.trim_end_matches('/'),
+        _ => return false,
+    };
+    !uri.starts_with(CHANNEL) && uri.strip_prefix(mirror).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Subdir of the channel where the packages are downloaded from, i.e. `Platform::subdir`
+/// unless MKL_CONDA_SUBDIR is set, e.g. for a mirror with another layout
+pub fn conda_subdir() -> &'static str {
+    static SUBDIR: OnceLock<&'static str> = OnceLock::new();
+    SUBDIR.get_or_init(|| match env_var("MKL_CONDA_SUBDIR") {
+        Some(subdir) => CONDA_SUBDIRS
+            .iter()
+            .find(|s| **s == subdir)
+            .unwrap_or_else(|| {
+                panic!(
+                    "MKL_CONDA_SUBDIR={} is not a subdir of conda. Use one of {}",
+                    subdir,
+                    CONDA_SUBDIRS.join(", ")
+                )
+            }),
+        None => platform().subdir,
+    })
+}
+
+/// Directory of the extracted libraries, `lib` on every platform
+///
+/// MKL_CONDA_LAYOUT keeps the layout of the conda package, i.e. `Library\\lib` on Windows.
+pub fn lib_path() -> &'static str {
+    static CONDA_LAYOUT: OnceLock<bool> = OnceLock::new();
+    if *CONDA_LAYOUT.get_or_init(|| env_flag("MKL_CONDA_LAYOUT")) {
+        platform().lib_path
+    } else {
+        "lib"
+    }
+}
+
+/// Platform whose libraries are provisioned
+///
+/// MKL_TARGET_OVERRIDE selects another platform than the target, e.g. to prepare a bundle for it.
+pub fn platform() -> &'static Platform {
+    static PLATFORM: OnceLock<&'static Platform> = OnceLock::new();
+    PLATFORM.get_or_init(|| {
+        let target_override = env_var("MKL_TARGET_OVERRIDE");
+        let target_os = var("CARGO_CFG_TARGET_OS").ok();
+        select_platform(target_override.as_deref(), target_os.as_deref()).unwrap_or_else(|e| panic!("{}", e))
+    })
+}
+
+/// Platform of the archive, by MKL_TARGET_OVERRIDE or CARGO_CFG_TARGET_OS
+///
+/// The build script itself runs on the host, and only the target selects the libraries when cross-compiling.
+/// The host is used without CARGO_CFG_TARGET_OS, e.g. in the tests of build.rs.
+pub fn select_platform(target_override: Option<&str>, target_os: Option<&str>) -> Result<&'static Platform, String> {
+    if let Some(name) = target_override {
+        return Platform::find(name).ok_or_else(|| {
+            let names: Vec<_> = PLATFORMS.iter().map(|p| p.name).collect();
+            format!("MKL_TARGET_OVERRIDE={} is not supported. Use one of {}", name, names.join(", "))
+        });
+    }
+    let target_os = target_os.unwrap_or(std::env::consts::OS);
+    Platform::find(target_os).ok_or_else(|| format!("MKL is not available for {}", target_os))
+}
+
+/// Compare versions of MKL by numbers, e.g. `2021.4 > 2020.4`. `None` if either is not numbers separated by `.`
+///
+/// Missing components are zeros, i.e. `2021.4 == 2021.4.0`.
+pub fn compare_versions(a: &str, b: &str) -> Option<std::cmp::Ordering> {
+    let parse = |v: &str| -> Option<Vec<u32>> {
+        let mut numbers: Vec<u32> = v.split('.').map(|n| n.parse().ok()).collect::<Option<_>>()?;
+        while numbers.last() == Some(&0) {
+            numbers.pop();
+        }
+        Some(numbers)
+    };
+    Some(parse(a)?.cmp(&parse(b)?))
+}
+
+/// Fail if `version` to be provisioned is older than MKL_MIN_VERSION
+fn check_min_version(version: &str) {
+    let min = match env_var("MKL_MIN_VERSION") {
+        Some(min) => min,
+        None => return,
+    };
+    match compare_versions(version, &min) {
+        Some(std::cmp::Ordering::Less) => panic!(
+            "MKL {} is older than MKL_MIN_VERSION={}. Set MKL_VERSION to {} or newer.",
+            version, min, min
+        ),
+        Some(_) => {}
+        None => panic!("Cannot compare MKL {} with MKL_MIN_VERSION={}", version, min),
+    }
+}
+
+/// mkl-static package in the conda channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Package {
+    pub version: String,
+    /// Build string, e.g. `intel_144`. `None` is resolved to the latest build using `repodata.json`
+    pub build: Option<String>,
+    /// `None` is resolved using `repodata.json`
+    pub md5: Option<String>,
+    /// URL of the archive given by MKL_MANIFEST_URL. `None` is the channel
+    pub url: Option<String>,
+    /// Format of the archive, `.tar.bz2` or `.conda`
+    pub extension: &'static str,
+}
+
+impl Package {
+    /// Select the package by `MKL_VERSION` and `MKL_BUILD_STRING`
+    ///
+    /// The checksums of `Platform::builds` are built-in, and others are taken from `repodata.json`.
+    pub fn select(version: Option<&str>, build: Option<&str>) -> Self {
+        let version = version.unwrap_or(DEFAULT_VERSION);
+        let known = platform().builds
+            .iter()
+            .find(|(v, b, _)| *v == version && build.is_none_or(|build| build == *b));
+        match known {
+            Some((_, build, md5)) => Package {
+                version: version.to_string(),
+                build: Some(build.to_string()),
+                md5: Some(md5.to_string()),
+                url: None,
+                extension: ".tar.bz2",
+            },
+            None => Package {
+                version: version.to_string(),
+                build: build.map(str::to_string),
+                md5: None,
+                url: None,
+                extension: ".tar.bz2",
+            },
+        }
+    }
+
+    /// Archive name, e.g. `mkl-static-2019.1-intel_144.tar.bz2`. Panics if the build is not resolved.
+    pub fn filename(&self) -> String {
+        format!("mkl-static-{}-{}{}", self.version, self.build.as_ref().unwrap(), self.extension)
+    }
+
+    pub fn uri(&self) -> String {
+        match self.url {
+            Some(ref url) => url.clone(),
+            None => format!("{}/{}/{}", channel(), conda_subdir(), self.filename()),
+        }
+    }
+
+    /// Select the package of `version` listed in the manifest of MKL_MANIFEST_URL, see `fetch_manifest()`
+    ///
+    /// Returns `Ok(None)` if the version is not listed, or `build` differs from the listed one.
+    /// The entry must contain all of `files`, if it lists the files of the archive.
+    pub fn from_manifest(
+        manifest: &serde_json::Value,
+        version: &str,
+        build: Option<&str>,
+        files: &[PathBuf],
+    ) -> Result<Option<Self>, String> {
+        let entry = &manifest[conda_subdir()][version];
+        if entry.is_null() {
+            return Ok(None);
+        }
+        let field = |key: &str| {
+            entry[key]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("`{}` of MKL {} is missing in the manifest", key, version))
+        };
+        let listed = field("build")?;
+        if build.is_some_and(|build| build != listed) {
+            return Ok(None);
+        }
+        if let Some(listed_files) = entry["files"].as_array() {
+            let missing: Vec<_> = files
+                .iter()
+                .map(|file| manifest_path(file))
+                .filter(|file| !listed_files.iter().any(|f| f.as_str() == Some(file.as_str())))
+                .collect();
+            if !missing.is_empty() {
+                return Err(format!("MKL {} in the manifest does not contain {}", version, missing.join(", ")));
+            }
+        }
+        let url = field("url")?;
+        Ok(Some(Package {
+            version: version.to_string(),
+            build: Some(listed),
+            md5: Some(field("md5")?),
+            extension: archive_extension(&url),
+            url: Some(url),
+        }))
+    }
+}
+
+/// Format of the archive at `url` by its name, `.conda` or `.tar.bz2` otherwise
+pub fn archive_extension(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    if path.ends_with(".conda") {
+        ".conda"
+    } else {
+        ".tar.bz2"
+    }
+}
+
+// Both of interface and threading layer accept only one feature.
+// Since cargo unifies features, these errors may be caused by another crate in the dependency graph.
+macro_rules! exclusive_features {
+    ($a:tt, $b:tt) => {
+        #[cfg(all(feature = $a, feature = $b))]
+        compile_error!(concat!(
+            "Features `", $a, "` and `", $b, "` of intel-mkl-src cannot be enabled at the same time. ",
+            "Enable only one of them, or none to use the default."
+        ));
+    };
+}
+
+exclusive_features!("lp64", "ilp64");
+// Conflicts of the threading features are checked in `MklConfig::from_features`,
+// since MKL_FORCE_SEQUENTIAL overrides them
+
+/// Integer width of the interface layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    /// 32-bit integers
+    LP64,
+    /// 64-bit integers
+    ILP64,
+}
+
+impl Interface {
+    /// Interface of the feature name, e.g. `ilp64`
+    pub fn parse(name: &str) -> Option<Self> {
+        [Interface::LP64, Interface::ILP64].iter().copied().find(|interface| interface.name() == name)
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Interface::LP64 => "lp64",
+            Interface::ILP64 => "ilp64",
+        }
+    }
+
+    fn library(&self) -> &'static str {
+        match *self {
+            Interface::LP64 => "mkl_intel_lp64",
+            Interface::ILP64 => "mkl_intel_ilp64",
+        }
+    }
+}
+
+/// Threading layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threading {
+    Sequential,
+    IntelOpenMP,
+    GnuOpenMP,
+    Tbb,
+}
+
+impl Threading {
+    /// Threading layer of the feature name, e.g. `intel-openmp`
+    pub fn parse(name: &str) -> Option<Self> {
+        [Threading::Sequential, Threading::IntelOpenMP, Threading::GnuOpenMP, Threading::Tbb]
+            .iter()
+            .copied()
+            .find(|threading| threading.feature() == name)
+    }
+
+    fn feature(&self) -> &'static str {
+        match *self {
+            Threading::Sequential => "sequential",
+            Threading::IntelOpenMP => "intel-openmp",
+            Threading::GnuOpenMP => "gnu-openmp",
+            Threading::Tbb => "tbb",
+        }
+    }
+
+    fn library(&self) -> &'static str {
+        match *self {
+            Threading::Sequential => "mkl_sequential",
+            Threading::IntelOpenMP => "mkl_intel_thread",
+            Threading::GnuOpenMP => "mkl_gnu_thread",
+            Threading::Tbb => "mkl_tbb_thread",
+        }
+    }
+
+    /// Threading runtime which is not included in the archive, and must be provided by the system
+    fn runtime(&self, target_os: &str) -> Option<&'static str> {
+        match *self {
+            Threading::Sequential => None,
+            Threading::IntelOpenMP if target_os == "windows" => Some("libiomp5md"),
+            Threading::IntelOpenMP => Some("iomp5"),
+            Threading::GnuOpenMP => Some("gomp"),
+            Threading::Tbb => Some("tbb"),
+        }
+    }
+}
+
+/// Configuration of MKL selected by the crate features
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MklConfig {
+    pub interface: Interface,
+    pub threading: Threading,
+    /// Link the single dynamic library `mkl_rt`, where the layers are selected at runtime.
+    /// `interface` and `threading` are the defaults applied by `runtime::init()` then.
+    pub rt: bool,
+    /// Link the Intel OpenMP runtime statically, taken from the intel-openmp package
+    pub static_openmp: bool,
+    /// Drop the unreferenced sections at link time, see feature `blas-only`
+    pub blas_only: bool,
+}
+
+impl MklConfig {
+    /// Static linking of the layers
+    pub fn new(interface: Interface, threading: Threading) -> Self {
+        MklConfig {
+            interface,
+            threading,
+            rt: false,
+            static_openmp: false,
+            blas_only: false,
+        }
+    }
+
+    /// Same layers with the sequential threading layer, see MKL_THREADING_FALLBACK
+    pub fn sequential(&self) -> Self {
+        MklConfig {
+            threading: Threading::Sequential,
+            static_openmp: false,
+            ..self.clone()
+        }
+    }
+
+    /// Read the features. `force_sequential` ignores the threading features, see MKL_FORCE_SEQUENTIAL.
+    pub fn from_features(force_sequential: bool) -> Self {
+        let interface = if cfg!(feature = "ilp64") {
+            Interface::ILP64
+        } else {
+            Interface::LP64
+        };
+        let enabled: Vec<Threading> = [
+            (cfg!(feature = "sequential"), Threading::Sequential),
+            (cfg!(feature = "intel-openmp"), Threading::IntelOpenMP),
+            (cfg!(feature = "gnu-openmp"), Threading::GnuOpenMP),
+            (cfg!(feature = "tbb"), Threading::Tbb),
+        ]
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, threading)| *threading)
+        .collect();
+        let threading = match enabled.as_slice() {
+            _ if force_sequential => Threading::Sequential,
+            [] => Threading::Sequential,
+            [threading] => *threading,
+            _ => {
+                let names: Vec<_> = enabled.iter().map(|t| format!("`{}`", t.feature())).collect();
+                panic!(
+                    "Features {} of intel-mkl-src cannot be enabled at the same time. \
+                     Enable only one of them, or none to use the default. \
+                     MKL_FORCE_SEQUENTIAL=1 ignores them and uses `sequential`.",
+                    names.join(", ")
+                );
+            }
+        };
+        MklConfig {
+            interface,
+            threading,
+            rt: cfg!(feature = "rt"),
+            // The sequential layer does not use OpenMP
+            static_openmp: cfg!(feature = "static-openmp") && !force_sequential,
+            blas_only: cfg!(feature = "blas-only"),
+        }
+    }
+
+    /// Select the layers of MKL_INTERFACE and MKL_THREADING, or `interface` and `threading` of build.mkl.toml,
+    /// unless the features or MKL_FORCE_SEQUENTIAL already do, which are `selected` as (interface, threading)
+    pub fn with_defaults(&self, interface: Option<&str>, threading: Option<&str>, selected: (bool, bool)) -> Result<Self, String> {
+        let mut config = self.clone();
+        if let Some(name) = interface {
+            let interface = Interface::parse(name).ok_or_else(|| format!("Unknown interface `{}`, expected `lp64` or `ilp64`", name))?;
+            if selected.0 && interface != config.interface {
+                return Err(format!(
+                    "Interface `{}` of MKL_INTERFACE or build.mkl.toml conflicts with feature `{}`",
+                    name,
+                    config.interface.name()
+                ));
+            }
+            config.interface = interface;
+        }
+        if let Some(name) = threading {
+            let threading = Threading::parse(name).ok_or_else(|| format!("Unknown threading `{}`, expected the name of the feature, e.g. `intel-openmp`", name))?;
+            if selected.1 && threading != config.threading {
+                return Err(format!(
+                    "Threading `{}` of MKL_THREADING or build.mkl.toml conflicts with `{}` selected by the features",
+                    name,
+                    config.threading.feature()
+                ));
+            }
+            config.threading = threading;
+        }
+        Ok(config)
+    }
+
+    /// Libraries to be linked, in link order
+    pub fn libraries(&self) -> Vec<&'static str> {
+        if self.rt {
+            return vec!["mkl_rt"];
+        }
+        vec![self.interface.library(), self.threading.library(), "mkl_core"]
+    }
+
+    /// Kind of libraries for `cargo:rustc-link-lib`
+    pub fn link_kind(&self) -> &'static str {
+        if self.rt {
+            "dylib"
+        } else {
+            "static"
+        }
+    }
+
+    /// Check the combinations which depend on the target, not only on the features
+    fn validate(&self, target_os: &str) {
+        if self.threading == Threading::GnuOpenMP && target_os != "linux" {
+            panic!(
+                "Feature `{}` of intel-mkl-src is supported only on Linux, but the target is {}. \
+                 Use `intel-openmp` or `tbb` instead.",
+                self.threading.feature(),
+                target_os
+            );
+        }
+        if self.static_openmp {
+            if self.threading != Threading::IntelOpenMP {
+                panic!(
+                    "Feature `static-openmp` of intel-mkl-src requires `intel-openmp`, but the threading layer is `{}`",
+                    self.threading.feature()
+                );
+            }
+            if self.rt {
+                panic!("Feature `static-openmp` of intel-mkl-src cannot be used with `rt`, which loads the runtime by itself");
+            }
+            if target_os == "windows" {
+                panic!("Feature `static-openmp` of intel-mkl-src is not supported on Windows, where only libiomp5md.dll exists");
+            }
+        }
+        if self.blas_only && self.rt {
+            panic!("Feature `blas-only` of intel-mkl-src cannot be used with `rt`, since mkl_rt is loaded as a whole at runtime");
+        }
+    }
+
+    /// Static Intel OpenMP runtime extracted from the intel-openmp package if `static_openmp`
+    pub fn openmp_files(&self) -> Vec<PathBuf> {
+        if !self.static_openmp {
+            return Vec::new();
+        }
+        vec![Path::new(lib_path()).join(format!("{}iomp5.{}", platform().lib_prefix, platform().lib_ext))]
+    }
+}
+
+/// Check that MKL is available for the target
+///
+/// On macOS, rustc already passes `-arch x86_64` to the linker for x86_64 targets.
+/// For other architectures, e.g. arm64 slice of a universal binary, MKL does not exist at all.
+pub fn validate_target(target_os: &str, target_arch: &str, pointer_width: &str) {
+    // The packages contain only the intel64 libraries, which cannot be linked into a 32-bit binary
+    if target_arch == "x86" || pointer_width == "32" {
+        panic!(
+            "32-bit targets are not supported by intel-mkl-src, but the target is {} with {}-bit pointers. \
+             Build for x86_64, e.g. x86_64-unknown-linux-gnu instead of i686-unknown-linux-gnu.",
+            target_arch, pointer_width
+        );
+    }
+    if target_os == "macos" && target_arch != "x86_64" {
+        panic!(
+            "MKL is available only for x86_64 on macOS, but the target architecture is {}. \
+             Build for x86_64-apple-darwin, and do not include MKL in a universal binary.",
+            target_arch
+        );
+    }
+}
+
+/// Files to be extracted from the archive for the configuration, in link order
+///
+/// All interface and threading layers are included in the `mkl-static` archive,
+/// but only the libraries of the selected layers are extracted and verified.
+pub fn mkl_files(config: &MklConfig) -> Vec<PathBuf> {
+    platform_files(platform(), lib_path(), config)
+}
+
+/// Files of `config` in the archive of `platform`, extracted into `lib_path`, see `mkl_files()`
+pub fn platform_files(platform: &Platform, lib_path: &str, config: &MklConfig) -> Vec<PathBuf> {
+    config
+        .libraries()
+        .iter()
+        .map(|name| Path::new(lib_path).join(format!("{}{}.{}", platform.lib_prefix, name, platform.lib_ext)))
+        .collect()
+}
+
+/// Errors in provisioning MKL
+#[derive(Debug)]
+pub enum BuildError {
+    Io(io::Error),
+    Curl(curl::Error),
+    /// Response other than 200 OK
+    HttpStatus { uri: String, code: u32 },
+    /// MD5 sum of the downloaded archive does not match
+    Checksum { path: PathBuf, expected: String, actual: String },
+    /// Redirected more than MKL_MAX_REDIRECTS times, e.g. by a loop of a mirror
+    TooManyRedirects { uri: String, max: u32 },
+    /// The version or build is not listed in `repodata.json`
+    PackageNotFound { version: String, build: Option<String> },
+    /// Redirected to a host not allowed by MKL_REDIRECT_HOSTS
+    UnexpectedHost { uri: String, url: String },
+    /// Redirected while MKL_NO_REDIRECT is set
+    Redirected { uri: String, location: String },
+    /// Entry of the version in MKL_MANIFEST_URL is broken
+    Manifest { url: String, reason: String },
+    /// Extracted libraries are built for another machine, see `check_arch()`
+    Architecture(String),
+    /// `info/index.json` of the archive names another package, see MKL_VERIFY_INDEX
+    PackageIndex(String),
+    /// The linked libcurl is built without the protocol of the URL, e.g. HTTPS on a minimal system
+    UnsupportedProtocol { uri: String, protocol: String },
+    /// The artifact of MKL_OCI_REF does not contain the archive
+    Oci { reference: String, reason: String },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::Io(ref e) => write!(f, "I/O error: {}", e),
+            BuildError::Curl(ref e) => write!(f, "Download failed: {}", e),
+            BuildError::HttpStatus { ref uri, code } => {
+                write!(f, "Unexpected response code {} for {}", code, uri)
+            }
+            BuildError::TooManyRedirects { ref uri, max } => write!(
+                f,
+                "{} is redirected more than {} times. Check the mirror, or increase MKL_MAX_REDIRECTS",
+                uri, max
+            ),
+            BuildError::Checksum {
+                ref path,
+                ref expected,
+                ref actual,
+            } => write!(
+                f,
+                "check sum of {} is incorrect: md5sum={}, expected={}",
+                path.display(),
+                actual,
+                expected
+            ),
+            BuildError::PackageNotFound {
+                ref version,
+                ref build,
+            } => write!(
+                f,
+                "mkl-static {} {} is not found in the channel",
+                version,
+                build.as_ref().map_or("", |b| b.as_str())
+            ),
+            BuildError::UnexpectedHost { ref uri, ref url } => write!(
+                f,
+                "{} is redirected to {}, whose host is not allowed by MKL_REDIRECT_HOSTS",
+                uri, url
+            ),
+            BuildError::Redirected { ref uri, ref location } => write!(
+                f,
+                "{} is redirected to {}, which is not followed since MKL_NO_REDIRECT is set",
+                uri, location
+            ),
+            BuildError::Manifest { ref url, ref reason } => write!(f, "MKL_MANIFEST_URL {}: {}", url, reason),
+            BuildError::Architecture(ref reason) => f.write_str(reason),
+            BuildError::PackageIndex(ref reason) => f.write_str(reason),
+            BuildError::UnsupportedProtocol { ref uri, ref protocol } => write!(
+                f,
+                "libcurl {} linked to the build script does not support {}, needed for {}. \
+                 Link a libcurl built with it, e.g. by the `static-curl` and `ssl` features of the curl crate, \
+                 or provide the archive by MKL_OFFLINE_ARCHIVE_DIR, or MKL itself by MKL_LIB_DIR",
+                curl::Version::get().version(),
+                protocol,
+                uri
+            ),
+            BuildError::Oci { ref reference, ref reason } => write!(f, "MKL_OCI_REF {}: {}", reference, reason),
+        }
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(e: io::Error) -> Self {
+        BuildError::Io(e)
+    }
+}
+
+impl From<curl::Error> for BuildError {
+    fn from(e: curl::Error) -> Self {
+        BuildError::Curl(e)
+    }
+}
+
+// Default of MKL_MAX_REDIRECTS
+const DEFAULT_MAX_REDIRECTS: u32 = 10;
+
+fn max_redirects() -> u32 {
+    match env_var("MKL_MAX_REDIRECTS") {
+        Some(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("MKL_MAX_REDIRECTS must be a number: {}", value)),
+        None => DEFAULT_MAX_REDIRECTS,
+    }
+}
+
+// Larger than the default of `BufWriter`, not to write each chunk of the large archive separately
+const DEFAULT_DOWNLOAD_BUFFER: usize = 1 << 20;
+
+/// Capacity of the write buffer of `download()`, set by MKL_DOWNLOAD_BUFFER in bytes
+fn download_buffer() -> usize {
+    match env_var("MKL_DOWNLOAD_BUFFER") {
+        Some(value) => value
+            .parse()
+            .ok()
+            .filter(|size| *size > 0)
+            .unwrap_or_else(|| panic!("MKL_DOWNLOAD_BUFFER must be a positive number of bytes: {}", value)),
+        None => DEFAULT_DOWNLOAD_BUFFER,
+    }
+}
+
+/// Whether a retried download opens new connections without the cached DNS entries, unless MKL_RETRY_FRESH_CONNECTION=0
+///
+/// Each download has its own handle, but the redirects in it reuse the connections and the resolved addresses,
+/// e.g. of a wedged CDN node.
+fn retry_fresh_connection() -> bool {
+    env_var("MKL_RETRY_FRESH_CONNECTION").as_deref() != Some("0")
+}
+
+/// Scheme of `uri` missing in `protocols` supported by libcurl, in lower case
+pub fn unsupported_protocol<'a, I: IntoIterator<Item = &'a str>>(uri: &str, protocols: I) -> Option<String> {
+    let scheme = uri.split_once("://")?.0.to_lowercase();
+    if protocols.into_iter().any(|protocol| protocol.eq_ignore_ascii_case(&scheme)) {
+        None
+    } else {
+        Some(scheme)
+    }
+}
+
+/// HTTP version of the downloads, set by MKL_HTTP_VERSION
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpProtocol {
+    /// Negotiated by libcurl, i.e. HTTP/2 over TLS if the server and libcurl support it
+    Auto,
+    V11,
+    V2,
+    V3,
+}
+
+impl HttpProtocol {
+    /// `1.1`, `2`, `3`, or `auto`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "auto" => Ok(HttpProtocol::Auto),
+            "1.1" => Ok(HttpProtocol::V11),
+            "2" => Ok(HttpProtocol::V2),
+            "3" => Ok(HttpProtocol::V3),
+            _ => Err(format!("Unknown MKL_HTTP_VERSION `{}`, expected `1.1`, `2`, `3`, or `auto`", value)),
+        }
+    }
+
+    /// Fall back to the newest version libcurl supports, given by its features
+    pub fn supported(self, http2: bool, http3: bool) -> Self {
+        match self {
+            HttpProtocol::V3 if !http3 && http2 => HttpProtocol::V2,
+            HttpProtocol::V3 if !http3 => HttpProtocol::Auto,
+            HttpProtocol::V2 if !http2 => HttpProtocol::Auto,
+            protocol => protocol,
+        }
+    }
+
+    fn curl_version(&self) -> HttpVersion {
+        match *self {
+            HttpProtocol::Auto => HttpVersion::Any,
+            HttpProtocol::V11 => HttpVersion::V11,
+            // Plain HTTP, e.g. of a local mirror, stays 1.1 instead of the upgrade to h2c
+            HttpProtocol::V2 => HttpVersion::V2TLS,
+            // libcurl falls back to the older versions if the QUIC connection fails, since 7.88
+            HttpProtocol::V3 => HttpVersion::V3,
+        }
+    }
+}
+
+/// MKL_HTTP_VERSION supported by the linked libcurl, `auto` by default
+fn http_protocol() -> HttpProtocol {
+    static PROTOCOL: OnceLock<HttpProtocol> = OnceLock::new();
+    *PROTOCOL.get_or_init(|| {
+        let requested = match env_var("MKL_HTTP_VERSION") {
+            Some(value) => HttpProtocol::parse(&value).unwrap_or_else(|e| panic!("{}", e)),
+            None => HttpProtocol::Auto,
+        };
+        let version = curl::Version::get();
+        let supported = requested.supported(version.feature_http2(), version.feature_http3());
+        if supported != requested {
+            println!(
+                "cargo:warning=MKL_HTTP_VERSION: libcurl {} does not support {:?}, {:?} is used instead",
+                version.version(),
+                requested,
+                supported
+            );
+        }
+        supported
+    })
+}
+
+/// Handle of curl for `uri` by the download policy, e.g. MKL_NO_REDIRECT, MKL_HTTP_VERSION, and MKL_INSECURE_TLS
+///
+/// `retry` is set for the attempts after a failure, see `retry_fresh_connection()`.
+/// The redirections are checked after the transfer by `check_redirect()`.
+fn download_handle(uri: &str, retry: bool) -> Result<Easy, BuildError> {
+    // Otherwise curl fails by an opaque "Unsupported protocol"
+    if let Some(protocol) = unsupported_protocol(uri, curl::Version::get().protocols()) {
+        return Err(BuildError::UnsupportedProtocol {
+            uri: uri.to_string(),
+            protocol,
+        });
+    }
+    let mut easy = Easy::new();
+    // A strict mirror must serve the URL itself
+    easy.follow_location(!env_flag("MKL_NO_REDIRECT"))?;
+    easy.max_redirections(max_redirects())?;
+    // Credentials are not sent to another host, e.g. storage redirected to by a signed URL
+    easy.unrestricted_auth(false)?;
+    easy.autoreferer(true)?;
+    if retry && retry_fresh_connection() {
+        easy.fresh_connect(true)?;
+        easy.forbid_reuse(true)?;
+        easy.dns_cache_timeout(Duration::from_secs(0))?;
+    }
+    easy.url(uri)?;
+    if http_protocol() != HttpProtocol::Auto {
+        easy.http_version(http_protocol().curl_version())?;
+    }
+    if insecure_tls(uri, mirror_base().as_deref(), env_flag("MKL_INSECURE_TLS")) {
+        easy.ssl_verify_peer(false)?;
+        easy.ssl_verify_host(false)?;
+    }
+    Ok(easy)
+}
+
+/// Check the transfer of `download_handle()` against MKL_REDIRECT_HOSTS and MKL_NO_REDIRECT
+fn check_redirect(easy: &mut Easy, uri: &str, performed: Result<(), curl::Error>) -> Result<(), BuildError> {
+    match performed {
+        Err(ref e) if e.is_too_many_redirects() => {
+            return Err(BuildError::TooManyRedirects {
+                uri: uri.to_string(),
+                max: max_redirects(),
+            })
+        }
+        result => result?,
+    }
+    if let (Some(allowed), Some(url)) = (env_var("MKL_REDIRECT_HOSTS"), easy.effective_url()?) {
+        if url != uri && !host_allowed(url, &allowed) {
+            return Err(BuildError::UnexpectedHost {
+                uri: uri.to_string(),
+                url: url.to_string(),
+            });
+        }
+    }
+    if env_flag("MKL_NO_REDIRECT") && (300..400).contains(&easy.response_code()?) {
+        return Err(BuildError::Redirected {
+            uri: uri.to_string(),
+            location: easy.redirect_url()?.unwrap_or("").to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Download into `out_dir/filename`, and returns the MD5 sum computed while downloading
+///
+/// Each chunk is also passed to `sink`, e.g. to extract the archive in the same pass.
+/// `retry` is set for the attempts after a failure, see `retry_fresh_connection()`.
+pub fn download_with<F: FnMut(&[u8])>(
+    uri: &str,
+    filename: &str,
+    out_dir: &Path,
+    retry: bool,
+    mut sink: F,
+) -> Result<String, BuildError> {
+    let mut easy = download_handle(uri, retry)?;
+    let out = out_dir.join(filename);
+    let _partial = Partial::new(&out);
+    set_phase(format!("downloading {}", uri));
+    let start = Instant::now();
+    let mut received = 0;
+
+    // Download the tarball.
+    let mut writer = BufWriter::with_capacity(download_buffer(), File::create(&out)?);
+    let mut context = md5::Context::new();
+    let performed = {
+        let mut transfer = easy.transfer();
+        // Returning a length different from the input aborts the transfer
+        transfer.write_function(|data| {
+            received += data.len() as u64;
+            context.consume(data);
+            report_download(data.len());
+            sink(data);
+            Ok(writer.write_all(data).map(|_| data.len()).unwrap_or(0))
+        })?;
+        transfer.perform()
+    };
+    if let Err(e) = check_redirect(&mut easy, uri, performed) {
+        drop(writer);
+        fs::remove_file(&out)?;
+        return Err(e);
+    }
+    writer.flush()?;
+
+    let response_code = easy.response_code()?;
+    if response_code != 200 {
+        drop(writer);
+        fs::remove_file(&out)?;
+        return Err(BuildError::HttpStatus {
+            uri: uri.to_string(),
+            code: response_code,
+        });
+    }
+    DOWNLOAD_STATS.record(start, received);
+    Ok(format!("{:x}", context.compute()))
+}
+
+/// Whether the host of `url` matches one of `patterns` of MKL_REDIRECT_HOSTS,
+/// comma-separated hosts, e.g. `conda.anaconda.org`, or suffixes, e.g. `*.blob.core.windows.net`
+pub fn host_allowed(url: &str, patterns: &str) -> bool {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host = authority.rsplit('@').next().unwrap().split(':').next().unwrap().to_lowercase();
+    patterns
+        .split(',')
+        .map(|pattern| pattern.trim().to_lowercase())
+        .filter(|pattern| !pattern.is_empty())
+        .any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+            None => host == pattern,
+        })
+}
+
+pub fn download(uri: &str, filename: &str, out_dir: &Path) -> Result<String, BuildError> {
+    download_with(uri, filename, out_dir, false, |_| {})
+}
+
+/// Download the archive and extract `files` from the same stream, checking the MD5 sum of the archive
+///
+/// Files are extracted into a staging directory, and moved into `out_dir` only if the MD5 sum matches.
+/// A corrupted archive, e.g. served by a stale CDN edge, is removed and downloaded once again.
+pub fn download_archive(
+    uri: &str,
+    archive: &str,
+    md5: &str,
+    out_dir: &Path,
+    files: &[PathBuf],
+) -> Result<(), BuildError> {
+    let archive_path = out_dir.join(archive);
+    let staging = out_dir.join(STAGING);
+    let _partial = (Partial::new(&archive_path), Partial::new(&staging));
+    // The central directory of `.conda` is at the end, and it is extracted after the download
+    let conda = archive.ends_with(".conda");
+    let mut sum = String::new();
+    for attempt in 0..2 {
+        let _ = fs::remove_dir_all(&staging);
+        // Bounded not to buffer the whole archive when the extraction is slower than the download
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(64);
+        let extractor = {
+            let (staging, files) = (staging.clone(), files.to_vec());
+            thread::spawn(move || {
+                if conda {
+                    drop(receiver);
+                    return Ok(Vec::new());
+                }
+                unpack(ChannelReader::new(receiver), &staging, &files)
+            })
+        };
+        // Sending fails only after the extractor has stopped, and its error is reported below
+        let downloaded = download_with(uri, archive, out_dir, attempt > 0, |data| {
+            let _ = sender.send(data.to_vec());
+        });
+        drop(sender);
+        let unpacked = extractor.join().expect("extractor thread panicked");
+        sum = match downloaded {
+            Ok(sum) => sum,
+            // A signed URL redirected to may have expired, and the original URI gives another one
+            Err(BuildError::HttpStatus { code: 403, .. }) if attempt == 0 => {
+                println!("cargo:warning={} is forbidden, request the archive again", uri);
+                let _ = fs::remove_dir_all(&staging);
+                continue;
+            }
+            Err(e) => {
+                let _ = fs::remove_dir_all(&staging);
+                return Err(e);
+            }
+        };
+        if sum == md5 {
+            let entries = if conda {
+                set_phase(format!("extracting {}", archive_path.display()));
+                unpack_conda(File::open(&archive_path)?, &staging, files)?
+            } else {
+                unpacked?
+            };
+            for file in files {
+                let dest = out_dir.join(file);
+                fs::create_dir_all(dest.parent().unwrap())?;
+                // Remove first not to replace the file through a hard link into the cache
+                if dest.exists() {
+                    retry_io(|| fs::remove_file(&dest))?;
+                }
+                retry_io(|| fs::rename(staging.join(file), &dest))?;
+            }
+            fs::remove_dir_all(&staging)?;
+            write_manifest(out_dir, files, &entries)?;
+            return Ok(());
+        }
+        fs::remove_dir_all(&staging)?;
+        if attempt == 0 {
+            println!(
+                "cargo:warning=check sum of downloaded archive is incorrect: md5sum={}, download again",
+                sum
+            );
+            fs::remove_file(&archive_path)?;
+        }
+    }
+    Err(BuildError::Checksum {
+        path: archive_path,
+        expected: md5.to_string(),
+        actual: sum,
+    })
+}
+
+pub fn calc_md5(path: &Path) -> String {
+    let mut f = BufReader::new(fs::File::open(path).unwrap());
+    let mut context = md5::Context::new();
+    io::copy(&mut f, &mut context).unwrap();
+    format!("{:x}", context.compute())
+}
+
+/// `Read` over the chunks sent from the download
+struct ChannelReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    fn new(receiver: mpsc::Receiver<Vec<u8>>) -> Self {
+        ChannelReader {
+            receiver,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // The download has finished
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// `Write` which computes the MD5 sum and the size of the written data
+struct HashWriter<W> {
+    inner: W,
+    context: md5::Context,
+    size: u64,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.context.consume(&buf[..len]);
+        self.size += len as u64;
+        report_progress();
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Record of the files written by `extract()`, placed next to the extracted libraries.
+const MANIFEST: &str = "mkl-manifest.txt";
+
+// Directory in `out_dir` where `download_archive()` extracts files before checking the archive
+const STAGING: &str = "mkl-staging";
+
+/// Extract `files` from the archive, and write the manifest
+///
+/// Entries are matched by the file name, and placed at the path in `files`
+/// even if the archive nests them under another directory.
+/// Each manifest line is `<md5>  <size>  <path>`, in the order of `files`
+/// regardless of the order of entries in the archive. Lines of the other files extracted before are kept.
+pub fn extract<P: AsRef<Path>, P2: AsRef<Path>>(archive_path: P, extract_to: P2, files: &[PathBuf]) -> io::Result<()> {
+    let (archive_path, extract_to) = (archive_path.as_ref(), extract_to.as_ref());
+    set_phase(format!("extracting {}", archive_path.display()));
+    let file = File::open(archive_path)?;
+    let entries = if archive_path.extension() == Some("conda".as_ref()) {
+        unpack_conda(file, extract_to, files)?
+    } else {
+        unpack(file, extract_to, files)?
+    };
+    write_manifest(extract_to, files, &entries)
+}
+
+/// Whether `archive` is extracted, recording the failure to try the next source
+fn extracted(archive: &Path, out_dir: &Path, files: &[PathBuf]) -> bool {
+    match extract(archive, out_dir, files) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("cargo:warning=Failed to extract {}: {}", archive.display(), e);
+            record_attempt(archive.display(), format!("failed to extract: {}", e));
+            false
+        }
+    }
+}
+
+/// Extract `files` from the `.tar.bz2` stream, and returns the MD5 sum and the size of each file
+fn unpack<R: Read>(reader: R, extract_to: &Path, files: &[PathBuf]) -> io::Result<Vec<(String, u64)>> {
+    unpack_tar(BzDecoder::new(reader), extract_to, files)
+}
+
+/// Extract `files` from the `.conda` package, see `unpack()`
+///
+/// `.conda` is a zip of `info-*.tar.zst` and `pkg-*.tar.zst`, found by its central directory.
+/// The libraries in the latter are decompressed as a stream, without writing the tarball.
+fn unpack_conda(file: File, extract_to: &Path, files: &[PathBuf]) -> io::Result<Vec<(String, u64)>> {
+    read_conda_tarball(file, "pkg-", |tarball| unpack_tar(tarball, extract_to, files))
+}
+
+/// Read the decompressed `<prefix>*.tar.zst`, i.e. `pkg-` or `info-`, of the `.conda` package by `read`
+fn read_conda_tarball<T, F>(file: File, prefix: &str, read: F) -> io::Result<T>
+where
+    F: FnOnce(&mut dyn Read) -> io::Result<T>,
+{
+    let mut package = zip::ZipArchive::new(file).map_err(io::Error::from)?;
+    let name = package
+        .file_names()
+        .find(|name| name.starts_with(prefix) && name.ends_with(".tar.zst"))
+        .map(str::to_string)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}*.tar.zst is not found in .conda package", prefix))
+        })?;
+    let inner = package.by_name(&name).map_err(io::Error::from)?;
+    let mut decoder = zstd::Decoder::new(inner)?;
+    read(&mut decoder)
+}
+
+/// `info/index.json` of the conda package, read until it is found
+///
+/// It is in `info-*.tar.zst` of `.conda`, and usually at the beginning of `.tar.bz2`.
+pub fn read_package_index(archive_path: &Path) -> io::Result<serde_json::Value> {
+    let file = File::open(archive_path)?;
+    let not_found = || io::Error::new(io::ErrorKind::InvalidData, "info/index.json is not found in the archive");
+    let find = |tarball: &mut dyn Read| -> io::Result<serde_json::Value> {
+        for entry in Archive::new(tarball).entries()? {
+            let entry = entry?;
+            if entry.path()? == Path::new("info/index.json") {
+                return serde_json::from_reader(entry).map_err(io::Error::from);
+            }
+        }
+        Err(not_found())
+    };
+    if archive_path.extension() != Some("conda".as_ref()) {
+        return find(&mut BzDecoder::new(file));
+    }
+    read_conda_tarball(file, "info-", find)
+}
+
+/// Check that `index`, `info/index.json` of the archive, is the package requested as `filename` of `version`
+///
+/// A mirror may serve another package under the requested name, which the checksum of the mirror does not catch.
+pub fn check_package_index(index: &serde_json::Value, filename: &str, version: &str) -> Result<(), String> {
+    let field = |key: &str| index.get(key).and_then(|value| value.as_str()).unwrap_or("");
+    let (name, found_version, build) = (field("name"), field("version"), field("build"));
+    let stem = filename.strip_suffix(".tar.bz2").or_else(|| filename.strip_suffix(".conda")).unwrap_or(filename);
+    if found_version != version || format!("{}-{}-{}", name, found_version, build) != stem {
+        return Err(format!(
+            "{} is {} {} {} by info/index.json, expected {} {}",
+            filename, name, found_version, build, stem, version
+        ));
+    }
+    Ok(())
+}
+
+/// Extract `files` from the tar stream
+///
+/// The stream is read on this thread, and with MKL_EXTRACT_JOBS larger than 1, the entries are written
+/// and hashed by that number of threads while the next entries are decompressed.
+fn unpack_tar<R: Read>(tarball: R, extract_to: &Path, files: &[PathBuf]) -> io::Result<Vec<(String, u64)>> {
+    let jobs = extract_jobs();
+    let start = Instant::now();
+    let mut progress = ExtractProgress::new();
+    let mut a = Archive::new(tarball);
+    let mut seen = vec![false; files.len()];
+    // Results of `write_entry()` for `files`
+    let extracted = Mutex::new(files.iter().map(|_| None).collect::<Vec<_>>());
+    // Rendezvous, so that the next entry waits for a free writer
+    let (job_sender, job_receiver) = mpsc::sync_channel::<(usize, PathBuf, u64, ChannelReader)>(0);
+    let job_receiver = Mutex::new(job_receiver);
+    thread::scope(|scope| -> io::Result<()> {
+        // Moved, and dropped at the end of the stream to stop the writers
+        let job_sender = job_sender;
+        if jobs > 1 {
+            for _ in 0..jobs {
+                scope.spawn(|| loop {
+                    let job = job_receiver.lock().unwrap().recv();
+                    match job {
+                        Ok((index, dest, mtime, reader)) => {
+                            let result = write_entry(reader, &dest, mtime);
+                            extracted.lock().unwrap()[index] = Some(result);
+                        }
+                        Err(_) => break,
+                    }
+                });
+            }
+        }
+        for entry in a.entries()? {
+            let mut entry = entry?;
+            progress.entries += 1;
+            progress.report();
+            let path = entry.path()?.into_owned();
+            let index = match files.iter().position(|f| path.file_name() == f.file_name()) {
+                Some(index) => index,
+                None => continue,
+            };
+            if seen[index] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} appears twice in the archive", files[index].display()),
+                ));
+            }
+            seen[index] = true;
+            let dest = extract_to.join(&files[index]);
+            fs::create_dir_all(dest.parent().unwrap())?;
+            // Remove first not to write through a hard link into the cache
+            if dest.exists() {
+                retry_io(|| fs::remove_file(&dest))?;
+            }
+            let mtime = entry.header().mtime()?;
+            progress.bytes += entry.size();
+            if jobs <= 1 {
+                let result = write_entry(&mut entry, &dest, mtime);
+                extracted.lock().unwrap()[index] = Some(result);
+                continue;
+            }
+            let (sender, receiver) = mpsc::sync_channel(16);
+            job_sender
+                .send((index, dest, mtime, ChannelReader::new(receiver)))
+                .map_err(|_| io::Error::other("writer threads have stopped"))?;
+            let mut buf = vec![0; 1 << 16];
+            loop {
+                let len = entry.read(&mut buf)?;
+                // Sending fails only after the writer has failed, and its error is reported below
+                if len == 0 || sender.send(buf[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    EXTRACT_STATS.record(start, progress.bytes);
+    files
+        .iter()
+        .zip(extracted.into_inner().unwrap())
+        .map(|(file, extracted)| {
+            extracted.unwrap_or_else(|| {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not found in the archive", file.display()),
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Progress of `unpack_tar()` shown at most once per second, unless MKL_QUIET is set
+///
+/// Cargo shows the warnings when the build script finishes, or immediately with `-vv`.
+struct ExtractProgress {
+    entries: usize,
+    /// Bytes of the extracted files
+    bytes: u64,
+    last: Instant,
+    quiet: bool,
+}
+
+impl ExtractProgress {
+    fn new() -> Self {
+        ExtractProgress {
+            entries: 0,
+            bytes: 0,
+            last: Instant::now(),
+            quiet: env_flag("MKL_QUIET"),
+        }
+    }
+
+    fn report(&mut self) {
+        if self.quiet || self.last.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        self.last = Instant::now();
+        println!(
+            "cargo:warning=Extracting MKL: {} entries processed, {} MiB written",
+            self.entries,
+            self.bytes >> 20
+        );
+    }
+}
+
+/// Write an entry into `dest`, and returns its MD5 sum and size
+fn write_entry<R: Read>(mut content: R, dest: &Path, mtime: u64) -> io::Result<(String, u64)> {
+    let mut writer = HashWriter {
+        inner: BufWriter::new(retry_io(|| File::create(dest))?),
+        context: md5::Context::new(),
+        size: 0,
+    };
+    io::copy(&mut content, &mut writer)?;
+    writer.flush()?;
+    // Keep the time in the archive, since cargo re-runs the build script for files newer than its last run
+    writer.inner.get_ref().set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    Ok((format!("{:x}", writer.context.compute()), writer.size))
+}
+
+/// Whether `error` may be transient, e.g. a file locked by an antivirus on Windows or busy on NFS
+///
+/// Others, e.g. no space left or a broken archive, fail immediately.
+pub fn is_retryable(error: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION of Windows
+    if cfg!(windows) && matches!(error.raw_os_error(), Some(32) | Some(33)) {
+        return true;
+    }
+    matches!(
+        error.kind(),
+        io::ErrorKind::PermissionDenied
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// Run a file operation of the extraction, retried `extract_retries()` times with a backoff
+/// while it fails by `is_retryable()` errors
+pub fn retry_io<T, F: FnMut() -> io::Result<T>>(mut operation: F) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(ref e) if attempt < extract_retries() && is_retryable(e) => {
+                attempt += 1;
+                println!("Retry after a transient error ({} of {}): {}", attempt, extract_retries(), e);
+                thread::sleep(Duration::from_millis(100 << attempt));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Number of retries of each file operation of the extraction, set by MKL_EXTRACT_RETRIES
+fn extract_retries() -> u32 {
+    static RETRIES: OnceLock<u32> = OnceLock::new();
+    *RETRIES.get_or_init(|| match env_var("MKL_EXTRACT_RETRIES") {
+        Some(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("MKL_EXTRACT_RETRIES must be a number: {}", value)),
+        None => 3,
+    })
+}
+
+/// Number of threads writing the extracted files, set by MKL_EXTRACT_JOBS. The default is 1, i.e. no threads.
+fn extract_jobs() -> usize {
+    static JOBS: OnceLock<usize> = OnceLock::new();
+    *JOBS.get_or_init(|| match env_var("MKL_EXTRACT_JOBS") {
+        Some(value) => value
+            .parse()
+            .ok()
+            .filter(|jobs| *jobs > 0)
+            .unwrap_or_else(|| panic!("MKL_EXTRACT_JOBS must be a positive number: {}", value)),
+        None => 1,
+    })
+}
+
+/// Write the manifest of `files`, keeping the lines of the other files
+fn write_manifest(extract_to: &Path, files: &[PathBuf], entries: &[(String, u64)]) -> io::Result<()> {
+    let keys: Vec<_> = files.iter().map(|file| manifest_path(file)).collect();
+    let kept: Vec<String> = fs::read_to_string(extract_to.join(MANIFEST))
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| line.splitn(3, "  ").nth(2).is_some_and(|path| !keys.iter().any(|key| key == path)))
+        .map(str::to_string)
+        .collect();
+    let mut manifest = BufWriter::new(File::create(extract_to.join(MANIFEST))?);
+    for line in kept {
+        writeln!(manifest, "{}", line)?;
+    }
+    for (file, (md5, size)) in files.iter().zip(entries) {
+        writeln!(manifest, "{}  {}  {}", md5, size, manifest_path(file))?;
+    }
+    manifest.flush()
+}
+
+/// Path in the manifest, separated by `/` on every platform
+fn manifest_path(path: &Path) -> String {
+    let components: Vec<_> = path.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+    components.join("/")
+}
+
+/// Check that `files` have been extracted and are unchanged since then, using the manifest
+pub fn check_files(extract_to: &Path, files: &[PathBuf]) -> bool {
+    verify_files(extract_to, files).is_empty()
+}
+
+/// (path, MD5 sum) of `files` recorded in the manifest, e.g. after `verify_files()`
+pub fn manifest_checksums(extract_to: &Path, files: &[PathBuf]) -> Vec<(PathBuf, String)> {
+    let manifest = fs::read_to_string(extract_to.join(MANIFEST)).unwrap_or_default();
+    files
+        .iter()
+        .filter_map(|file| {
+            let key = manifest_path(file);
+            manifest
+                .lines()
+                .map(|line| line.splitn(3, "  ").collect::<Vec<_>>())
+                .find(|e| e.len() == 3 && e[2] == key)
+                .map(|e| (extract_to.join(file), e[0].to_string()))
+        })
+        .collect()
+}
+
+/// Fingerprint of the linked configuration, i.e. the MD5 sum of the version, the platform, the layers,
+/// and the (file name, MD5 sum) of the libraries, which does not depend on their order or directories
+pub fn config_fingerprint(
+    version: Option<&str>,
+    platform: &str,
+    config: &MklConfig,
+    checksums: &[(String, String)],
+) -> String {
+    let mut lines = vec![
+        format!("version={}", version.unwrap_or("unknown")),
+        format!("platform={}", platform),
+        format!("interface={}", config.interface.name()),
+        format!("threading={}", config.threading.feature()),
+        format!("link={}", if config.rt { "dynamic" } else { "static" }),
     ];
+    let mut files: Vec<String> = checksums.iter().map(|(name, md5)| format!("{}={}", name, md5)).collect();
+    files.sort();
+    lines.extend(files);
+    format!("{:x}", md5::compute(lines.join("\n")))
+}
+
+/// Source of `verified_libraries()` in the library, included from OUT_DIR
+pub fn verified_source(verified: &[(PathBuf, String)]) -> String {
+    let entries: Vec<_> = verified
+        .iter()
+        .map(|(path, md5)| format!("({:?}, {:?})", path.to_string_lossy(), md5))
+        .collect();
+    format!("&[{}]\n", entries.join(", "))
+}
+
+/// Files which are missing, or changed since extracted, using the manifest
+///
+/// The files are hashed in parallel by `verify_jobs()` threads.
+pub fn verify_files(extract_to: &Path, files: &[PathBuf]) -> Vec<PathBuf> {
+    let manifest = match fs::read_to_string(extract_to.join(MANIFEST)) {
+        Ok(manifest) => manifest,
+        Err(_) => return files.to_vec(),
+    };
+    let entries: Vec<Vec<&str>> = manifest.lines().map(|line| line.splitn(3, "  ").collect()).collect();
+    set_phase(format!("verifying {}", extract_to.display()));
+    let start = Instant::now();
+    // Bytes read to hash the files
+    let hashed = AtomicU64::new(0);
+    let is_bad = |file: &PathBuf| {
+        let key = manifest_path(file);
+        let entry = match entries.iter().find(|e| e.len() == 3 && e[2] == key) {
+            Some(entry) => entry,
+            None => return true,
+        };
+        let path = extract_to.join(file);
+        let size = entry[1].parse().unwrap_or(u64::MAX);
+        let verified = verify_file(&path, entry[0], size);
+        report_progress();
+        if verified.is_ok() || verified.as_ref().is_err_and(|e| e.starts_with("checksum")) {
+            hashed.fetch_add(size, Ordering::Relaxed);
+        }
+        match verified {
+            Ok(()) => false,
+            Err(reason) => {
+                println!("{}: {}", path.display(), reason);
+                true
+            }
+        }
+    };
+
+    // Each thread takes the next file, so that at most `verify_jobs()` files are read at once
+    let next = AtomicUsize::new(0);
+    let bad: Vec<AtomicBool> = files.iter().map(|_| AtomicBool::new(false)).collect();
+    thread::scope(|scope| {
+        for _ in 0..verify_jobs().min(files.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                match files.get(index) {
+                    Some(file) => bad[index].store(is_bad(file), Ordering::Relaxed),
+                    None => break,
+                }
+            });
+        }
+    });
+    VERIFY_STATS.record(start, hashed.into_inner());
+    files
+        .iter()
+        .zip(&bad)
+        .filter(|(_, bad)| bad.load(Ordering::Relaxed))
+        .map(|(file, _)| file.clone())
+        .collect()
+}
+
+/// Check `path` by the size and the MD5 sum recorded in the manifest
+///
+/// The size is compared first, so that a truncated file is rejected without reading it.
+pub fn verify_file(path: &Path, md5: &str, size: u64) -> Result<(), String> {
+    let actual = fs::metadata(path).map_err(|e| format!("not found: {}", e))?.len();
+    if actual != size {
+        return Err(format!("size mismatch, expected {} bytes but {} bytes, file truncated?", size, actual));
+    }
+    let actual = calc_md5(path);
+    if actual != md5 {
+        return Err(format!("checksum mismatch, expected {} but {}", md5, actual));
+    }
+    Ok(())
+}
+
+/// Machine of the packages, which contain only the intel64 libraries
+pub const EXPECTED_ARCH: &str = "x86_64 little-endian";
+
+/// Machine and byte order of an object, e.g. `aarch64 little-endian`, of ELF, COFF, the short import object of a DLL, or Mach-O
+pub fn object_arch<'data, R: ReadRef<'data>>(data: R) -> Option<String> {
+    let (architecture, little) = match FileKind::parse(data).ok()? {
+        FileKind::CoffImport => (ImportFile::parse(data).ok()?.architecture(), true),
+        _ => {
+            let object = object::File::parse(data).ok()?;
+            (object.architecture(), object.is_little_endian())
+        }
+    };
+    let machine = match architecture {
+        Architecture::X86_64 => "x86_64".to_string(),
+        Architecture::I386 => "x86".to_string(),
+        Architecture::Arm => "arm".to_string(),
+        Architecture::Aarch64 => "aarch64".to_string(),
+        Architecture::PowerPc | Architecture::PowerPc64 => "powerpc".to_string(),
+        Architecture::S390x => "s390x".to_string(),
+        Architecture::Riscv32 | Architecture::Riscv64 => "riscv".to_string(),
+        other => format!("{:?}", other),
+    };
+    Some(format!("{} {}", machine, if little { "little-endian" } else { "big-endian" }))
+}
+
+/// Visit the members of the static library `path` by `visit` with their names, and returns the results,
+/// or None if it is not an `ar` archive
+///
+/// Each member is read through its own `ReadCache`, i.e. only the parts `visit` reads are loaded, not the whole library.
+/// The symbol tables and the table of the long names are not visited.
+pub fn archive_members<T, F>(path: &Path, mut visit: F) -> io::Result<Option<Vec<T>>>
+where
+    F: FnMut(&str, ReadCacheRange<'_, &File>) -> Option<T>,
+{
+    let file = File::open(path)?;
+    let mut magic = [0; 8];
+    if (&file).read_exact(&mut magic).is_err() || &magic != b"!<arch>\n" {
+        return Ok(None);
+    }
+    let invalid = |e: object::Error| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e));
+    let members = {
+        let cache = ReadCache::new(&file);
+        let archive = ArchiveFile::parse(&cache).map_err(invalid)?;
+        archive
+            .members()
+            .map(|member| {
+                let member = member.map_err(invalid)?;
+                Ok((String::from_utf8_lossy(member.name()).into_owned(), member.file_range()))
+            })
+            .collect::<io::Result<Vec<_>>>()?
+    };
+    let mut results = Vec::new();
+    for (name, (offset, size)) in members {
+        let cache = ReadCache::new(&file);
+        if let Some(result) = visit(&name, cache.range(offset, size)) {
+            results.push(result);
+        }
+    }
+    Ok(Some(results))
+}
+
+/// Machines of the objects in the static library `path`, in the order found, or empty if it is not an `ar` archive
+pub fn library_archs(path: &Path) -> io::Result<Vec<String>> {
+    let mut archs: Vec<String> = Vec::new();
+    for arch in archive_members(path, |_, data| object_arch(data))?.unwrap_or_default() {
+        if !archs.contains(&arch) {
+            archs.push(arch);
+        }
+    }
+    Ok(archs)
+}
+
+/// Check that the libraries extracted from `archive` are built for `EXPECTED_ARCH`,
+/// not to fail in the linker by the objects of another machine, e.g. served by a misconfigured mirror
+///
+/// Every object is checked, since a library may mix the objects of machines.
+pub fn check_arch(archive: &str, dir: &Path, files: &[PathBuf]) -> Result<(), String> {
+    for file in files {
+        let archs = library_archs(&dir.join(file)).map_err(|e| format!("Failed to read {}: {}", dir.join(file).display(), e))?;
+        if let Some(arch) = archs.iter().find(|arch| *arch != EXPECTED_ARCH) {
+            return Err(format!(
+                "archive {} contains {} objects, expected {} ({})",
+                archive,
+                arch,
+                EXPECTED_ARCH,
+                file.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Relocation model of the target, `CARGO_CFG_RELOCATION_MODEL` if the toolchain exposes it,
+/// or the last `-C relocation-model=` of RUSTFLAGS, or None for the default `pic` of the target
+pub fn relocation_model<I: IntoIterator<Item = (String, String)>>(vars: I) -> Option<String> {
+    let vars: Vec<(String, String)> = vars.into_iter().collect();
+    let value = |name: &str| vars.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    if let Some(model) = value("CARGO_CFG_RELOCATION_MODEL") {
+        return Some(model.to_string());
+    }
+    let flags: Vec<&str> = match (value("CARGO_ENCODED_RUSTFLAGS"), value("RUSTFLAGS")) {
+        (Some(flags), _) => flags.split('\x1f').collect(),
+        (None, Some(flags)) => flags.split_whitespace().collect(),
+        (None, None) => return None,
+    };
+    let mut model = None;
+    let mut codegen = false;
+    for flag in flags {
+        let option = if codegen { Some(flag) } else { flag.strip_prefix("-C") };
+        codegen = flag == "-C" || flag == "--codegen";
+        if let Some(found) = option.and_then(|option| option.strip_prefix("relocation-model=")) {
+            model = Some(found.to_string());
+        }
+    }
+    model
+}
+
+/// Whether `data` is an x86_64 ELF object with absolute 32-bit relocations in its loaded sections,
+/// i.e. was compiled without `-fPIC` and cannot be linked into a PIE nor a shared library
+///
+/// The relocations of the debug sections are not loaded, and are absolute even in PIC objects.
+pub fn object_non_pic<'data, R: ReadRef<'data>>(data: R) -> bool {
+    let object = match object::File::parse(data) {
+        Ok(object) if object.format() == BinaryFormat::Elf && object.architecture() == Architecture::X86_64 => object,
+        _ => return false,
+    };
+    object.sections().any(|section| {
+        let alloc = matches!(section.flags(), SectionFlags::Elf { sh_flags } if sh_flags & u64::from(elf::SHF_ALLOC) != 0);
+        alloc
+            && section.relocations().any(|(_, relocation)| {
+                matches!(relocation.flags(), RelocationFlags::Elf { r_type } if r_type == elf::R_X86_64_32 || r_type == elf::R_X86_64_32S)
+            })
+    })
+}
+
+/// Members of the static library `path` compiled without `-fPIC`, by `object_non_pic()`
+pub fn non_pic_members(path: &Path) -> io::Result<Vec<String>> {
+    let members = archive_members(path, |name, data| {
+        if object_non_pic(data) {
+            Some(name.trim_end_matches('/').to_string())
+        } else {
+            None
+        }
+    })?;
+    Ok(members.unwrap_or_default())
+}
+
+/// Check that the static libraries `libs` can be linked into the executables of the relocation `model`
+///
+/// Rust builds PIE executables on the ELF targets by default, into which objects compiled without `-fPIC`
+/// fail to be linked by e.g. `relocation R_X86_64_32 against ... can not be used when making a PIE object`.
+/// rustc links by `-no-pie` for the `static` and `dynamic-no-pic` models, where any objects are linked.
+pub fn check_pic(libs: &[PathBuf], model: Option<&str>, target_os: &str) -> Result<(), String> {
+    if target_os == "windows" || target_os == "macos" || matches!(model, Some("static") | Some("dynamic-no-pic")) {
+        return Ok(());
+    }
+    for lib in libs {
+        let members = non_pic_members(lib).map_err(|e| format!("Failed to read {}: {}", lib.display(), e))?;
+        if let Some(member) = members.first() {
+            return Err(format!(
+                "{} contains objects compiled without -fPIC ({}{}), which cannot be linked into PIE executables \
+                 of the `{}` relocation model, the default of Rust on {}.\n\
+                 Link non-PIE executables by RUSTFLAGS=\"-C relocation-model=static\", where rustc passes -no-pie to the linker, \
+                 or use the libraries compiled with -fPIC, e.g. the ones provisioned from the channel.\n\
+                 Shared libraries, e.g. of a `cdylib` crate, cannot contain these objects in any model",
+                lib.display(),
+                member,
+                if members.len() > 1 { format!(" and {} more", members.len() - 1) } else { String::new() },
+                model.unwrap_or("pic"),
+                target_os
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Number of threads hashing the files, set by MKL_VERIFY_JOBS
+///
+/// The default is at most 4 not to thrash the disk on many-core machines.
+fn verify_jobs() -> usize {
+    static JOBS: OnceLock<usize> = OnceLock::new();
+    *JOBS.get_or_init(|| match env_var("MKL_VERIFY_JOBS") {
+        Some(value) => value
+            .parse()
+            .ok()
+            .filter(|jobs| *jobs > 0)
+            .unwrap_or_else(|| panic!("MKL_VERIFY_JOBS must be a positive number: {}", value)),
+        None => thread::available_parallelism().map_or(1, |n| n.get()).min(4),
+    })
+}
+
+// Calls `cblas_dgemm` (BLAS) and `vdAdd` (VML) to check that the libraries can be linked
+const SELFTEST_SOURCE: &str = r#"
+#ifdef MKL_ILP64
+typedef long long MKL_INT;
+#else
+typedef int MKL_INT;
+#endif
+
+void cblas_dgemm(int layout, int transa, int transb, MKL_INT m, MKL_INT n, MKL_INT k,
+                 double alpha, const double *a, MKL_INT lda, const double *b, MKL_INT ldb,
+                 double beta, double *c, MKL_INT ldc);
+void vdAdd(MKL_INT n, const double *a, const double *b, double *y);
+
+int main(void) {
+    double a = 2.0, b = 3.0, c = 0.0, y = 0.0;
+    cblas_dgemm(102, 111, 111, 1, 1, 1, 1.0, &a, 1, &b, 1, 0.0, &c, 1);
+    // VML is also in mkl_core
+    vdAdd(1, &a, &b, &y);
+    return c == 6.0 && y == 5.0 ? 0 : 1;
+}
+"#;
+
+/// Link a C program calling `cblas_dgemm` and `vdAdd` against the libraries, and panic with the linker output on failure
+///
+/// The program is only linked, not executed, since the target may differ from the host.
+fn self_test(
+    config: &MklConfig,
+    lib_dir: &Path,
+    libs: &[String],
+    link_kinds: &[(String, &'static str)],
+    target_os: &str,
+    out_dir: &Path,
+) {
+    let build = cc::Build::new();
+    let compiler = build.get_compiler();
+    if compiler.is_like_msvc() {
+        println!("cargo:warning=MKL_SELFTEST is not supported for MSVC, skipped");
+        return;
+    }
+    let source = out_dir.join("mkl_selftest.c");
+    fs::write(&source, SELFTEST_SOURCE).unwrap();
+
+    let mut cmd = compiler.to_command();
+    if config.interface == Interface::ILP64 {
+        cmd.arg("-DMKL_ILP64");
+    }
+    cmd.arg(&source).arg("-o").arg(out_dir.join("mkl_selftest"));
+    cmd.arg(format!("-L{}", lib_dir.display()));
+    // Static MKL libraries depend on each other
+    if target_os == "linux" {
+        cmd.arg("-Wl,--start-group");
+    }
+    // `-l` prefers the shared library, and the static parts of MKL_LINK_KIND are named as files
+    let mixed = link_kinds.iter().any(|(_, kind)| *kind == "dylib");
+    for lib in libs {
+        let dynamic = link_kinds.iter().any(|(linked, kind)| linked == lib && *kind == "dylib");
+        if mixed && !dynamic && target_os == "linux" {
+            cmd.arg(format!("-l:lib{}.a", lib));
+        } else {
+            cmd.arg(format!("-l{}", lib));
+        }
+    }
+    if target_os == "linux" {
+        cmd.arg("-Wl,--end-group");
+    }
+    if mixed {
+        cmd.arg(format!("-Wl,-rpath,{}", lib_dir.display()));
+    }
+    if let Some(runtime) = config.threading.runtime(target_os).filter(|_| !config.rt) {
+        cmd.arg(format!("-l{}", runtime));
+    }
+    cmd.args(["-lpthread", "-lm"]);
+    if target_os == "linux" {
+        cmd.arg("-ldl");
+    }
+
+    set_phase(format!("linking {} by MKL_SELFTEST", lib_dir.display()));
+    let output = cmd
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to run the compiler for MKL_SELFTEST: {}", e));
+    report_progress();
+    if !output.status.success() {
+        panic!(
+            "MKL_SELFTEST failed to link cblas_dgemm and vdAdd: {:?} {:?}\n{}{}",
+            cmd.get_program(),
+            cmd.get_args().collect::<Vec<_>>(),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    println!("MKL_SELFTEST succeeded");
+}
+
+/// Extract `files` into `out_dir` from the archive, which is downloaded unless it exists, and returns its path
+///
+/// The build string or checksum unknown to `Platform::builds` is resolved using `repodata.json`.
+/// When a build which is not pinned by `MKL_BUILD_STRING` has been removed from the channel,
+/// another build of the same version is downloaded instead.
+pub fn fetch_archive(
+    package: &Package,
+    pinned: bool,
+    out_dir: &Path,
+    files: &[PathBuf],
+) -> Result<PathBuf, BuildError> {
+    let mut package = package.clone();
+    let mut repodata = None;
+    if package.build.is_none() || package.md5.is_none() {
+        let fetched = fetch_repodata(out_dir);
+        if let Err(ref e) = fetched {
+            record_attempt("repodata.json to resolve the build", e);
+        }
+        let builds = find_builds(repodata.get_or_insert(fetched?), &package.version);
+        let found = match package.build {
+            Some(ref build) => builds.into_iter().find(|(b, _, _)| b == build),
+            None => builds.into_iter().next(),
+        };
+        let found = found.ok_or_else(|| BuildError::PackageNotFound {
+            version: package.version.clone(),
+            build: package.build.clone(),
+        });
+        if let Err(ref e) = found {
+            record_attempt("repodata.json to resolve the build", e);
+        }
+        let (build, md5, extension) = found?;
+        package.build = Some(build);
+        package.md5 = Some(md5);
+        // The archive listed in the manifest keeps its URL
+        if package.url.is_none() {
+            package.extension = extension;
+        }
+    }
+
+    let archive = package.filename();
+    let mut md5 = package.md5.clone().unwrap();
+    if env_flag("MKL_USE_SIDECAR_CHECKSUM") {
+        match sidecar_checksum(&package.uri(), &archive, out_dir) {
+            Some(sidecar) => {
+                println!("Verify {} by {}.md5", archive, package.uri());
+                md5 = sidecar;
+            }
+            None => println!(
+                "cargo:warning=MKL_USE_SIDECAR_CHECKSUM: {}.md5 is not available, the built-in checksum is used",
+                package.uri()
+            ),
+        }
+    }
+    let archive_path = out_dir.join(&archive);
+    if let Some(dir) = env_var("MKL_OFFLINE_ARCHIVE_DIR") {
+        if let Some(offline) = find_offline_archive(Path::new(&dir), &archive, &md5) {
+            println!("Use pre-downloaded archive {}", offline.display());
+            if extracted(&offline, out_dir, files) {
+                return Ok(offline);
+            }
+        }
+    }
+    // Shared by the installs, e.g. on a network filesystem, while the libraries are extracted into `out_dir`
+    let archive_cache = archive_cache_dir();
+    if let Some(ref dir) = archive_cache {
+        if let Some(cached) = find_offline_archive(dir, &archive, &md5) {
+            println!("Use cached archive {}", cached.display());
+            if extracted(&cached, out_dir, files) {
+                return Ok(cached);
+            }
+        }
+    }
+    // The archive may be left by a previous build which failed after the download
+    if archive_path.exists() {
+        if calc_md5(&archive_path) == md5 {
+            println!("Use existings archive");
+            if extracted(&archive_path, out_dir, files) {
+                return Ok(archive_path);
+            }
+        } else {
+            println!("Remove incomplete archive {}", archive_path.display());
+            record_attempt(archive_path.display(), "incomplete, removed");
+        }
+        fs::remove_file(&archive_path)?;
+    }
+    // Instead of the channel, and the other builds are not looked for
+    if let Some(value) = env_var("MKL_OCI_REF") {
+        let reference = OciRef::parse(&value, &package.version, package.build.as_ref().unwrap())
+            .map_err(|reason| BuildError::Oci { reference: value.clone(), reason })?;
+        let pulled = pull_oci(&reference, &archive, &md5, out_dir);
+        match pulled {
+            Ok(ref path) => record_attempt(&value, format!("pulled into {}", path.display())),
+            Err(ref e) => record_attempt(&value, e),
+        }
+        let pulled = pulled?;
+        if extracted(&pulled, out_dir, files) {
+            return Ok(keep_archive(&archive_path, archive_cache.as_deref()));
+        }
+        // The channel is tried instead of the broken artifact
+        fs::remove_file(&pulled)?;
+    }
+    println!("Download archive");
+    let downloaded = download_archive(&package.uri(), &archive, &md5, out_dir, files);
+    match downloaded {
+        Ok(_) => record_attempt(package.uri(), "downloaded"),
+        Err(ref e) => record_attempt(package.uri(), e),
+    }
+    match downloaded {
+        Err(BuildError::HttpStatus { code: 404, .. }) if !pinned => {}
+        result => return result.map(|_| keep_archive(&archive_path, archive_cache.as_deref())),
+    }
+
+    let repodata = match repodata.map_or_else(|| fetch_repodata(out_dir), Ok) {
+        Ok(repodata) => repodata,
+        Err(e) => {
+            record_attempt("repodata.json to find another build", &e);
+            println!("cargo:warning=Failed to fetch repodata.json: {}", e);
+            return Err(BuildError::HttpStatus {
+                uri: package.uri(),
+                code: 404,
+            });
+        }
+    };
+    let builds = find_builds(&repodata, &package.version);
+    let (build, md5, extension) = builds
+        .into_iter()
+        .find(|(b, _, _)| Some(b) != package.build.as_ref())
+        .ok_or_else(|| BuildError::HttpStatus {
+            uri: package.uri(),
+            code: 404,
+        })?;
+    let alternative = Package {
+        build: Some(build),
+        md5: Some(md5.clone()),
+        url: None,
+        extension,
+        ..package
+    };
+    println!(
+        "cargo:warning={} has been removed from the channel, use {} instead",
+        archive,
+        alternative.filename()
+    );
+    // The checksum of the alternative build is taken from repodata.json
+    if let Err(e) = download_archive(&alternative.uri(), &alternative.filename(), &md5, out_dir, files) {
+        record_attempt(alternative.uri(), &e);
+        return Err(e);
+    }
+    Ok(keep_archive(&out_dir.join(alternative.filename()), archive_cache.as_deref()))
+}
+
+/// MD5 sum in `<uri>.md5` served next to the archive by a mirror, see MKL_USE_SIDECAR_CHECKSUM
+///
+/// `None` if it is not served, or is not in the format of `md5sum`, i.e. the sum followed by the file name.
+pub fn sidecar_checksum(uri: &str, archive: &str, out_dir: &Path) -> Option<String> {
+    let filename = format!("{}.md5", archive);
+    let downloaded = download(&format!("{}.md5", uri), &filename, out_dir);
+    let content = downloaded.and_then(|_| Ok(fs::read_to_string(out_dir.join(&filename))?));
+    let _ = fs::remove_file(out_dir.join(&filename));
+    let sum = content.ok()?.split_whitespace().next()?.to_lowercase();
+    Some(sum).filter(|sum| sum.len() == 32 && sum.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Directory where the archives are stored, i.e. MKL_ARCHIVE_CACHE_DIR,
+/// or `mkl-archives` in the target directory with MKL_KEEP_ARCHIVE
+///
+/// The archive kept there is extracted again into another install directory, or for another configuration,
+/// without downloading it.
+pub fn archive_cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = env_var("MKL_ARCHIVE_CACHE_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    if !env_flag("MKL_KEEP_ARCHIVE") {
+        return None;
+    }
+    let out_dir = out_dir().ok()?;
+    Some(profile_dir(&out_dir)?.parent()?.join("mkl-archives"))
+}
+
+/// Move the downloaded archive into `archive_cache_dir()` if set, and returns its path
+///
+/// The archive is kept in `out_dir` if it cannot be stored.
+fn keep_archive(archive_path: &Path, archive_cache: Option<&Path>) -> PathBuf {
+    let dir = match archive_cache {
+        Some(dir) => dir,
+        None => return archive_path.to_path_buf(),
+    };
+    match store_archive(archive_path, dir) {
+        Ok(stored) => {
+            println!("Store archive in {}", stored.display());
+            stored
+        }
+        Err(e) => {
+            println!("cargo:warning=Failed to store the archive in {}: {}", dir.display(), e);
+            archive_path.to_path_buf()
+        }
+    }
+}
+
+/// Move the archive into `dir`, and returns the moved path
+///
+/// It is copied under a temporary name and renamed, so that other builds sharing `dir` never find a partial archive.
+pub fn store_archive(archive_path: &Path, dir: &Path) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let name = archive_path.file_name().unwrap().to_string_lossy().into_owned();
+    let dest = dir.join(&name);
+    let temp = dir.join(format!(".{}.{}-{}", name, std::process::id(), now_secs()));
+    let stored = fs::copy(archive_path, &temp).and_then(|_| fs::rename(&temp, &dest));
+    if stored.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    stored?;
+    fs::remove_file(archive_path)?;
+    Ok(dest)
+}
+
+/// Archive in the directory of pre-downloaded archives, i.e. MKL_OFFLINE_ARCHIVE_DIR or MKL_ARCHIVE_CACHE_DIR,
+/// if it matches the checksum
+pub fn find_offline_archive(dir: &Path, archive: &str, md5: &str) -> Option<PathBuf> {
+    let path = dir.join(archive);
+    if !path.exists() {
+        println!("{} is not found, and will be downloaded", path.display());
+        record_attempt(path.display(), "not found");
+        return None;
+    }
+    if calc_md5(&path) != md5 {
+        println!("cargo:warning={} does not match the checksum, and is not used", path.display());
+        record_attempt(path.display(), "checksum mismatch");
+        return None;
+    }
+    Some(path)
+}
+
+// `repodata.json` is large, and reused in this period
+const REPODATA_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Download and parse `repodata.json` of the channel, or reuse the one recently downloaded into `out_dir`
+pub fn fetch_repodata(out_dir: &Path) -> Result<serde_json::Value, BuildError> {
+    // Named by the subdir, since MKL_TARGET_OVERRIDE may change it
+    let filename = format!("repodata-{}.json", conda_subdir());
+    let path = out_dir.join(&filename);
+    let fresh = fs::metadata(&path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|elapsed| elapsed < REPODATA_TTL);
+    if !fresh {
+        download(&format!("{}/{}/repodata.json", channel(), conda_subdir()), &filename, out_dir)?;
+    }
+    let parsed = serde_json::from_reader(BufReader::new(File::open(&path)?));
+    if parsed.is_err() {
+        // Do not reuse a broken one
+        let _ = fs::remove_file(&path);
+    }
+    parsed.map_err(|e| BuildError::Io(e.into()))
+}
+
+/// Download and parse the manifest of MKL_MANIFEST_URL, or reuse the one recently downloaded into `out_dir`
+///
+/// The manifest is a JSON object keyed by the subdir of the channel and the version, e.g.
+/// `{"linux-64": {"2019.1": {"build": "intel_144", "url": "https://...", "md5": "...", "files": ["lib/libmkl_core.a", ...]}}}`,
+/// where `files` is optional. When it is unreachable, the previously downloaded one is used regardless of its age.
+pub fn fetch_manifest(url: &str, out_dir: &Path) -> Result<serde_json::Value, BuildError> {
+    let filename = "mkl-manifest-url.json";
+    let path = out_dir.join(filename);
+    // Downloaded from another URL
+    let source = out_dir.join("mkl-manifest-url.txt");
+    let same_source = fs::read_to_string(&source).ok().as_deref() == Some(url);
+    let fresh = same_source
+        && fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|elapsed| elapsed < REPODATA_TTL);
+    if !fresh {
+        match download(url, filename, out_dir) {
+            Ok(_) => fs::write(&source, url)?,
+            Err(e) if same_source && path.exists() => {
+                println!("cargo:warning=Failed to fetch {}, use the previous one: {}", url, e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let parsed = serde_json::from_reader(BufReader::new(File::open(&path)?));
+    if parsed.is_err() {
+        let _ = fs::remove_file(&path);
+    }
+    parsed.map_err(|e| BuildError::Io(e.into()))
+}
+
+/// Reference of an OCI artifact containing the archive, e.g. `ghcr.io/org/mkl-static:{version}-{build}`, see MKL_OCI_REF
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciRef {
+    /// `https`, or `http` for a registry given as `http://<host>/...`
+    pub scheme: String,
+    /// Host of the registry, with the port if any
+    pub registry: String,
+    pub repository: String,
+    /// Tag, or digest like `sha256:...`
+    pub reference: String,
+}
+
+impl OciRef {
+    /// Parse `<registry>/<repository>[:<tag>|@<digest>]`, where `{version}` and `{build}` are replaced by `version` and `build`
+    ///
+    /// The host is required, except `docker.io` for Docker Hub. The tag defaults to `latest`.
+    pub fn parse(value: &str, version: &str, build: &str) -> Result<Self, String> {
+        let value = value.replace("{version}", version).replace("{build}", build);
+        let (scheme, rest) = match value.split_once("://") {
+            Some((scheme, rest)) if scheme == "http" || scheme == "https" => (scheme.to_string(), rest.to_string()),
+            Some((scheme, _)) => return Err(format!("MKL_OCI_REF {}: unknown scheme `{}`", value, scheme)),
+            None => ("https".to_string(), value.clone()),
+        };
+        let (host, path) = rest
+            .split_once('/')
+            .filter(|(host, _)| host.contains('.') || host.contains(':') || *host == "localhost")
+            .ok_or_else(|| format!("MKL_OCI_REF {}: must start with the host of the registry, e.g. `ghcr.io/`", value))?;
+        let (repository, reference) = match path.split_once('@') {
+            Some((repository, digest)) => (repository, digest),
+            None => match path.rsplit_once(':').filter(|(_, tag)| !tag.contains('/')) {
+                Some((repository, tag)) => (repository, tag),
+                None => (path, "latest"),
+            },
+        };
+        if repository.is_empty() || reference.is_empty() {
+            return Err(format!("MKL_OCI_REF {}: the repository or the tag is empty", value));
+        }
+        Ok(OciRef {
+            scheme,
+            registry: if host == "docker.io" { "registry-1.docker.io".to_string() } else { host.to_string() },
+            repository: repository.to_string(),
+            reference: reference.to_string(),
+        })
+    }
+
+    /// URL of the registry API, e.g. `manifests` of the reference or `blobs` of a digest
+    pub fn url(&self, kind: &str, reference: &str) -> String {
+        format!("{}://{}/v2/{}/{}/{}", self.scheme, self.registry, self.repository, kind, reference)
+    }
+}
+
+/// Digest of the layer named `filename` in the OCI image manifest, or of its only layer
+///
+/// The layers pushed by `oras push` are named by the `org.opencontainers.image.title` annotation.
+pub fn oci_layer(manifest: &serde_json::Value, filename: &str) -> Result<String, String> {
+    let layers = manifest["layers"]
+        .as_array()
+        .ok_or_else(|| "the manifest has no layers, or is an index of multiple platforms".to_string())?;
+    let titled = layers
+        .iter()
+        .find(|layer| layer["annotations"]["org.opencontainers.image.title"].as_str() == Some(filename));
+    let layer = match titled {
+        Some(layer) => layer,
+        None if layers.len() == 1 => &layers[0],
+        None => {
+            let titles: Vec<_> = layers
+                .iter()
+                .filter_map(|layer| layer["annotations"]["org.opencontainers.image.title"].as_str())
+                .collect();
+            return Err(format!("{} is not found in the layers [{}]", filename, titles.join(", ")));
+        }
+    };
+    layer["digest"].as_str().map(str::to_string).ok_or_else(|| "the layer has no digest".to_string())
+}
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 of `data`, for the Basic authentication
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode standard base64, e.g. `auth` of the Docker config
+pub fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let (mut n, mut bits) = (0u32, 0);
+    for c in encoded.trim_end_matches('=').bytes() {
+        n = n << 6 | BASE64.iter().position(|b| *b == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((n >> bits & 0xff) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// (user, password) for `registry` in the Docker config, i.e. `~/.docker/config.json` or `$DOCKER_CONFIG/config.json`
+///
+/// `auths` is read directly, and `credHelpers` or `credsStore` runs `docker-credential-<helper> get`.
+pub fn docker_credentials(config: &serde_json::Value, registry: &str) -> Option<(String, String)> {
+    // Docker Hub is stored by its legacy URL
+    let keys = if registry == "registry-1.docker.io" {
+        vec!["https://index.docker.io/v1/".to_string(), "docker.io".to_string()]
+    } else {
+        vec![registry.to_string(), format!("https://{}", registry)]
+    };
+    for key in &keys {
+        if let Some(auth) = config["auths"][key]["auth"].as_str() {
+            let decoded = String::from_utf8(base64_decode(auth)?).ok()?;
+            let (user, password) = decoded.split_once(':')?;
+            return Some((user.to_string(), password.to_string()));
+        }
+    }
+    let helper = keys
+        .iter()
+        .find_map(|key| config["credHelpers"][key].as_str())
+        .or_else(|| config["credsStore"].as_str())?;
+    let mut child = Command::new(format!("docker-credential-{}", helper))
+        .arg("get")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .ok()?;
+    child.stdin.take()?.write_all(keys[0].as_bytes()).ok()?;
+    let output = child.wait_with_output().ok()?;
+    let credentials: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    Some((
+        credentials["Username"].as_str()?.to_string(),
+        credentials["Secret"].as_str()?.to_string(),
+    ))
+}
+
+/// Auth-params of a `WWW-Authenticate` challenge, e.g. `realm="...",scope="repository:org/mkl:pull,push"`
+///
+/// Quoted values may contain commas and backslash escapes. Returns None if it is malformed.
+pub fn parse_auth_params(params: &str) -> Option<Vec<(String, String)>> {
+    let mut parsed = Vec::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Some(parsed);
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=') {
+            key.push(c);
+        }
+        chars.next()?;
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => value.push(chars.next()?),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                value.push(c);
+            }
+        }
+        parsed.push((key.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+/// Percent-encode `value` for a query, keeping only the unreserved characters of RFC 3986
+pub fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// URL to get a token by the `WWW-Authenticate: Bearer realm="...",service="...",scope="..."` challenge
+pub fn bearer_token_url(challenge: &str) -> Option<String> {
+    let params = challenge.trim().strip_prefix("Bearer ")?;
+    let mut realm = None;
+    let mut query = Vec::new();
+    for (key, value) in parse_auth_params(params)? {
+        match key.as_str() {
+            "realm" => realm = Some(value),
+            _ => query.push(format!("{}={}", percent_encode(&key), percent_encode(&value))),
+        }
+    }
+    let realm = realm?;
+    Some(if query.is_empty() { realm } else { format!("{}?{}", realm, query.join("&")) })
+}
+
+/// Response of the registry, and the `WWW-Authenticate` header of 401
+struct OciResponse {
+    code: u32,
+    challenge: Option<String>,
+    body: Vec<u8>,
+}
+
+/// GET `url` of the registry, writing the body into `out` if given, or returning it otherwise
+///
+/// Blobs are redirected to the storage, where curl does not send the Authorization header.
+fn oci_get(url: &str, headers: &[String], out: Option<&Path>) -> Result<OciResponse, BuildError> {
+    let mut easy = download_handle(url, false)?;
+    let mut list = curl::easy::List::new();
+    for header in headers {
+        list.append(header)?;
+    }
+    easy.http_headers(list)?;
+    let mut challenge = None;
+    let mut body = Vec::new();
+    let _partial = out.map(Partial::new);
+    if out.is_some() {
+        set_phase(format!("downloading {}", url));
+    }
+    let mut file = match out {
+        Some(path) => Some(BufWriter::with_capacity(download_buffer(), File::create(path)?)),
+        None => None,
+    };
+    let mut failed = None;
+    let performed = {
+        let mut transfer = easy.transfer();
+        transfer.header_function(|line| {
+            let line = String::from_utf8_lossy(line);
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("www-authenticate") {
+                    challenge = Some(value.trim().to_string());
+                }
+            }
+            true
+        })?;
+        transfer.write_function(|data| {
+            match file {
+                Some(ref mut file) => {
+                    if let Err(e) = file.write_all(data) {
+                        failed = Some(e);
+                        return Ok(0);
+                    }
+                    report_download(data.len());
+                }
+                None => body.extend_from_slice(data),
+            }
+            Ok(data.len())
+        })?;
+        transfer.perform()
+    };
+    check_redirect(&mut easy, url, performed)?;
+    if let Some(e) = failed {
+        return Err(e.into());
+    }
+    if let Some(mut file) = file {
+        file.flush()?;
+    }
+    Ok(OciResponse {
+        code: easy.response_code()?,
+        challenge,
+        body,
+    })
+}
+
+/// Pull the layer `archive` of MKL_OCI_REF into `out_dir`, and check its MD5 sum
+///
+/// The anonymous request is retried with a Bearer token of the challenge, requested by the credentials of the Docker config.
+pub fn pull_oci(reference: &OciRef, archive: &str, md5: &str, out_dir: &Path) -> Result<PathBuf, BuildError> {
+    let docker_config = env_var("DOCKER_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| var("HOME").ok().map(|home| Path::new(&home).join(".docker")))
+        .and_then(|dir| fs::read_to_string(dir.join("config.json")).ok())
+        .and_then(|config| serde_json::from_str(&config).ok());
+    let credentials = docker_config.and_then(|config| docker_credentials(&config, &reference.registry));
+    let basic = credentials.map(|(user, password)| format!("Basic {}", base64_encode(format!("{}:{}", user, password).as_bytes())));
+    let oci_error = |reason: String| BuildError::Oci {
+        reference: format!("{}/{}:{}", reference.registry, reference.repository, reference.reference),
+        reason,
+    };
+
+    let mut authorization = None;
+    let mut get = |url: &str, accept: Option<&str>, out: Option<&Path>| -> Result<OciResponse, BuildError> {
+        let headers = |authorization: &Option<String>| -> Vec<String> {
+            accept
+                .map(|accept| format!("Accept: {}", accept))
+                .into_iter()
+                .chain(authorization.as_ref().map(|value| format!("Authorization: {}", value)))
+                .collect()
+        };
+        let mut response = oci_get(url, &headers(&authorization), out)?;
+        if response.code == 401 && authorization.is_none() {
+            // Token of the scope, or the credentials themselves for a registry with the Basic authentication
+            authorization = match response.challenge.as_deref().and_then(bearer_token_url) {
+                Some(token_url) => {
+                    let headers: Vec<String> = basic.iter().map(|value| format!("Authorization: {}", value)).collect();
+                    let token = oci_get(&token_url, &headers, None)?;
+                    let token: serde_json::Value = serde_json::from_slice(&token.body).unwrap_or_default();
+                    let token = token["token"].as_str().or_else(|| token["access_token"].as_str());
+                    token.map(|token| format!("Bearer {}", token))
+                }
+                None => basic.clone(),
+            };
+            if authorization.is_some() {
+                response = oci_get(url, &headers(&authorization), out)?;
+            }
+        }
+        if response.code != 200 {
+            return Err(BuildError::HttpStatus {
+                uri: url.to_string(),
+                code: response.code,
+            });
+        }
+        Ok(response)
+    };
+
+    let manifest_url = reference.url("manifests", &reference.reference);
+    let manifest = get(&manifest_url, Some("application/vnd.oci.image.manifest.v1+json"), None)?;
+    let manifest: serde_json::Value = serde_json::from_slice(&manifest.body).map_err(|e| oci_error(e.to_string()))?;
+    let digest = oci_layer(&manifest, archive).map_err(oci_error)?;
+    let path = out_dir.join(archive);
+    println!("Pull {} of {}", digest, manifest_url);
+    get(&reference.url("blobs", &digest), None, Some(&path))?;
+    let actual = calc_md5(&path);
+    if actual != md5 {
+        let _ = fs::remove_file(&path);
+        return Err(BuildError::Checksum {
+            path,
+            expected: md5.to_string(),
+            actual,
+        });
+    }
+    Ok(path)
+}
+
+/// Builds of mkl-static of the version in `repodata.json`, as (build string, MD5 sum, extension) from the latest
+pub fn find_builds(repodata: &serde_json::Value, version: &str) -> Vec<(String, String, &'static str)> {
+    find_package_builds(repodata, "mkl-static", version)
+}
+
+/// Builds of the package `name`, see `find_builds()`
+///
+/// The `.tar.bz2` archive in `packages` is preferred to the `.conda` one in `packages.conda` of the same build.
+pub fn find_package_builds(repodata: &serde_json::Value, name: &str, version: &str) -> Vec<(String, String, &'static str)> {
+    let mut builds: Vec<(&serde_json::Value, &'static str)> = Vec::new();
+    for (key, extension) in &[("packages", ".tar.bz2"), ("packages.conda", ".conda")] {
+        for info in repodata[key].as_object().into_iter().flat_map(|packages| packages.values()) {
+            let listed = info["name"].as_str() == Some(name)
+                && info["version"].as_str() == Some(version)
+                && info["build"].is_string()
+                && info["md5"].is_string();
+            if listed && !builds.iter().any(|(found, _)| found["build"] == info["build"]) {
+                builds.push((info, extension));
+            }
+        }
+    }
+    builds.sort_by_key(|(info, _)| std::cmp::Reverse(info["build_number"].as_u64().unwrap_or(0)));
+    builds
+        .into_iter()
+        .map(|(info, extension)| {
+            (
+                info["build"].as_str().unwrap().to_string(),
+                info["md5"].as_str().unwrap().to_string(),
+                extension,
+            )
+        })
+        .collect()
+}
+
+/// Extract the entries under `prefix` of the archive into `extract_to`
+///
+/// The headers are not listed in advance unlike the libraries, and the whole directory is extracted.
+pub fn extract_dir(archive_path: &Path, prefix: &Path, extract_to: &Path) -> io::Result<usize> {
+    let file = File::open(archive_path)?;
+    let unpack = |tarball: &mut dyn Read| -> io::Result<usize> {
+        let mut count = 0;
+        for entry in Archive::new(tarball).entries()? {
+            let mut entry = entry?;
+            if entry.path()?.starts_with(prefix) && entry.unpack_in(extract_to)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    };
+    if archive_path.extension() == Some("conda".as_ref()) {
+        read_conda_tarball(file, "pkg-", unpack)
+    } else {
+        unpack(&mut BzDecoder::new(file))
+    }
+}
+
+/// Download the mkl-include package of `version` and extract the headers into `out_dir`,
+/// and returns the include directory
+///
+/// `mkl-include.txt` records the version, the build and the MD5 sum of the extracted package,
+/// and the headers are reused by it without fetching `repodata.json`, e.g. offline.
+pub fn provision_headers(version: &str, out_dir: &Path) -> Result<PathBuf, BuildError> {
+    let include_dir = out_dir.join(platform().include_path);
+    let marker = out_dir.join("mkl-include.txt");
+    let extracted = fs::read_to_string(&marker).unwrap_or_default();
+    if extracted.split_whitespace().next() == Some(version) && include_dir.join("mkl.h").exists() {
+        println!("Use existing headers");
+        return Ok(include_dir);
+    }
+    let repodata = fetch_repodata(out_dir)?;
+    let (build, md5, extension) = find_package_builds(&repodata, "mkl-include", version)
+        .into_iter()
+        .next()
+        .ok_or_else(|| BuildError::PackageNotFound {
+            version: version.to_string(),
+            build: None,
+        })?;
+    let archive = format!("mkl-include-{}-{}{}", version, build, extension);
+    let archive_path = out_dir.join(&archive);
+    if !archive_path.exists() || calc_md5(&archive_path) != md5 {
+        let uri = format!("{}/{}/{}", channel(), conda_subdir(), archive);
+        let sum = download(&uri, &archive, out_dir)?;
+        if sum != md5 {
+            return Err(BuildError::Checksum {
+                path: archive_path,
+                expected: md5,
+                actual: sum,
+            });
+        }
+    }
+    let _ = fs::remove_dir_all(&include_dir);
+    extract_dir(&archive_path, Path::new(platform().include_path), out_dir)?;
+    fs::write(&marker, format!("{} {} {}", version, build, md5))?;
+    Ok(include_dir)
+}
+
+/// Extract `files` from the latest build of the package `name` of `version` into `out_dir`,
+/// unless they have been extracted
+///
+/// The checksum is taken from `repodata.json`, and each file is verified by the manifest as the libraries of MKL.
+pub fn provision_package(name: &str, version: &str, out_dir: &Path, files: &[PathBuf]) -> Result<(), BuildError> {
+    let bad = verify_files(out_dir, files);
+    if bad.is_empty() {
+        return Ok(());
+    }
+    ensure_provisioner(name);
+    let repodata = fetch_repodata(out_dir)?;
+    let (build, md5, extension) = find_package_builds(&repodata, name, version)
+        .into_iter()
+        .next()
+        .ok_or_else(|| BuildError::PackageNotFound {
+            version: version.to_string(),
+            build: None,
+        })?;
+    let archive = format!("{}-{}-{}{}", name, version, build, extension);
+    let uri = format!("{}/{}/{}", channel(), conda_subdir(), archive);
+    println!("Download {}", archive);
+    download_archive(&uri, &archive, &md5, out_dir, &bad)
+}
+
+/// Install name in the output of `otool -D`, i.e. the second line
+pub fn parse_otool_id(output: &str) -> Option<String> {
+    output.lines().nth(1).map(|line| line.trim().to_string()).filter(|id| !id.is_empty())
+}
+
+/// Dependencies in the output of `otool -L`, without the first line of the file name and the versions
+pub fn parse_otool_deps(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.trim().split(" (").next())
+        .filter(|dep| !dep.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn run_tool(program: &str, args: &[&str]) -> String {
+    let output = Command::new(program).args(args).output().unwrap_or_else(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            panic!("{} is not found. Install the Xcode command line tools", program)
+        }
+        panic!("Failed to run {}: {}", program, e)
+    });
+    if !output.status.success() {
+        panic!(
+            "{} {} failed:\n{}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Make the dylibs of MKL in `lib_dir` loadable through the rpath on macOS, and returns the directory to be linked
+///
+/// Dylibs whose install names are absolute paths, e.g. of a conda prefix, are copied into `out_dir`,
+/// and rewritten to `@rpath/<name>` by `install_name_tool`. `lib_dir` itself is never modified.
+fn relocatable_dylibs(lib_dir: &Path, out_dir: &Path) -> PathBuf {
+    let mut dylibs: Vec<PathBuf> = fs::read_dir(lib_dir)
+        .unwrap_or_else(|e| panic!("Cannot read {}: {}", lib_dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            (name.starts_with("libmkl_") || name.starts_with("libiomp5")) && name.ends_with(".dylib")
+        })
+        .collect();
+    dylibs.sort();
+    let names: Vec<String> = dylibs
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect();
+    let relocatable = dylibs.iter().all(|path| {
+        let id = parse_otool_id(&run_tool("otool", &["-D", &path.to_string_lossy()]));
+        id.is_some_and(|id| id.starts_with("@rpath/"))
+    });
+    if relocatable {
+        return lib_dir.to_path_buf();
+    }
+
+    let dir = out_dir.join("rpath-lib");
+    fs::create_dir_all(&dir).unwrap();
+    for (path, name) in dylibs.iter().zip(&names) {
+        let dest = dir.join(name);
+        let dest_str = dest.to_string_lossy().into_owned();
+        fs::copy(path, &dest).unwrap();
+        let id = format!("@rpath/{}", name);
+        run_tool("install_name_tool", &["-id", &id, &dest_str]);
+        for dep in parse_otool_deps(&run_tool("otool", &["-L", &dest_str])) {
+            let dep_name = Path::new(&dep).file_name().map(|n| n.to_string_lossy().into_owned());
+            if dep.starts_with('/') && dep_name.as_ref().is_some_and(|n| names.contains(n)) {
+                let new = format!("@rpath/{}", dep_name.unwrap());
+                run_tool("install_name_tool", &["-change", &dep, &new, &dest_str]);
+            }
+        }
+        println!("Rewrite the install name of {} to {}", name, id);
+    }
+    dir
+}
+
+/// Strip the `\\?\` prefix of a verbatim path returned by `fs::canonicalize()` on Windows
+///
+/// The linkers do not accept it in the library search paths, while `C:\` and UNC paths are kept as they are.
+pub fn strip_verbatim(path: &Path) -> PathBuf {
+    match path.to_str().and_then(|s| s.strip_prefix(r"\\?\")) {
+        Some(rest) if rest.starts_with(r"UNC\") => PathBuf::from(format!(r"\\{}", &rest[4..])),
+        Some(rest) if rest.as_bytes().get(1) == Some(&b':') => PathBuf::from(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Absolute path of the existing directory `dir`, without symlinks nor the verbatim prefix
+pub fn normalize_dir(name: &str, dir: &Path) -> Result<PathBuf, String> {
+    let path = fs::canonicalize(dir).map_err(|e| format!("{} {} is not found: {}", name, dir.display(), e))?;
+    if !path.is_dir() {
+        return Err(format!("{} {} is not a directory", name, dir.display()));
+    }
+    Ok(strip_verbatim(&path))
+}
+
+/// OUT_DIR normalized by `normalize_dir()`
+pub fn out_dir() -> Result<PathBuf, String> {
+    let dir = var("OUT_DIR").map_err(|_| "OUT_DIR is not set, and the build script must be run by cargo".to_string())?;
+    normalize_dir("OUT_DIR", Path::new(&dir))
+}
+
+/// Directory of the profile, e.g. `target/debug`, where the executables are placed
+pub fn profile_dir(out_dir: &Path) -> Option<PathBuf> {
+    // OUT_DIR is `<target>/<profile>/build/intel-mkl-src-<hash>/out`
+    out_dir
+        .ancestors()
+        .find(|dir| dir.file_name() == Some("build".as_ref()))?
+        .parent()
+        .map(Path::to_path_buf)
+}
+
+/// Library directories of MKLROOT used by the versions of MKL, in the order probed by `probe_lib_dir()`
+pub fn mklroot_layouts(target_os: &str) -> &'static [&'static str] {
+    match target_os {
+        "windows" => &["lib", "lib\\intel64", "lib\\intel64_win"],
+        "macos" => &["lib"],
+        _ => &["lib", "lib/intel64", "lib/intel64_lin"],
+    }
+}
+
+/// First library directory of `mklroot_layouts()` under `root` containing the libraries of `config`,
+/// or all of the probed directories
+pub fn probe_lib_dir(root: &Path, config: &MklConfig, target_os: &str) -> Result<PathBuf, Vec<PathBuf>> {
+    let probed = mklroot_layouts(target_os).iter().map(|layout| root.join(layout)).collect();
+    find_lib_dir(probed, config, target_os)
+}
+
+/// Library directories of the pip wheels of MKL, `mkl`, `mkl-static`, and `mkl-devel`, installed in the virtualenv `venv`
+///
+/// The wheels install the libraries into the data directory of the environment, i.e. `lib` (`Library\\lib` on Windows).
+/// `mkl/lib` in site-packages is also probed, where some of the repackaged wheels place them.
+pub fn pip_lib_dirs(venv: &Path, target_os: &str) -> Vec<PathBuf> {
+    if target_os == "windows" {
+        return vec![venv.join("Library\\lib"), venv.join("Lib\\site-packages\\mkl\\lib")];
+    }
+    let mut dirs = vec![venv.join("lib")];
+    // `lib/python3.X/site-packages`
+    let mut site_packages: Vec<PathBuf> = fs::read_dir(venv.join("lib"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with("python"))
+        .map(|path| path.join("site-packages/mkl/lib"))
+        .collect();
+    site_packages.sort();
+    dirs.extend(site_packages);
+    dirs
+}
+
+/// First directory of `probed` containing the libraries of `config`, or all of the probed directories
+pub fn find_lib_dir(probed: Vec<PathBuf>, config: &MklConfig, target_os: &str) -> Result<PathBuf, Vec<PathBuf>> {
+    // `mkl_rt.lib` is the import library on Windows
+    let has_libraries = |dir: &Path| {
+        if config.rt && target_os != "windows" {
+            find_runtime_lib(dir, target_os).is_some()
+        } else {
+            mkl_files(config).iter().all(|file| dir.join(file.file_name().unwrap()).exists())
+        }
+    };
+    probed.iter().find(|dir| has_libraries(dir)).cloned().ok_or(probed)
+}
+
+/// Shared `mkl_rt` of `target_os` in `dir`, e.g. `libmkl_rt.so`, `libmkl_rt.dylib`, or `mkl_rt.1.dll`
+pub fn find_runtime_lib(dir: &Path, target_os: &str) -> Option<PathBuf> {
+    let mut found: Vec<PathBuf> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().to_lowercase();
+            match target_os {
+                "windows" => name.starts_with("mkl_rt") && name.ends_with(".dll"),
+                "macos" => name.starts_with("libmkl_rt") && name.ends_with(".dylib"),
+                _ => name.starts_with("libmkl_rt.so"),
+            }
+        })
+        .collect();
+    found.sort();
+    found.into_iter().next()
+}
+
+/// Copy the DLLs of MKL in `bin_dir`, i.e. `mkl_rt` and the layers loaded by it, into `to`,
+/// and returns the copied files
+///
+/// Each copy is checked by the MD5 sum of the original.
+pub fn copy_runtime_dlls(bin_dir: &Path, to: &Path) -> Result<Vec<PathBuf>, BuildError> {
+    let mut copied = Vec::new();
+    for entry in fs::read_dir(bin_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_lowercase();
+        if !(name.starts_with("mkl_") || name.starts_with("libiomp5")) || !name.ends_with(".dll") {
+            continue;
+        }
+        let dest = to.join(path.file_name().unwrap());
+        let expected = calc_md5(&path);
+        if !dest.exists() || calc_md5(&dest) != expected {
+            fs::copy(&path, &dest)?;
+            let actual = calc_md5(&dest);
+            if actual != expected {
+                return Err(BuildError::Checksum {
+                    path: dest,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        copied.push(dest);
+    }
+    copied.sort();
+    Ok(copied)
+}
+
+/// Directory of the shared cache, keyed by the platform, the archives, and the selected layers
+///
+/// The default is `mkl-cache` next to the profile directories, e.g. `target/mkl-cache`,
+/// so that debug and release builds share the libraries.
+pub fn cache_dir(
+    out_dir: &Path,
+    cache_root: Option<&Path>,
+    package: &Package,
+    config: &MklConfig,
+) -> Option<PathBuf> {
+    let root = match cache_root {
+        Some(root) => root.to_path_buf(),
+        None => profile_dir(out_dir)?.parent()?.join("mkl-cache"),
+    };
+    Some(root.join(conda_subdir()).join(cache_key(package, config)))
+}
+
+/// Key of the shared cache, which changes only by the MKL-related configuration
+pub fn cache_key(package: &Package, config: &MklConfig) -> String {
+    format!(
+        "{}-{}-{}-{}{}",
+        package.version,
+        package.build.as_ref().map_or("latest", |b| b.as_str()),
+        config.interface.name(),
+        config.threading.feature(),
+        if strip_libs() { "-stripped" } else { "" }
+    )
+}
+
+/// MKL_STRIP_LIBS, which strips the debug sections of the extracted libraries
+fn strip_libs() -> bool {
+    static STRIP: OnceLock<bool> = OnceLock::new();
+    *STRIP.get_or_init(|| env_flag("MKL_STRIP_LIBS"))
+}
+
+/// Strip the debug sections of `files` in `dir`, keeping the symbol table for static linking,
+/// and update the manifest. Returns the total size before and after.
+///
+/// The host `strip` is used, and only static libraries of the host platform are supported.
+pub fn strip_files(dir: &Path, files: &[PathBuf]) -> io::Result<(u64, u64)> {
+    if platform().name != std::env::consts::OS || platform().lib_ext != "a" {
+        return Err(io::Error::other(format!(
+            "stripping the libraries of {} is not supported on {}",
+            platform().name,
+            std::env::consts::OS
+        )));
+    }
+    // `--strip-debug` is not supported by `strip` of macOS, where `-S` is the same
+    let flag = if platform().name == "macos" { "-S" } else { "--strip-debug" };
+    let (mut before, mut after) = (0, 0);
+    let mut entries = Vec::new();
+    set_phase(format!("stripping {}", dir.display()));
+    for file in files {
+        report_progress();
+        let path = dir.join(file);
+        let meta = fs::metadata(&path)?;
+        before += meta.len();
+        let status = Command::new("strip").arg(flag).arg(&path).status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("strip {} failed: {}", path.display(), status)));
+        }
+        // Keep the time for `cargo:rerun-if-changed`
+        File::options().write(true).open(&path)?.set_modified(meta.modified()?)?;
+        let size = fs::metadata(&path)?.len();
+        after += size;
+        entries.push((calc_md5(&path), size));
+    }
+    write_manifest(dir, files, &entries)?;
+    Ok((before, after))
+}
+
+/// Hard link (or copy if not possible) `files` and the manifest from `from` to `to`
+pub fn link_files(from: &Path, to: &Path, files: &[PathBuf]) -> io::Result<()> {
+    for file in files {
+        let dest = to.join(file);
+        fs::create_dir_all(dest.parent().unwrap())?;
+        if dest.exists() {
+            fs::remove_file(&dest)?;
+        }
+        if fs::hard_link(from.join(file), &dest).is_err() {
+            fs::copy(from.join(file), &dest)?;
+            File::options()
+                .write(true)
+                .open(&dest)?
+                .set_modified(fs::metadata(from.join(file))?.modified()?)?;
+        }
+    }
+    // The manifest is rewritten in place by `extract()`, and must not be shared
+    fs::copy(from.join(MANIFEST), to.join(MANIFEST))?;
+    Ok(())
+}
+
+/// Move `files` in `dir` into the content-addressed `store` of MKL_CONTENT_STORE, and hard link them back.
+/// Returns the bytes shared with the objects already stored, e.g. by another version or project.
+///
+/// Objects are named by the MD5 sum in the manifest, e.g. `<store>/37/37e3a60ff2643cf40b5cf9d2c183588c`,
+/// and `dir` keeps its layout as the view of the version searched by the linker.
+/// A file on another filesystem than the store is copied there, and is not shared.
+pub fn store_files(dir: &Path, store: &Path, files: &[PathBuf]) -> io::Result<u64> {
+    let mut shared = 0;
+    set_phase(format!("storing {} into {}", dir.display(), store.display()));
+    for (path, md5) in manifest_checksums(dir, files) {
+        report_progress();
+        let object = store.join(&md5[..2]).join(&md5);
+        let temp = object.with_extension(format!("tmp-{}", std::process::id()));
+        fs::create_dir_all(object.parent().unwrap())?;
+        // An object changed in the store is replaced
+        if object.exists() && calc_md5(&object) == md5 {
+            if fs::hard_link(&object, &temp).is_ok() {
+                shared += fs::metadata(&object)?.len();
+                fs::rename(&temp, &path)?;
+            }
+            continue;
+        }
+        if fs::hard_link(&path, &temp).is_err() {
+            fs::copy(&path, &temp)?;
+        }
+        // Renamed, so that concurrent builds never see a partial object
+        fs::rename(&temp, &object)?;
+    }
+    Ok(shared)
+}
+
+/// Whether files can be created in `dir`, creating it if not exists
+pub fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(".mkl-write-test");
+    let writable = fs::create_dir_all(dir).is_ok() && File::create(&probe).is_ok();
+    let _ = fs::remove_file(&probe);
+    writable
+}
+
+// Cache key of the libraries extracted into `out_dir`, not to reuse them after MKL_VERSION changes
+const PACKAGE_MARKER: &str = "mkl-package.txt";
+
+/// Download and extract the libraries into `out_dir` unless they have been extracted,
+/// and returns the directory to be linked from
+///
+/// The cache is keyed by the version, so that switching MKL_VERSION between cached versions
+/// does not access the network.
+///
+/// Verified libraries in `cache_dir` are used directly without writing anything there,
+/// so that the cache may be read-only. Newly extracted libraries are stored there if it is writable.
+///
+/// Panics if it fails, with the sources tried, see `try_provision()`.
+pub fn provision(
+    package: &Package,
+    pinned: bool,
+    config: &MklConfig,
+    out_dir: &Path,
+    cache_dir: Option<&Path>,
+) -> PathBuf {
+    try_provision(package, pinned, config, out_dir, cache_dir)
+        .unwrap_or_else(|e| panic!("{}\n\nTried to provision MKL from:\n{}", e, attempts_summary()))
+}
+
+/// Same as `provision()`, returning the error instead of panicking
+pub fn try_provision(
+    package: &Package,
+    pinned: bool,
+    config: &MklConfig,
+    out_dir: &Path,
+    cache_dir: Option<&Path>,
+) -> Result<PathBuf, BuildError> {
+    let files = mkl_files(config);
+    let key = cache_key(package, config);
+    // Verified just before by `Source::Cache` of MKL_SOURCE_PRIORITY, for the same layers
+    let found = PROVISIONED.lock().unwrap().take().filter(|(_, verified)| *verified == files).map(|(dir, _)| dir);
+    if found.as_deref() == Some(out_dir) {
+        println!("Use existing libraries");
+        return Ok(out_dir.to_path_buf());
+    }
+    if let (Some(dir), Some(cache_dir)) = (found, cache_dir) {
+        if dir == cache_dir {
+            println!("Use cached libraries in {}", cache_dir.display());
+            return Ok(dir);
+        }
+    }
+    let marker = out_dir.join(PACKAGE_MARKER);
+    // Libraries of another package are extracted again
+    let bad = if fs::read_to_string(&marker).ok().as_deref() == Some(key.as_str()) {
+        verify_files(out_dir, &files)
+    } else {
+        files.clone()
+    };
+    if bad.is_empty() {
+        // regardless of the archive, which may have been removed
+        println!("Use existing libraries");
+        return Ok(out_dir.to_path_buf());
+    }
+    record_attempt(
+        out_dir.display(),
+        format!("{} of {} libraries are missing or changed", bad.len(), files.len()),
+    );
+    if let Some(cache_dir) = cache_dir {
+        if check_files(cache_dir, &files) {
+            println!("Use cached libraries in {}", cache_dir.display());
+            return Ok(cache_dir.to_path_buf());
+        }
+        record_attempt(cache_dir.display(), "not populated, or changed");
+    }
+    ensure_provisioner("MKL");
+    // Valid libraries are kept, and only the others are extracted
+    let archive = fetch_archive(package, pinned, out_dir, &bad)?;
+    if let Err(e) = check_arch(&package.filename(), out_dir, &bad) {
+        // Extracted again by the next build, which would verify them by the manifest otherwise
+        for file in &bad {
+            let _ = fs::remove_file(out_dir.join(file));
+        }
+        return Err(BuildError::Architecture(e));
+    }
+    if env_flag("MKL_VERIFY_INDEX") {
+        // Named by the build actually requested, e.g. an alternative to a removed one
+        let filename = archive.file_name().unwrap().to_string_lossy().into_owned();
+        let checked = read_package_index(&archive)
+            .map_err(|e| format!("Failed to read info/index.json of {}: {}", archive.display(), e))
+            .and_then(|index| check_package_index(&index, &filename, &package.version));
+        if let Err(e) = checked {
+            for file in &bad {
+                let _ = fs::remove_file(out_dir.join(file));
+            }
+            return Err(BuildError::PackageIndex(e));
+        }
+        println!("Confirmed {} by info/index.json", filename);
+    }
+    // The verified libraries are used by the later builds without the archive.
+    // Archives shared in MKL_OFFLINE_ARCHIVE_DIR or `archive_cache_dir()` are kept.
+    if env_flag("MKL_REMOVE_ARCHIVE") && archive.starts_with(out_dir) {
+        println!("Remove archive {}", archive.display());
+        fs::remove_file(&archive)?;
+    }
+    if strip_libs() {
+        let (before, after) = strip_files(out_dir, &bad).unwrap_or_else(|e| panic!("MKL_STRIP_LIBS: {}", e));
+        println!(
+            "cargo:warning=MKL_STRIP_LIBS: stripped the debug sections, {} MiB -> {} MiB",
+            before >> 20,
+            after >> 20
+        );
+    }
+    if let Some(store) = env_var("MKL_CONTENT_STORE") {
+        match store_files(out_dir, Path::new(&store), &bad) {
+            Ok(shared) => println!("Stored libraries in {}, {} MiB shared with the other versions", store, shared >> 20),
+            Err(e) => println!("cargo:warning=Failed to store libraries in MKL_CONTENT_STORE {}: {}", store, e),
+        }
+    }
+    fs::write(&marker, &key)?;
+    match cache_dir {
+        Some(cache_dir) if is_writable(cache_dir) => {
+            if let Err(e) = link_files(out_dir, cache_dir, &files) {
+                println!("cargo:warning=Failed to store libraries in {}: {}", cache_dir.display(), e);
+            }
+        }
+        Some(cache_dir) => println!("Cache {} is not writable, libraries are not stored", cache_dir.display()),
+        None => {}
+    }
+    Ok(out_dir.to_path_buf())
+}
+
+/// MKL provisioned by `provision_mkl()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provisioned {
+    /// Directory of the libraries to be linked
+    pub lib_dir: PathBuf,
+    /// Directory of the static Intel OpenMP runtime of `static-openmp`
+    pub openmp_dir: Option<PathBuf>,
+    /// Configuration provisioned, which is the sequential one if MKL_THREADING_FALLBACK applies
+    pub config: MklConfig,
+    pub version: String,
+    /// Paths of the verified libraries, and their MD5 sums
+    pub verified: Vec<(PathBuf, String)>,
+}
+
+/// Package of MKL_VERSION and MKL_BUILD_STRING, listed in MKL_MANIFEST_URL,
+/// and whose checksum is taken from `repodata.json` with MKL_VERIFY_FROM_REPODATA
+///
+/// The manifest and `repodata.json` are cached in `install_dir`.
+pub fn select_package(config: &MklConfig, install_dir: &Path) -> Result<Package, BuildError> {
+    let build = env_var("MKL_BUILD_STRING");
+    let mut package = Package::select(env_var("MKL_VERSION").as_deref(), build.as_deref());
+    if let Some(url) = env_var("MKL_MANIFEST_URL") {
+        match fetch_manifest(&url, install_dir) {
+            Ok(manifest) => match Package::from_manifest(&manifest, &package.version, build.as_deref(), &mkl_files(config)) {
+                Ok(Some(listed)) => {
+                    println!("Use {} listed in {}", listed.uri(), url);
+                    package = listed;
+                }
+                Ok(None) => println!("cargo:warning=MKL {} is not listed in {}, use the channel", package.version, url),
+                Err(reason) => return Err(BuildError::Manifest { url, reason }),
+            },
+            Err(e) => println!("cargo:warning=Failed to fetch {}, use the built-in archives: {}", url, e),
+        }
+    }
+    check_min_version(&package.version);
+    if env_flag("MKL_VERIFY_FROM_REPODATA") && package.build.is_some() {
+        let found = fetch_repodata(install_dir).map(|repodata| {
+            find_builds(&repodata, &package.version)
+                .into_iter()
+                .find(|(build, _, _)| Some(build) == package.build.as_ref())
+        });
+        match found {
+            Ok(Some((_, md5, extension))) => {
+                package.md5 = Some(md5);
+                if package.url.is_none() {
+                    package.extension = extension;
+                }
+            }
+            Ok(None) => {
+                return Err(BuildError::PackageNotFound {
+                    version: package.version,
+                    build: package.build,
+                })
+            }
+            // Checksum in `platform().builds` is used if exists
+            Err(e) => println!("cargo:warning=Failed to fetch repodata.json: {}", e),
+        }
+    }
+    Ok(package)
+}
+
+/// Provision `package` for `config` into `install_dir` as the build script does, e.g. from a setup script outside of cargo
+///
+/// The libraries are shared by MKL_CACHE_DIR, or `mkl-cache` of the target directory when `out_dir` is OUT_DIR.
+/// Other settings are also read from the environment, and the invalid ones panic as in the build script.
+pub fn provision_mkl(package: &Package, config: &MklConfig, install_dir: &Path, out_dir: &Path) -> Result<Provisioned, BuildError> {
+    let mut config = config.clone();
+    let mut openmp_dir = None;
+    let mut verified = Vec::new();
+    if config.static_openmp {
+        match provision_package("intel-openmp", &package.version, install_dir, &config.openmp_files()) {
+            Ok(()) => {
+                openmp_dir = Some(install_dir.join(lib_path()));
+                verified.extend(manifest_checksums(install_dir, &config.openmp_files()));
+            }
+            Err(e) => config = threading_fallback(&config, e),
+        }
+    }
+    // Keyed by the threading layer actually linked
+    let cache_root = env_var("MKL_CACHE_DIR").map(PathBuf::from);
+    let cache_dir = cache_dir(out_dir, cache_root.as_deref(), package, &config);
+    let pinned = env_var("MKL_BUILD_STRING").is_some();
+    let root = try_provision(package, pinned, &config, install_dir, cache_dir.as_deref())?;
+    verified.extend(manifest_checksums(&root, &mkl_files(&config)));
+    Ok(Provisioned {
+        lib_dir: root.join(lib_path()),
+        openmp_dir,
+        config,
+        version: package.version.clone(),
+        verified,
+    })
+}
+
+/// Total wall time and bytes of a phase of provisioning, reported by MKL_STATS
+pub struct PhaseStats {
+    nanos: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl PhaseStats {
+    const fn new() -> Self {
+        PhaseStats {
+            nanos: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, start: Instant, bytes: u64) {
+        self.nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+// The extraction runs while downloading, and their times overlap
+static DOWNLOAD_STATS: PhaseStats = PhaseStats::new();
+static EXTRACT_STATS: PhaseStats = PhaseStats::new();
+static VERIFY_STATS: PhaseStats = PhaseStats::new();
+
+/// e.g. `download: 12.3 s, 420 MiB, 34.1 MiB/s`
+pub fn format_phase(name: &str, elapsed: Duration, bytes: u64) -> String {
+    let mib = bytes as f64 / (1 << 20) as f64;
+    let secs = elapsed.as_secs_f64();
+    let throughput = if secs > 0.0 { mib / secs } else { 0.0 };
+    format!("{}: {:.1} s, {:.0} MiB, {:.1} MiB/s", name, secs, mib, throughput)
+}
+
+/// Show the phases of provisioning in this build as warnings, if MKL_STATS is set
+fn report_stats() {
+    if !env_flag("MKL_STATS") {
+        return;
+    }
+    for (name, stats) in &[("download", &DOWNLOAD_STATS), ("extract", &EXTRACT_STATS), ("verify", &VERIFY_STATS)] {
+        let elapsed = Duration::from_nanos(stats.nanos.load(Ordering::Relaxed));
+        let bytes = stats.bytes.load(Ordering::Relaxed);
+        println!("cargo:warning=MKL_STATS: {}", format_phase(name, elapsed, bytes));
+    }
+}
+
+/// Sources of MKL, tried in the order of MKL_SOURCE_PRIORITY
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// MKL_LIB_DIR, MKL_COMBINED_LIB, or MKLROOT with MKL_USE_MKLROOT
+    System,
+    /// MKL installed by pip in VIRTUAL_ENV
+    Pip,
+    /// Libraries verified in the install directory or the shared cache, without downloading anything
+    Cache,
+    /// The mkl-static package, where the verified libraries are still reused
+    Download,
+}
+
+impl Source {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Source::System => "system",
+            Source::Pip => "pip",
+            Source::Cache => "cache",
+            Source::Download => "download",
+        }
+    }
+}
+
+/// Order of the sources without MKL_SOURCE_PRIORITY
+pub const DEFAULT_SOURCE_PRIORITY: &[Source] = &[Source::System, Source::Pip, Source::Cache, Source::Download];
+
+/// Parse MKL_SOURCE_PRIORITY, e.g. `cache,system,download`. Sources not listed are never used.
+pub fn parse_source_priority(value: &str) -> Result<Vec<Source>, String> {
+    let mut sources = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        let source = DEFAULT_SOURCE_PRIORITY
+            .iter()
+            .copied()
+            .find(|source| source.name() == name)
+            .ok_or_else(|| format!("unknown source `{}`, expected `system`, `pip`, `cache`, or `download`", name))?;
+        if sources.contains(&source) {
+            return Err(format!("`{}` is listed twice", name));
+        }
+        sources.push(source);
+    }
+    if sources.is_empty() {
+        return Err("no sources are listed".to_string());
+    }
+    Ok(sources)
+}
+
+// Set when `download` is not in MKL_SOURCE_PRIORITY
+static DOWNLOAD_DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Directory of the libraries of `package` already provisioned and verified, i.e. `out_dir` or `cache_dir`
+pub fn find_provisioned(package: &Package, config: &MklConfig, out_dir: &Path, cache_dir: Option<&Path>) -> Option<PathBuf> {
+    let files = mkl_files(config);
+    let key = cache_key(package, config);
+    let found = if fs::read_to_string(out_dir.join(PACKAGE_MARKER)).ok().as_deref() == Some(key.as_str()) && check_files(out_dir, &files) {
+        Some(out_dir.to_path_buf())
+    } else {
+        cache_dir.filter(|dir| check_files(dir, &files)).map(Path::to_path_buf)
+    };
+    if let Some(ref dir) = found {
+        *PROVISIONED.lock().unwrap() = Some((dir.clone(), files));
+    }
+    found
 }
 
-#[cfg(target_os = "macos")]
-mod mkl {
-    pub const LIB_PATH: &'static str = "lib";
+/// Directory and the files verified by `find_provisioned()`, taken by the next `try_provision()` not to hash them again
+static PROVISIONED: Mutex<Option<(PathBuf, Vec<PathBuf>)>> = Mutex::new(None);
+
+/// Fail unless this build may provision `what`, see MKL_CONSUMER_ONLY
+///
+/// A consumer build only links the libraries provisioned by a provisioner build into the shared directory,
+/// and never downloads them by itself.
+fn ensure_provisioner(what: &str) {
+    if DOWNLOAD_DISABLED.load(Ordering::Relaxed) {
+        panic!(
+            "`download` is not in MKL_SOURCE_PRIORITY, but {} has not been provisioned.\n\n\
+             Tried to provision MKL from:\n{}",
+            what,
+            attempts_summary()
+        );
+    }
+    if env_flag("MKL_CONSUMER_ONLY") {
+        panic!(
+            "MKL_CONSUMER_ONLY is set, but {} has not been provisioned. \
+             Build once with MKL_PROVISIONER_ONLY=1 and the same MKL_CACHE_DIR or MKL_INSTALL_DIR first.\n\n\
+             Tried to provision MKL from:\n{}",
+            what,
+            attempts_summary()
+        );
+    }
+}
 
-    pub const DLS: &[(&'static str, &'static str, &'static str)] = &[
-        ("mkl-static-2019.1-intel_144.tar.bz2", 
-         "https://conda.anaconda.org/intel/osx-64/mkl-static-2019.1-intel_144.tar.bz2", 
-         "74a186a5e325146c7de7e1e1c8fc3bc3")
+/// Source which `provision()` will take the libraries from and why, without downloading or extracting anything
+///
+/// The libraries are verified as `provision()` does. See MKL_EXPLAIN.
+pub fn explain_provision(package: &Package, config: &MklConfig, out_dir: &Path, cache_dir: Option<&Path>) -> String {
+    let files = mkl_files(config);
+    let key = cache_key(package, config);
+    if fs::read_to_string(out_dir.join(PACKAGE_MARKER)).ok().as_deref() == Some(key.as_str())
+        && check_files(out_dir, &files)
+    {
+        return format!("use the libraries extracted in {}, verified for {}", out_dir.display(), key);
+    }
+    if let Some(cache_dir) = cache_dir.filter(|dir| check_files(dir, &files)) {
+        return format!("use the shared cache at {}, verified for {}", cache_dir.display(), key);
+    }
+    if env_flag("MKL_CONSUMER_ONLY") {
+        return format!("fail, since MKL_CONSUMER_ONLY is set and {} has not been provisioned", key);
+    }
+    let md5 = match (package.build.as_ref(), package.md5.as_ref()) {
+        (Some(_), Some(md5)) => md5,
+        _ => {
+            return format!(
+                "resolve the build of MKL {} by repodata.json of {}, then download it",
+                package.version,
+                channel()
+            )
+        }
+    };
+    let archive = package.filename();
+    let dirs = [
+        ("MKL_OFFLINE_ARCHIVE_DIR", env_var("MKL_OFFLINE_ARCHIVE_DIR").map(PathBuf::from)),
+        ("MKL_ARCHIVE_CACHE_DIR", archive_cache_dir()),
+        ("the archive left in the install directory", Some(out_dir.to_path_buf())),
     ];
+    for (name, dir) in &dirs {
+        let path = match dir {
+            Some(dir) => dir.join(&archive),
+            None => continue,
+        };
+        if path.exists() && calc_md5(&path) == *md5 {
+            return format!("extract {} of {}, which matches the checksum", path.display(), name);
+        }
+    }
+    format!("download {}, since no verified libraries or archives are found", package.uri())
 }
 
-#[cfg(target_os = "windows")]
-mod mkl {
-    pub const LIB_PATH: &'static str = "Library\\lib";
+// (source, result) tried by `provision()`, summarized when all of them fail
+static ATTEMPTS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
 
-    pub const DLS: &[(&'static str, &'static str, &'static str)] = &[
-        ("mkl-static-2019.1-intel_144.tar.bz2", 
-         "https://conda.anaconda.org/intel/win-64/mkl-static-2019.1-intel_144.tar.bz2", 
-         "0b65a55b6bcda83392e9defff8e1edbe")
-    ];
+fn record_attempt<S: fmt::Display, R: fmt::Display>(source: S, result: R) {
+    ATTEMPTS.lock().unwrap().push((source.to_string(), result.to_string()));
 }
 
-fn download(uri: &str, filename: &str, out_dir: &Path) {
+/// Sources tried to provision MKL and their results, one per line
+pub fn attempts_summary() -> String {
+    let attempts = ATTEMPTS.lock().unwrap();
+    let lines: Vec<_> = attempts
+        .iter()
+        .map(|(source, result)| format!("  {} -> {}", source, result))
+        .collect();
+    lines.join("\n")
+}
 
-    let out = PathBuf::from(out_dir.join(filename));
+// Time of the last progress of provisioning, in seconds since UNIX epoch
+static LAST_PROGRESS: AtomicU64 = AtomicU64::new(0);
 
-    // Download the tarball.
-    let f = File::create(&out).unwrap();
-    let mut writer = BufWriter::new(f);
-    let mut easy = Easy::new();
-    easy.follow_location(true).unwrap();
-    easy.autoreferer(true).unwrap();
-    easy.url(&uri).unwrap();
-    easy.write_function(move |data| {
-        Ok(writer.write(data).unwrap())
-    }).unwrap();
-    easy.perform().unwrap();
-
-    let response_code = easy.response_code().unwrap();
-    if response_code != 200 {
-        panic!("Unexpected response code {} for {}", response_code, uri);
+// Bytes downloaded while provisioning, which extend the deadline of `Watchdog`
+static DOWNLOADED: AtomicU64 = AtomicU64::new(0);
+
+// What provisioning is doing, named when `Watchdog` aborts it
+static PHASE: Mutex<String> = Mutex::new(String::new());
+
+// Partial archives and staging directories, removed when `Watchdog` aborts provisioning
+static PARTIAL: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Record that the download, the extraction or the check of a file is progressing, see `Watchdog`
+fn report_progress() {
+    LAST_PROGRESS.store(now_secs(), Ordering::Relaxed);
+}
+
+/// Record that `len` bytes of the download have arrived, see `Watchdog`
+fn report_download(len: usize) {
+    DOWNLOADED.fetch_add(len as u64, Ordering::Relaxed);
+    report_progress();
+}
+
+/// Name what provisioning is doing from now, e.g. `verifying <dir>`
+fn set_phase(phase: String) {
+    *PHASE.lock().unwrap() = phase;
+    report_progress();
+}
+
+/// A file or a directory removed if provisioning is aborted before dropped
+struct Partial(PathBuf);
+
+impl Partial {
+    fn new(path: &Path) -> Self {
+        PARTIAL.lock().unwrap().push(path.to_path_buf());
+        Partial(path.to_path_buf())
     }
 }
 
-fn calc_md5(path: &Path) -> String {
-    let mut f = BufReader::new(fs::File::open(path).unwrap());
-    let mut buf = Vec::new();
-    f.read_to_end(&mut buf).unwrap();
+impl Drop for Partial {
+    fn drop(&mut self) {
+        let mut partial = PARTIAL.lock().unwrap();
+        if let Some(index) = partial.iter().rposition(|path| *path == self.0) {
+            partial.remove(index);
+        }
+    }
+}
 
-    let digest = md5::compute(&buf);
-    format!("{:x}", digest)
+// Default of MKL_PROVISION_TIMEOUT
+const DEFAULT_PROVISION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// Download rate in bytes per second, above which the time spent extends the deadline of `Watchdog`
+const MIN_DOWNLOAD_RATE: f64 = 64.0 * 1024.0;
+
+/// Abort the build script when provisioning makes no progress in `timeout`,
+/// or does not finish in `timeout` from the start, until dropped
+///
+/// The deadline is extended while the download is arriving at `MIN_DOWNLOAD_RATE` or faster,
+/// so that a large archive on a slow but working network does not abort, and a trickling one does.
+/// The partial archives and the staging directory are removed before exiting.
+pub struct Watchdog {
+    done: Arc<AtomicBool>,
 }
 
-fn extract<P: AsRef<Path>, P2: AsRef<Path>>(archive_path: P, extract_to: P2) {
-    let file = File::open(archive_path).unwrap();
-    let unzipped = BzDecoder::new(file);
-    let mut a = Archive::new(unzipped);
-    a.unpack(extract_to).unwrap();
+impl Watchdog {
+    pub fn start(timeout: Duration) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        set_phase("starting".to_string());
+        let flag = done.clone();
+        thread::spawn(move || {
+            let mut deadline = Instant::now() + timeout;
+            let (mut tick, mut downloaded) = (Instant::now(), DOWNLOADED.load(Ordering::Relaxed));
+            while !flag.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_secs(1));
+                let (now, total) = (Instant::now(), DOWNLOADED.load(Ordering::Relaxed));
+                let elapsed = now - tick;
+                if (total - downloaded) as f64 >= MIN_DOWNLOAD_RATE * elapsed.as_secs_f64() {
+                    deadline += elapsed;
+                }
+                (tick, downloaded) = (now, total);
+                let idle = now_secs().saturating_sub(LAST_PROGRESS.load(Ordering::Relaxed));
+                let reason = if idle > timeout.as_secs() {
+                    "made no progress"
+                } else if now > deadline {
+                    "did not finish"
+                } else {
+                    continue;
+                };
+                if !flag.load(Ordering::Relaxed) {
+                    abort(reason, timeout);
+                }
+            }
+        });
+        Watchdog { done }
+    }
 }
 
-fn main() {
-    let out_dir = PathBuf::from(var("OUT_DIR").unwrap());
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+}
 
-    for (archive, uri, md5) in mkl::DLS {
-        let archive_path = out_dir.join(archive);
-        if archive_path.exists() && calc_md5(&archive_path) == *md5 {
-            println!("Use existings archive");
+/// Remove the partial files, and exit the build script naming the phase
+fn abort(reason: &str, timeout: Duration) -> ! {
+    for path in PARTIAL.lock().unwrap().iter() {
+        if path.is_dir() {
+            let _ = fs::remove_dir_all(path);
         } else {
-            println!("Download archive");
-            download(uri, archive, &out_dir);
-            extract(&archive_path, &out_dir);
-            
-            let sum = calc_md5(&archive_path);
-            if sum != *md5 {
-                panic!(
-                    "check sum of downloaded archive is incorrect: md5sum={}",
-                    sum
-                );
+            let _ = fs::remove_file(path);
+        }
+    }
+    eprintln!(
+        "Provisioning MKL {} in {} seconds while {}, and is aborted. \
+         Check the network and the file system, or increase MKL_PROVISION_TIMEOUT.",
+        reason,
+        timeout.as_secs(),
+        PHASE.lock().unwrap()
+    );
+    std::process::exit(1);
+}
+
+/// MKL_PROVISION_TIMEOUT in seconds, where `0` disables the watchdog
+fn provision_timeout() -> Option<Duration> {
+    match env_var("MKL_PROVISION_TIMEOUT") {
+        Some(value) => match value.parse() {
+            Ok(0) => None,
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => panic!("MKL_PROVISION_TIMEOUT must be a number of seconds: {}", value),
+        },
+        None => Some(DEFAULT_PROVISION_TIMEOUT),
+    }
+}
+
+/// Keys of build.mkl.toml, and the environment variables which override them
+pub const CONFIG_KEYS: &[(&str, &str)] = &[
+    ("version", "MKL_VERSION"),
+    ("build", "MKL_BUILD_STRING"),
+    ("min_version", "MKL_MIN_VERSION"),
+    ("mirror", "MKL_MIRROR_BASE"),
+    ("manifest_url", "MKL_MANIFEST_URL"),
+    ("cache_dir", "MKL_CACHE_DIR"),
+    ("install_dir", "MKL_INSTALL_DIR"),
+    ("lib_dir", "MKL_LIB_DIR"),
+    ("interface", "MKL_INTERFACE"),
+    ("threading", "MKL_THREADING"),
+];
+
+/// Directories relative to build.mkl.toml
+const CONFIG_PATHS: &[&str] = &["cache_dir", "install_dir", "lib_dir"];
+
+/// build.mkl.toml, i.e. MKL_CONFIG or `build.mkl.toml` in the manifest directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    /// Pairs of the key in `CONFIG_KEYS` and the string value
+    pub values: Vec<(String, String)>,
+}
+
+static CONFIG_FILE: OnceLock<Option<ConfigFile>> = OnceLock::new();
+
+impl ConfigFile {
+    /// Parse the top-level `key = "value"` pairs of `CONFIG_KEYS`, where the other keys, tables,
+    /// and values which are not strings are rejected
+    pub fn parse(path: &Path, content: &str) -> Result<Self, String> {
+        let table: toml::Table = content.parse().map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut values = Vec::new();
+        for (key, value) in table {
+            let invalid = |reason: &str| format!("{}: `{}`: {}", path.display(), key, reason);
+            if !CONFIG_KEYS.iter().any(|(known, _)| *known == key) {
+                return Err(invalid("unknown key"));
+            }
+            match value {
+                toml::Value::String(value) => values.push((key, value)),
+                _ => return Err(invalid("the value must be a string")),
+            }
+        }
+        Ok(ConfigFile {
+            path: path.to_path_buf(),
+            values,
+        })
+    }
+
+    /// Read MKL_CONFIG, or `build.mkl.toml` in `manifest_dir` if exists
+    ///
+    /// A relative MKL_CONFIG is resolved from `manifest_dir`, the root of this crate, instead of the working directory.
+    pub fn load(config: Option<&str>, manifest_dir: &Path) -> Result<Option<Self>, String> {
+        let path = match config {
+            Some(path) => manifest_dir.join(path),
+            None => manifest_dir.join("build.mkl.toml"),
+        };
+        if config.is_none() && !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::parse(&path, &content).map(Some)
+    }
+
+    /// Value of `key`, where the directories are resolved from the directory of the file
+    pub fn get(&self, key: &str) -> Option<String> {
+        let (_, value) = self.values.iter().find(|(defined, _)| defined == key)?;
+        if CONFIG_PATHS.contains(&key) {
+            let base = self.path.parent().unwrap_or_else(|| Path::new(""));
+            return Some(base.join(value).display().to_string());
+        }
+        Some(value.clone())
+    }
+
+    /// Value for the environment variable `name`, which is not set
+    fn env_value(&self, name: &str) -> Option<String> {
+        let (key, _) = CONFIG_KEYS.iter().find(|(_, env)| *env == name)?;
+        self.get(key)
+    }
+}
+
+/// Read an environment variable, and let cargo re-run the build script when it changes.
+/// Unless it is set, the value in build.mkl.toml is used.
+fn env_var(name: &str) -> Option<String> {
+    println!("cargo:rerun-if-env-changed={}", name);
+    var(name)
+        .ok()
+        .or_else(|| CONFIG_FILE.get().and_then(Option::as_ref).and_then(|file| file.env_value(name)))
+}
+
+/// Whether an environment variable is set to `1`
+fn env_flag(name: &str) -> bool {
+    env_var(name).as_deref() == Some("1")
+}
+
+/// Check MKL_EXPECT_THREADING, the threading layers acceptable to the crates in the dependency graph, e.g. `sequential`
+///
+/// A crate which parallelizes the calls by itself oversubscribes the cores with a threaded MKL linked for another crate.
+pub fn check_expected_threading(value: &str, threading: Threading) -> Result<(), String> {
+    let mut expected = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        expected.push(Threading::parse(name).ok_or_else(|| format!("Unknown threading layer `{}` in MKL_EXPECT_THREADING", name))?);
+    }
+    if expected.is_empty() || expected.contains(&threading) {
+        return Ok(());
+    }
+    Err(format!(
+        "The `{}` threading layer is linked, but MKL_EXPECT_THREADING={}.\n\
+         Another crate in the dependency graph may enable the feature of intel-mkl-src, see `cargo tree -e features -i intel-mkl-src`",
+        threading.feature(),
+        value
+    ))
+}
+
+/// Libraries of MKL without `mkl_` prefix, the keys of MKL_LIB_NAMES and MKL_LINK_KIND
+const LIB_KEYS: &[&str] = &[
+    "intel_lp64",
+    "intel_ilp64",
+    "sequential",
+    "intel_thread",
+    "gnu_thread",
+    "tbb_thread",
+    "core",
+];
+
+/// Parse `MKL_LIB_NAMES`, e.g. `intel_lp64=mkl_intel_lp64_patched,core=mkl_core_patched`
+///
+/// Keys are the library names without `mkl_` prefix, and the result maps the original names to new ones.
+pub fn parse_lib_names(value: &str) -> Result<Vec<(String, String)>, String> {
+    let mut names = Vec::new();
+    for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let mut kv = pair.splitn(2, '=');
+        let key = kv.next().unwrap().trim();
+        let name = match kv.next() {
+            Some(name) if !name.trim().is_empty() => name.trim(),
+            _ => return Err(format!("`{}` is not a form of `<library>=<name>`", pair)),
+        };
+        if !LIB_KEYS.contains(&key) {
+            return Err(format!("Unknown library `{}`, expected one of {}", key, LIB_KEYS.join(", ")));
+        }
+        names.push((format!("mkl_{}", key), name.to_string()));
+    }
+    Ok(names)
+}
+
+/// Parse `MKL_LINK_KIND`, e.g. `core=static,sequential=dynamic`
+///
+/// Keys are the same as MKL_LIB_NAMES, and the result maps the original names to the kinds of `rustc-link-lib`.
+pub fn parse_link_kinds(value: &str) -> Result<Vec<(String, &'static str)>, String> {
+    let mut kinds: Vec<(String, &'static str)> = Vec::new();
+    for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (key, kind) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("`{}` is not a form of `<library>=static` or `<library>=dynamic`", pair))?;
+        let key = key.trim();
+        if !LIB_KEYS.contains(&key) {
+            return Err(format!("Unknown library `{}`, expected one of {}", key, LIB_KEYS.join(", ")));
+        }
+        let kind = match kind.trim() {
+            "static" => "static",
+            "dynamic" => "dylib",
+            other => return Err(format!("Unknown kind `{}` of {}, expected `static` or `dynamic`", other, key)),
+        };
+        let lib = format!("mkl_{}", key);
+        if kinds.iter().any(|(listed, _)| *listed == lib) {
+            return Err(format!("`{}` is listed twice", key));
+        }
+        kinds.push((lib, kind));
+    }
+    Ok(kinds)
+}
+
+/// Shared library of `lib` for `target_os`, linked by the `dynamic` kind of MKL_LINK_KIND
+pub fn shared_lib_name(lib: &str, target_os: &str) -> String {
+    match target_os {
+        "macos" => format!("lib{}.dylib", lib),
+        _ => format!("lib{}.so", lib),
+    }
+}
+
+/// Link the `dynamic` libraries of `linked`, pairs of the linked name and the kind, as dylibs, loaded from `lib_dir` at runtime
+///
+/// The static layers bundled into the rlib still resolve the symbols the dynamic ones refer to,
+/// since the linker exports the symbols referenced by the shared libraries in the link.
+pub fn apply_link_kinds(directives: &mut Vec<Directive>, linked: &[(String, &'static str)], lib_dir: &Path) {
+    for directive in directives.iter_mut() {
+        if let Directive::LinkLib(ref mut kind, ref name) = *directive {
+            if let Some((_, linked_kind)) = linked.iter().find(|(lib, _)| lib == name) {
+                *kind = linked_kind;
+            }
+        }
+    }
+    if linked.iter().any(|(_, kind)| *kind == "dylib") {
+        let at = directives.iter().rposition(|directive| matches!(directive, Directive::LinkLib(..))).map_or(0, |i| i + 1);
+        directives.insert(at, Directive::LinkArg(format!("-Wl,-rpath,{}", lib_dir.display())));
+    }
+}
+
+/// Parse MKL_VARIANTS, e.g. `lp64-sequential,ilp64-intel-openmp`, the layers provisioned in addition to the linked ones
+pub fn parse_variants(value: &str) -> Result<Vec<MklConfig>, String> {
+    let mut variants: Vec<MklConfig> = Vec::new();
+    for variant in value.split(',').map(str::trim).filter(|variant| !variant.is_empty()) {
+        let invalid = || format!("`{}` is not `<interface>-<threading>`, e.g. `ilp64-intel-openmp`", variant);
+        let (interface, threading) = variant.split_once('-').ok_or_else(invalid)?;
+        let config = MklConfig::new(
+            Interface::parse(interface).ok_or_else(invalid)?,
+            Threading::parse(threading).ok_or_else(invalid)?,
+        );
+        if !variants.contains(&config) {
+            variants.push(config);
+        }
+    }
+    Ok(variants)
+}
+
+/// Source of `intel_mkl_src::variants()`, as (interface, threading, library directory)
+pub fn variants_source(variants: &[(MklConfig, PathBuf)]) -> String {
+    let entries: Vec<_> = variants
+        .iter()
+        .map(|(config, dir)| {
+            format!(
+                "({:?}, {:?}, {:?})",
+                config.interface.name(),
+                config.threading.feature(),
+                dir.to_string_lossy()
+            )
+        })
+        .collect();
+    format!("&[{}]\n", entries.join(", "))
+}
+
+/// Name of the library `path` of MKL_COMBINED_LIB for `rustc-link-lib`, e.g. `mkl_combined` for `libmkl_combined.a`
+pub fn combined_lib_name(path: &Path, lib_prefix: &str, lib_ext: &str) -> Result<String, String> {
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    file_name
+        .strip_suffix(&format!(".{}", lib_ext))
+        .and_then(|name| name.strip_prefix(lib_prefix))
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| format!("{} is not a static library named `{}<name>.{}`", path.display(), lib_prefix, lib_ext))
+}
+
+/// Parse MKL_EXTRA_STATIC_LIBS, e.g. `mykernels,mkl_patch`, the names of static libraries in the library directory
+pub fn parse_extra_static_libs(value: &str) -> Result<Vec<String>, String> {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            if name.contains(['/', '\\', '=', '.']) {
+                Err(format!("`{}` is not a name of library, e.g. `foo` for `libfoo.a`", name))
+            } else {
+                Ok(name.to_string())
+            }
+        })
+        .collect()
+}
+
+/// Replace the names of libraries by the result of `parse_lib_names`
+pub fn rename_libraries(libs: &[&str], names: &[(String, String)]) -> Vec<String> {
+    libs.iter()
+        .map(|lib| match names.iter().find(|(original, _)| original == lib) {
+            Some((_, name)) => name.clone(),
+            None => lib.to_string(),
+        })
+        .collect()
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // Read before any other settings, which it provides
+    let manifest_dir = PathBuf::from(var("CARGO_MANIFEST_DIR").unwrap());
+    println!("cargo:rerun-if-env-changed=MKL_CONFIG");
+    let config_file = ConfigFile::load(var("MKL_CONFIG").ok().as_deref(), &manifest_dir).unwrap_or_else(|e| panic!("{}", e));
+    // A missing file would re-run the build script every time
+    if let Some(ref file) = config_file {
+        println!("cargo:rerun-if-changed={}", file.path.display());
+        println!("Read {}", file.path.display());
+    }
+    CONFIG_FILE.set(config_file).unwrap();
+
+    // Used for the downloads, the extraction, and the library search paths
+    let out_dir = out_dir().unwrap_or_else(|e| panic!("{}", e));
+    let target_os = var("CARGO_CFG_TARGET_OS").unwrap();
+
+    let target_arch = var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let pointer_width = var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap();
+    validate_target(&target_os, &target_arch, &pointer_width);
+    if platform().name != target_os {
+        println!(
+            "cargo:warning=MKL_TARGET_OVERRIDE is set, libraries for {} are provisioned and cannot be linked for {}",
+            platform().name,
+            target_os
+        );
+    }
+
+    let force_sequential = env_flag("MKL_FORCE_SEQUENTIAL");
+    if force_sequential {
+        println!("cargo:warning=MKL_FORCE_SEQUENTIAL is set, mkl_sequential is linked regardless of the threading features");
+    }
+    // The static mkl_core contains the kernels of all instruction sets, and nothing is selected at build time
+    if env_var("MKL_ENABLE_AVX512").is_some() {
+        println!(
+            "cargo:warning=MKL_ENABLE_AVX512 has no effect, since the AVX-512 kernels are always linked. \
+             Allow them at runtime by MKL_ENABLE_INSTRUCTIONS=AVX512 or `intel_mkl_src::runtime::enable_instructions`"
+        );
+    }
+    if env_flag("MKL_PROVISIONER_ONLY") && env_flag("MKL_CONSUMER_ONLY") {
+        panic!("MKL_PROVISIONER_ONLY and MKL_CONSUMER_ONLY cannot be set at the same time");
+    }
+    if env_flag("MKL_KEEP_ARCHIVE") && env_flag("MKL_REMOVE_ARCHIVE") {
+        panic!("MKL_KEEP_ARCHIVE and MKL_REMOVE_ARCHIVE cannot be set at the same time");
+    }
+    if env_flag("MKL_INSECURE_TLS") {
+        match mirror_base() {
+            Some(base) => println!(
+                "cargo:warning=MKL_INSECURE_TLS: the TLS certificate of {} is NOT verified. \
+                 The downloads from it can be tampered with, and are trusted only by their checksums",
+                base
+            ),
+            None => println!("cargo:warning=MKL_INSECURE_TLS is ignored, since it applies only to MKL_MIRROR_BASE"),
+        }
+    }
+    let selected = (
+        cfg!(feature = "lp64") || cfg!(feature = "ilp64"),
+        force_sequential
+            || cfg!(feature = "sequential")
+            || cfg!(feature = "intel-openmp")
+            || cfg!(feature = "gnu-openmp")
+            || cfg!(feature = "tbb"),
+    );
+    let mut config = MklConfig::from_features(force_sequential)
+        .with_defaults(env_var("MKL_INTERFACE").as_deref(), env_var("MKL_THREADING").as_deref(), selected)
+        .unwrap_or_else(|e| panic!("{}", e));
+    config.validate(&target_os);
+
+    // Libraries taken from MKL_LIB_DIR are not verified, and not listed.
+    // Written first, since the library includes it even when nothing is linked
+    let verified_path = out_dir.join("verified_libraries.rs");
+    let mut verified = Vec::new();
+    fs::write(&verified_path, verified_source(&verified)).unwrap();
+    let fingerprint_path = out_dir.join("config_fingerprint.txt");
+    fs::write(&fingerprint_path, "").unwrap();
+    let variants_path = out_dir.join("variants.rs");
+    fs::write(&variants_path, variants_source(&[])).unwrap();
+    // Unknown for MKL_LIB_DIR
+    let mut version = None;
+
+    let watchdog = provision_timeout().map(Watchdog::start);
+    if env_flag("MKL_HEADERS_ONLY") {
+        let install_dir = env_var("MKL_INSTALL_DIR").map_or_else(|| out_dir.clone(), PathBuf::from);
+        fs::create_dir_all(&install_dir).unwrap();
+        let version = env_var("MKL_VERSION").unwrap_or_else(|| DEFAULT_VERSION.to_string());
+        check_min_version(&version);
+        let include_dir = provision_headers(&version, &install_dir).unwrap_or_else(|e| panic!("{}", e));
+        // Another crate in the dependency graph links MKL
+        println!("cargo:include={}", include_dir.display());
+        for directive in config_directives(&config, None) {
+            println!("cargo:{}", directive);
+        }
+        if env_flag("MKL_STRICT_ABI") {
+            for directive in abi_directives(&config) {
+                println!("cargo:{}", directive);
+            }
+        }
+        return;
+    }
+
+    // Directory of the static Intel OpenMP runtime, if it is not `lib_dir`
+    let mut openmp_dir = None;
+    // A single library combining the layers, e.g. by `ar` and an MRI script, is linked instead of them
+    let combined = env_var("MKL_COMBINED_LIB").map(|path| {
+        if config.rt {
+            panic!("MKL_COMBINED_LIB is a static library, and is not supported with feature `rt`");
+        }
+        let path = fs::canonicalize(&path).unwrap_or_else(|e| panic!("MKL_COMBINED_LIB {} is not found: {}", path, e));
+        let path = strip_verbatim(&path);
+        let name = combined_lib_name(&path, platform().lib_prefix, platform().lib_ext).unwrap_or_else(|e| panic!("{}", e));
+        if let Some(md5) = env_var("MKL_COMBINED_LIB_MD5") {
+            let actual = calc_md5(&path);
+            if actual != md5 {
+                panic!("Checksum mismatch of MKL_COMBINED_LIB {}, expected {} but {}", path.display(), md5, actual);
             }
         }
+        (path.parent().unwrap().to_path_buf(), name)
+    });
+    let lib_dir_var = combined.as_ref().map(|(dir, _)| dir.display().to_string()).or_else(|| env_var("MKL_LIB_DIR"));
+    let system = || match lib_dir_var {
+        Some(ref dir) => Some(PathBuf::from(dir)),
+        None if env_flag("MKL_USE_MKLROOT") => {
+            let root = env_var("MKLROOT").expect("MKL_USE_MKLROOT is set, but MKLROOT is not");
+            let found = probe_lib_dir(Path::new(&root), &config, &target_os).unwrap_or_else(|probed| {
+                let probed: Vec<_> = probed.iter().map(|dir| format!("  {}", dir.display())).collect();
+                panic!("MKL libraries are not found in MKLROOT, probed:\n{}", probed.join("\n"))
+            });
+            Some(found)
+        }
+        None => None,
+    };
+    // MKL installed by pip is used only if it has all of the libraries
+    let pip = || {
+        let venv = env_var("VIRTUAL_ENV")?;
+        let dir = find_lib_dir(pip_lib_dirs(Path::new(&venv), &target_os), &config, &target_os).ok()?;
+        println!("Found MKL installed by pip in the virtualenv {}", venv);
+        Some(dir)
+    };
+    let priority = match env_var("MKL_SOURCE_PRIORITY") {
+        Some(value) => parse_source_priority(&value).unwrap_or_else(|e| panic!("Invalid MKL_SOURCE_PRIORITY: {}", e)),
+        None => DEFAULT_SOURCE_PRIORITY.to_vec(),
+    };
+    if !priority.contains(&Source::Download) {
+        DOWNLOAD_DISABLED.store(true, Ordering::Relaxed);
+    }
+    // OUT_DIR may be removed between the jobs of CI, while the install directory is kept
+    let install_dir = env_var("MKL_INSTALL_DIR").map_or_else(|| out_dir.clone(), PathBuf::from);
+    // Selected by `cache` for the later provisioning
+    let mut package = None;
+    // The system MKL, or None to provision the package
+    let mut chosen = None;
+    for source in &priority {
+        let found = match *source {
+            Source::System => system().map(Some),
+            Source::Pip => pip().map(Some),
+            // mkl_rt is never provisioned
+            Source::Cache if config.rt => None,
+            Source::Cache => {
+                fs::create_dir_all(&install_dir).unwrap();
+                let selected = select_package(&config, &install_dir).unwrap_or_else(|e| panic!("{}", e));
+                let cache_root = env_var("MKL_CACHE_DIR").map(PathBuf::from);
+                let cache_dir = cache_dir(&out_dir, cache_root.as_deref(), &selected, &config);
+                let found = find_provisioned(&selected, &config, &install_dir, cache_dir.as_deref()).map(|_| None);
+                package = Some(selected);
+                found
+            }
+            Source::Download => Some(None),
+        };
+        if found.is_some() {
+            chosen = found;
+            break;
+        }
+    }
+    let system_dir = match chosen {
+        Some(dir) => dir,
+        None => {
+            let sources: Vec<_> = priority.iter().map(|source| source.name()).collect();
+            panic!("MKL is not found in any source of MKL_SOURCE_PRIORITY={}", sources.join(","))
+        }
+    };
+    if env_flag("MKL_EXPLAIN") {
+        if let Some(ref dir) = system_dir {
+            println!(
+                "cargo:warning=MKL_EXPLAIN: link the system MKL in {}, which is not verified",
+                dir.display()
+            );
+            return;
+        }
+    }
+    let lib_dir = match system_dir {
+        Some(_) if env_var("MKL_VARIANTS").is_some() => panic!(
+            "MKL_VARIANTS is provisioned from the mkl-static package, and is not supported with MKL_LIB_DIR and the others"
+        ),
+        Some(dir) => {
+            if env_var("MKL_MIN_VERSION").is_some() {
+                println!("cargo:warning=MKL_MIN_VERSION is not checked for MKL in {}, whose version is unknown", dir.display());
+            }
+            println!("Use MKL in {}", dir.display());
+            dir
+        }
+        None if config.rt => panic!(
+            "Feature `rt` of intel-mkl-src requires MKL_LIB_DIR, MKL_USE_MKLROOT, or MKL installed by pip in VIRTUAL_ENV, \
+             since mkl_rt is not included in the mkl-static package"
+        ),
+        None => {
+            fs::create_dir_all(&install_dir).unwrap();
+            let package = package.unwrap_or_else(|| select_package(&config, &install_dir).unwrap_or_else(|e| panic!("{}", e)));
+            if env_flag("MKL_EXPLAIN") {
+                let cache_root = env_var("MKL_CACHE_DIR").map(PathBuf::from);
+                let cache_dir = cache_dir(&out_dir, cache_root.as_deref(), &package, &config);
+                let source = explain_provision(&package, &config, &install_dir, cache_dir.as_deref());
+                println!("cargo:warning=MKL_EXPLAIN: {}", source);
+                return;
+            }
+            let provisioned = match provision_mkl(&package, &config, &install_dir, &out_dir) {
+                Ok(provisioned) => provisioned,
+                Err(e) if target_os == "macos" && env_flag("MKL_MACOS_ACCELERATE_FALLBACK") => {
+                    link_accelerate(&config, &e);
+                    return;
+                }
+                Err(e) => panic!("{}\n\nTried to provision MKL from:\n{}", e, attempts_summary()),
+            };
+            version = Some(provisioned.version);
+            config = provisioned.config;
+            openmp_dir = provisioned.openmp_dir;
+            verified = provisioned.verified;
+            fs::write(&verified_path, verified_source(&verified)).unwrap();
+            // Never linked, and the symbols of the layers do not conflict with the linked ones
+            if let Some(value) = env_var("MKL_VARIANTS") {
+                let variants = parse_variants(&value).unwrap_or_else(|e| panic!("Invalid MKL_VARIANTS: {}", e));
+                let mut provisioned_variants = Vec::new();
+                for variant in variants.into_iter().filter(|variant| mkl_files(variant) != mkl_files(&config)) {
+                    let dir = install_dir
+                        .join("mkl-variants")
+                        .join(format!("{}-{}", variant.interface.name(), variant.threading.feature()));
+                    fs::create_dir_all(&dir).unwrap();
+                    // Extracted from the archive of the linked one, not to download it again
+                    let archive = install_dir.join(package.filename());
+                    if archive.exists() && !dir.join(package.filename()).exists() {
+                        let _ = fs::hard_link(&archive, dir.join(package.filename()));
+                    }
+                    let provisioned = provision_mkl(&package, &variant, &dir, &out_dir)
+                        .unwrap_or_else(|e| panic!("MKL_VARIANTS: {}\n\nTried to provision MKL from:\n{}", e, attempts_summary()));
+                    println!("Provisioned the variant {:?} in {}", variant, provisioned.lib_dir.display());
+                    provisioned_variants.push((provisioned.config, provisioned.lib_dir));
+                }
+                fs::write(&variants_path, variants_source(&provisioned_variants)).unwrap();
+            }
+            provisioned.lib_dir
+        }
+    };
+    drop(watchdog);
+    if let Some(file) = config.openmp_files().first() {
+        let path = openmp_dir.get_or_insert_with(|| lib_dir.clone()).join(file.file_name().unwrap());
+        if !path.exists() {
+            config = threading_fallback(&config, format!("{} for feature `static-openmp` is not found", path.display()));
+            openmp_dir = None;
+        }
+    }
+
+    // Warm the cache in a stage with network access, for a later build.
+    // MKL_PROVISIONER_ONLY is the provisioner of the builds with MKL_CONSUMER_ONLY
+    if env_flag("MKL_DOWNLOAD_ONLY") || env_flag("MKL_PROVISIONER_ONLY") {
+        for file in mkl_files(&config) {
+            let name = file.file_name().unwrap();
+            println!("cargo:warning=MKL_DOWNLOAD_ONLY: {} is ready, and not linked", lib_dir.join(name).display());
+        }
+        report_stats();
+        return;
+    }
+
+    let names = match env_var("MKL_LIB_NAMES") {
+        Some(value) => parse_lib_names(&value).unwrap_or_else(|e| panic!("Invalid MKL_LIB_NAMES: {}", e)),
+        None => Vec::new(),
+    };
+    // Pairs of the linked name and the kind, overriding the static kind of the layers
+    let link_kinds: Vec<(String, &'static str)> = match env_var("MKL_LINK_KIND") {
+        Some(value) => {
+            if config.rt || combined.is_some() {
+                panic!("MKL_LINK_KIND selects the kinds of the layers, and is not supported with feature `rt` nor MKL_COMBINED_LIB");
+            }
+            if target_os == "windows" {
+                panic!("MKL_LINK_KIND is not supported on Windows, where the layers are linked by the `_dll` import libraries");
+            }
+            let kinds = parse_link_kinds(&value).unwrap_or_else(|e| panic!("Invalid MKL_LINK_KIND: {}", e));
+            kinds
+                .into_iter()
+                .map(|(lib, kind)| {
+                    if !config.libraries().contains(&lib.as_str()) {
+                        panic!("{} of MKL_LINK_KIND is not linked by the selected features", lib);
+                    }
+                    (rename_libraries(&[&lib], &names).remove(0), kind)
+                })
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    let mut libs = match combined {
+        Some((_, name)) => vec![name],
+        None => rename_libraries(&config.libraries(), &names),
+    };
+    for lib in libs.iter().filter(|lib| names.iter().any(|(_, name)| name == *lib)) {
+        let path = lib_dir.join(format!("{}{}.{}", platform().lib_prefix, lib, platform().lib_ext));
+        if !path.exists() {
+            panic!("{} renamed by MKL_LIB_NAMES is not found", path.display());
+        }
+    }
+    if let Some(value) = env_var("MKL_EXTRA_STATIC_LIBS") {
+        if config.rt {
+            panic!("MKL_EXTRA_STATIC_LIBS is linked together with the static MKL, and is not supported with feature `rt`");
+        }
+        let extra = parse_extra_static_libs(&value).unwrap_or_else(|e| panic!("Invalid MKL_EXTRA_STATIC_LIBS: {}", e));
+        for lib in &extra {
+            let path = lib_dir.join(format!("{}{}.{}", platform().lib_prefix, lib, platform().lib_ext));
+            if !path.exists() {
+                panic!("{} of MKL_EXTRA_STATIC_LIBS is not found", path.display());
+            }
+        }
+        // Linked before MKL, since they call or override the functions of MKL
+        libs.splice(0..0, extra);
+    }
+
+    // The sums of MKL_LIB_DIR, and of the libraries renamed by MKL_LIB_NAMES are calculated here
+    let mut linked: Vec<PathBuf> = libs
+        .iter()
+        .map(|lib| lib_dir.join(format!("{}{}.{}", platform().lib_prefix, lib, platform().lib_ext)))
+        .collect();
+    if let (Some(dir), Some(file)) = (openmp_dir.as_ref(), config.openmp_files().first()) {
+        linked.push(dir.join(file.file_name().unwrap()));
+    }
+    let checksums: Vec<(String, String)> = linked
+        .iter()
+        .filter(|path| path.exists())
+        .map(|path| {
+            let md5 = verified
+                .iter()
+                .find(|(verified, _)| verified == path)
+                .map_or_else(|| calc_md5(path), |(_, md5)| md5.clone());
+            (path.file_name().unwrap().to_string_lossy().into_owned(), md5)
+        })
+        .collect();
+    let fingerprint = config_fingerprint(version.as_deref(), platform().name, &config, &checksums);
+    fs::write(&fingerprint_path, &fingerprint).unwrap();
+    if let Some(expected) = env_var("MKL_EXPECT_THREADING") {
+        check_expected_threading(&expected, config.threading).unwrap_or_else(|e| panic!("{}", e));
+    }
+    if let Some(expected) = env_var("MKL_EXPECT_FINGERPRINT") {
+        if expected != fingerprint {
+            panic!(
+                "The fingerprint of the linked MKL configuration is {}, but MKL_EXPECT_FINGERPRINT={}.\n\
+                 The version, the layers, or the libraries differ from the expected ones",
+                fingerprint, expected
+            );
+        }
+    }
+
+    // Re-verify the libraries when they are replaced, e.g. in a shared cache.
+    // Extracted libraries keep the time in the archive, not to re-run the build script just after extraction.
+    for lib in &libs {
+        let path = lib_dir.join(format!("{}{}.{}", platform().lib_prefix, lib, platform().lib_ext));
+        if path.exists() {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+
+    // MKL installed centrally is loaded from there at runtime, without copying
+    let runtime_dir = env_var("MKL_RUNTIME_DIR").map(PathBuf::from);
+    if let Some(ref dir) = runtime_dir {
+        if !config.rt {
+            panic!("MKL_RUNTIME_DIR requires feature `rt`, since the static libraries are not loaded at runtime");
+        }
+        match find_runtime_lib(dir, &target_os) {
+            Some(lib) => println!("mkl_rt is loaded from {} at runtime", lib.display()),
+            None => panic!("mkl_rt for {} is not found in MKL_RUNTIME_DIR {}", target_os, dir.display()),
+        }
+        if target_os == "windows" {
+            println!(
+                "cargo:warning=MKL_RUNTIME_DIR: the DLLs of MKL are not copied, add {} to PATH to run the executables",
+                dir.display()
+            );
+        }
+    }
+
+    // `mkl_rt.lib` is an import library, and `mkl_rt.dll` must be found next to the executables at runtime
+    if config.rt && target_os == "windows" && runtime_dir.is_none() {
+        let import_lib = lib_dir.join("mkl_rt.lib");
+        if !import_lib.exists() {
+            panic!("{} is not found in MKL_LIB_DIR", import_lib.display());
+        }
+        let bin_dir = lib_dir.parent().unwrap().join("bin");
+        let profile_dir = profile_dir(&out_dir).expect("OUT_DIR is not in the target directory");
+        let copied = copy_runtime_dlls(&bin_dir, &profile_dir).unwrap_or_else(|e| panic!("{}", e));
+        if !copied.iter().any(|dll| dll.file_stem().is_some_and(|stem| stem.to_string_lossy().starts_with("mkl_rt"))) {
+            panic!("mkl_rt.dll is not found in {}", bin_dir.display());
+        }
+        for dll in &copied {
+            println!("Copied {}", dll.display());
+        }
+    }
+
+    // The install names of dylibs may be absolute paths of the conda prefix, which are not found at runtime
+    let lib_dir = if config.rt && target_os == "macos" && runtime_dir.is_none() {
+        relocatable_dylibs(&lib_dir, &out_dir)
+    } else {
+        lib_dir
+    };
+
+    let is_dynamic = |lib: &String| link_kinds.iter().any(|(linked, kind)| linked == lib && *kind == "dylib");
+    let static_libs: Vec<String> = libs.iter().filter(|lib| !is_dynamic(lib)).cloned().collect();
+    check_lib_dir(&lib_dir, &static_libs, config.rt, &target_os).unwrap_or_else(|e| panic!("{}", e));
+    for lib in libs.iter().filter(|lib| is_dynamic(lib)) {
+        let path = lib_dir.join(shared_lib_name(lib, &target_os));
+        if !path.exists() {
+            panic!("{} linked dynamically by MKL_LINK_KIND is not found", path.display());
+        }
+    }
+    // Scanning the objects reads all the libraries, and is skipped while the fingerprint and the model are unchanged
+    if !config.rt {
+        let model = relocation_model(std::env::vars());
+        let checked_path = out_dir.join("pic_checked.txt");
+        let checked = format!("{} {}", fingerprint, model.as_deref().unwrap_or("pic"));
+        if fs::read_to_string(&checked_path).ok().as_deref() != Some(checked.as_str()) {
+            let archives: Vec<PathBuf> = linked
+                .iter()
+                .filter(|path| path.extension().is_some_and(|ext| ext == "a") && path.exists())
+                .filter(|path| {
+                    let name = path.file_stem().unwrap().to_string_lossy();
+                    !libs.iter().any(|lib| is_dynamic(lib) && name == format!("lib{}", lib))
+                })
+                .cloned()
+                .collect();
+            check_pic(&archives, model.as_deref(), &target_os).unwrap_or_else(|e| panic!("{}", e));
+            fs::write(&checked_path, checked).unwrap();
+        }
+    }
+    if env_flag("MKL_SELFTEST") {
+        // The compiler may hang as well, e.g. on a network file system
+        let _watchdog = provision_timeout().map(Watchdog::start);
+        self_test(&config, &lib_dir, &libs, &link_kinds, &target_os, &out_dir);
+    }
+
+    let extra_args = env_var("MKL_EXTRA_LINK_ARGS");
+    for signal in other_blas_signals(std::env::vars()) {
+        println!(
+            "cargo:warning=Another BLAS may be linked together with MKL ({}). \
+             Their symbols collide, and which implementation is called is undefined",
+            signal
+        );
+    }
+    let openmp_dir = openmp_dir.filter(|dir| *dir != lib_dir);
+    let mut directives = link_directives(
+        &config,
+        &lib_dir,
+        openmp_dir.as_deref(),
+        runtime_dir.as_deref(),
+        &libs,
+        &target_os,
+        extra_args.as_deref(),
+    );
+    // After the libraries of MKL, and before the extra link arguments
+    let fortran = env_var("MKL_FORTRAN_RT").map(|value| FortranRuntime::parse(&value).unwrap_or_else(|e| panic!("{}", e)));
+    if let Some(runtime) = fortran.flatten() {
+        let dir = env_var("MKL_FORTRAN_RT_DIR").map_or_else(|| lib_dir.clone(), PathBuf::from);
+        let linked = fortran_directives(runtime, &dir, &target_os).unwrap_or_else(|e| panic!("{}", e));
+        let at = directives.iter().rposition(|directive| matches!(directive, Directive::LinkLib(..))).map_or(0, |i| i + 1);
+        directives.splice(at..at, linked);
+    }
+    apply_link_kinds(&mut directives, &link_kinds, &lib_dir);
+    if env_flag("MKL_STRICT_ABI") {
+        directives.extend(abi_directives(&config));
+    }
+
+    let pc_out = env_var("MKL_PC_OUT").map(PathBuf::from);
+    if pc_out.is_some() || env_flag("MKL_PKG_CONFIG") {
+        let path = pc_out.unwrap_or_else(|| out_dir.join("intel-mkl.pc"));
+        let include_dir = find_include_dir(&lib_dir, &install_dir);
+        if include_dir.is_none() {
+            println!(
+                "cargo:warning=MKL_PKG_CONFIG: mkl.h is not found, and {} has no Cflags of the headers. \
+                 Provision them by MKL_HEADERS_ONLY into the same MKL_INSTALL_DIR",
+                path.display()
+            );
+        }
+        let pc = pkg_config(&directives, include_dir.as_deref(), &config, version.as_deref(), &target_os);
+        fs::write(&path, pc).unwrap_or_else(|e| panic!("Failed to write {}: {}", path.display(), e));
+        println!("Wrote {}", path.display());
+        directives.push(Directive::Metadata("pkgconfig", path.display().to_string()));
+    }
+
+    let print_link_line = env_flag("MKL_PRINT_LINK_LINE");
+    if print_link_line {
+        println!("cargo:warning=MKL libraries in {}", lib_dir.display());
+    }
+    for directive in &directives {
+        println!("cargo:{}", directive);
+        if print_link_line && directive.is_link() {
+            println!("cargo:warning={}", directive);
+        }
+    }
+    report_stats();
+}
+
+/// Check that `libs` to be linked exist in `lib_dir` before emitting it as the link-search path,
+/// not to leave a missing directory, e.g. by an unexpected layout of the archive, to the linker
+///
+/// `mkl_rt` is found by `find_runtime_lib()` except on Windows, where the import library is linked.
+pub fn check_lib_dir(lib_dir: &Path, libs: &[String], rt: bool, target_os: &str) -> Result<(), String> {
+    let expected = |reason: String| format!("expected libraries at {} but {}", lib_dir.display(), reason);
+    let entries = match fs::read_dir(lib_dir) {
+        Ok(entries) => entries.count(),
+        Err(_) => return Err(expected("directory is missing".to_string())),
+    };
+    if entries == 0 {
+        return Err(expected("directory is empty".to_string()));
+    }
+    for lib in libs {
+        let found = if rt && target_os != "windows" {
+            find_runtime_lib(lib_dir, target_os).is_some()
+        } else {
+            lib_dir.join(format!("{}{}.{}", platform().lib_prefix, lib, platform().lib_ext)).exists()
+        };
+        if !found {
+            return Err(expected(format!("{} is not found", lib)));
+        }
+    }
+    Ok(())
+}
+
+/// Signs of another BLAS linked into the same binary, found in the environment of the build script
+///
+/// The build script cannot see the whole dependency graph. Only obvious ones are detected:
+/// metadata of BLAS crates, e.g. `DEP_OPENBLAS_*`, and the libraries in RUSTFLAGS or MKL_EXTRA_LINK_ARGS.
+pub fn other_blas_signals<I: IntoIterator<Item = (String, String)>>(vars: I) -> Vec<String> {
+    const PREFIXES: &[&str] = &["DEP_OPENBLAS", "DEP_BLAS", "DEP_CBLAS", "DEP_BLIS", "DEP_NETLIB"];
+    const LIBS: &[&str] = &["openblas", "blas", "cblas", "blis", "Accelerate"];
+    // `-lKIND=NAME` or `-l KIND=NAME`, and CARGO_ENCODED_RUSTFLAGS is separated by 0x1f
+    let links_other = |flags: &str| {
+        let flags: Vec<&str> = flags.split(|c: char| c.is_whitespace() || c == '\x1f').collect();
+        flags
+            .iter()
+            .enumerate()
+            .filter_map(|(i, flag)| match flag.strip_prefix("-l") {
+                Some("") => flags.get(i + 1).copied(),
+                lib => lib,
+            })
+            .map(|lib| lib.rsplit('=').next().unwrap())
+            .any(|lib| LIBS.contains(&lib))
+    };
+    let mut signals: Vec<String> = vars
+        .into_iter()
+        .filter(|(key, value)| match key.as_str() {
+            "CARGO_ENCODED_RUSTFLAGS" | "RUSTFLAGS" | "MKL_EXTRA_LINK_ARGS" => links_other(value),
+            _ => PREFIXES.iter().any(|prefix| key.starts_with(&format!("{}_", prefix))),
+        })
+        .map(|(key, _)| key)
+        .collect();
+    signals.sort();
+    signals
+}
+
+/// Fortran runtime of MKL_FORTRAN_RT, required by the objects compiled by the Fortran compilers,
+/// e.g. ScaLAPACK or LAPACK routines built from Fortran sources, linked together with MKL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FortranRuntime {
+    /// `ifcore` of the Intel Fortran compiler
+    Intel,
+    /// `gfortran` of GCC
+    Gnu,
+}
+
+impl FortranRuntime {
+    /// Parse MKL_FORTRAN_RT, i.e. `intel`, `gnu`, or `none`
+    pub fn parse(value: &str) -> Result<Option<Self>, String> {
+        match value {
+            "intel" => Ok(Some(FortranRuntime::Intel)),
+            "gnu" => Ok(Some(FortranRuntime::Gnu)),
+            "none" | "" => Ok(None),
+            _ => Err(format!("Unknown MKL_FORTRAN_RT `{}`, expected `intel`, `gnu`, or `none`", value)),
+        }
+    }
+
+    /// Library of the runtime. On Windows, the DLL version of the Intel runtime is linked as MKL does.
+    pub fn library(&self, target_os: &str) -> &'static str {
+        match (*self, target_os) {
+            (FortranRuntime::Intel, "windows") => "libifcoremd",
+            (FortranRuntime::Intel, _) => "ifcore",
+            (FortranRuntime::Gnu, _) => "gfortran",
+        }
+    }
+}
+
+/// Directives to link the Fortran runtime in `dir`, or an error if it is not found there
+///
+/// Both the shared and the static libraries are found, and the linker prefers the shared one.
+pub fn fortran_directives(runtime: FortranRuntime, dir: &Path, target_os: &str) -> Result<Vec<Directive>, String> {
+    let lib = runtime.library(target_os);
+    let exists = fs::read_dir(dir).into_iter().flatten().filter_map(Result::ok).any(|entry| {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        match target_os {
+            "windows" => name.eq_ignore_ascii_case(&format!("{}.lib", lib)),
+            "macos" => name == format!("lib{}.a", lib) || name == format!("lib{}.dylib", lib),
+            // `libgfortran.so.5` of the runtime package is not found by `-lgfortran`
+            _ => name == format!("lib{}.a", lib) || name == format!("lib{}.so", lib),
+        }
+    });
+    if !exists {
+        return Err(format!(
+            "The Fortran runtime `{}` of MKL_FORTRAN_RT is not found in {}. \
+             Set MKL_FORTRAN_RT_DIR to the library directory of the Fortran compiler",
+            lib,
+            dir.display()
+        ));
+    }
+    Ok(vec![Directive::Search(dir.to_path_buf()), Directive::LinkLib("dylib", lib.to_string())])
+}
+
+/// Sequential layer instead of the threading runtime failed to provision, if MKL_THREADING_FALLBACK is set
+///
+/// Without it, the build fails by `error`, not to link another layer than the features silently.
+fn threading_fallback<E: fmt::Display>(config: &MklConfig, error: E) -> MklConfig {
+    if !env_flag("MKL_THREADING_FALLBACK") {
+        panic!("{}\n\nMKL_THREADING_FALLBACK=1 links mkl_sequential instead.", error);
+    }
+    println!(
+        "cargo:warning=MKL_THREADING_FALLBACK: the `{}` threading layer is NOT linked, mkl_sequential is used instead: {}",
+        config.threading.feature(),
+        error
+    );
+    config.sequential()
+}
+
+/// Directive of the build script, printed with `cargo:` prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Directive {
+    /// `rustc-link-search` of the `native` kind
+    Search(PathBuf),
+    /// `rustc-link-lib`, as (kind, name)
+    LinkLib(&'static str, String),
+    /// `rustc-link-arg`
+    LinkArg(String),
+    /// `rustc-check-cfg`
+    CheckCfg(&'static str),
+    /// `rustc-cfg`, as (name, value)
+    Cfg(&'static str, &'static str),
+    /// Metadata for the build scripts of dependents, as (key, value)
+    Metadata(&'static str, String),
+}
+
+impl Directive {
+    /// Whether it is passed to the linker, see MKL_PRINT_LINK_LINE
+    pub fn is_link(&self) -> bool {
+        matches!(self, Directive::Search(_) | Directive::LinkLib(..) | Directive::LinkArg(_))
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Directive::Search(ref dir) => write!(f, "rustc-link-search=native={}", dir.display()),
+            Directive::LinkLib(kind, ref name) => write!(f, "rustc-link-lib={}={}", kind, name),
+            Directive::LinkArg(ref arg) => write!(f, "rustc-link-arg={}", arg),
+            Directive::CheckCfg(check) => write!(f, "rustc-check-cfg={}", check),
+            Directive::Cfg(name, value) => write!(f, "rustc-cfg={}=\"{}\"", name, value),
+            Directive::Metadata(key, ref value) => write!(f, "{}={}", key, value),
+        }
+    }
+}
+
+/// All directives to link MKL and to expose the configuration, in the order to be printed
+///
+/// Static MKL is sensitive to the order of libraries: interface, threading, and core layers, then the threading runtime.
+/// Their circular references are resolved without `--start-group`, since the static libraries are bundled
+/// into the rlib of this crate, which the linker scans repeatedly as a single archive.
+pub fn link_directives(
+    config: &MklConfig,
+    lib_dir: &Path,
+    openmp_dir: Option<&Path>,
+    runtime_dir: Option<&Path>,
+    libs: &[String],
+    target_os: &str,
+    extra_args: Option<&str>,
+) -> Vec<Directive> {
+    let mut directives = vec![Directive::Search(lib_dir.to_path_buf())];
+    if let Some(dir) = openmp_dir {
+        directives.push(Directive::Search(dir.to_path_buf()));
     }
-    
-    println!("cargo:rustc-link-search={}", out_dir.join(mkl::LIB_PATH).display());
 
     // mkl_intel_ilp64 links to a version w/ 64-bit ints,
     // mkl_intel_lp64 links to a version w/ 32-bit ints.
-    // existing binding need lp64
-    println!("cargo:rustc-link-lib=static=mkl_intel_lp64");
-    println!("cargo:rustc-link-lib=static=mkl_sequential");
-    println!("cargo:rustc-link-lib=static=mkl_core");
+    // lp64 is used unless `ilp64` feature is enabled
+    for lib in libs {
+        directives.push(Directive::LinkLib(config.link_kind(), lib.clone()));
+    }
+    // mkl_rt loads the threading runtime by itself
+    if let Some(runtime) = config.threading.runtime(target_os).filter(|_| !config.rt) {
+        let kind = if config.static_openmp { "static" } else { "dylib" };
+        directives.push(Directive::LinkLib(kind, runtime.to_string()));
+    }
+    // Windows has no rpath, and the DLLs are found by PATH
+    let rpath = runtime_dir.or_else(|| Some(lib_dir).filter(|_| target_os == "macos"));
+    if let Some(dir) = rpath.filter(|_| config.rt && target_os != "windows") {
+        directives.push(Directive::LinkArg(format!("-Wl,-rpath,{}", dir.display())));
+    }
+    // The objects of the static libraries are pulled only when referenced, and their unreferenced sections are dropped here.
+    // The prebuilt objects cannot be recompiled with `-ffunction-sections`, so the routines sharing a section with a called one remain.
+    if config.blas_only {
+        let gc = match target_os {
+            "windows" => "/OPT:REF",
+            "macos" => "-Wl,-dead_strip",
+            _ => "-Wl,--gc-sections",
+        };
+        directives.push(Directive::LinkArg(gc.to_string()));
+    }
+    for arg in extra_args.iter().flat_map(|args| args.split_whitespace()) {
+        directives.push(Directive::LinkArg(arg.to_string()));
+    }
+    directives.extend(config_directives(config, Some(lib_dir)));
+    directives
+}
+
+/// Directives linking Accelerate of macOS instead of MKL, see MKL_MACOS_ACCELERATE_FALLBACK
+///
+/// Dependents find the substitution by `cfg(mkl_fallback = "accelerate")` in this crate,
+/// or by `DEP_MKL_INTEL_LP64_FALLBACK` in their build scripts.
+pub fn accelerate_directives(config: &MklConfig) -> Vec<Directive> {
+    let mut directives = vec![
+        Directive::LinkLib("framework", "Accelerate".to_string()),
+        Directive::CheckCfg("cfg(mkl_fallback, values(\"accelerate\"))"),
+        Directive::Cfg("mkl_fallback", "accelerate"),
+        Directive::Metadata("fallback", "accelerate".to_string()),
+    ];
+    directives.extend(config_directives(config, None));
+    directives
+}
+
+/// Link Accelerate, since MKL has failed to be provisioned by `error`
+///
+/// It resolves CBLAS and LAPACK with 32-bit integers, but not the functions specific to MKL, e.g. VML and VSL.
+fn link_accelerate(config: &MklConfig, error: &BuildError) {
+    if config.interface == Interface::ILP64 {
+        panic!(
+            "{}\n\nMKL_MACOS_ACCELERATE_FALLBACK is not applied to `ilp64`, since Accelerate uses 32-bit integers.\n\n\
+             Tried to provision MKL from:\n{}",
+            error,
+            attempts_summary()
+        );
+    }
+    println!(
+        "cargo:warning=MKL_MACOS_ACCELERATE_FALLBACK: MKL is NOT linked, Accelerate is linked instead, \
+         since MKL could not be provisioned: {}",
+        error
+    );
+    println!(
+        "cargo:warning=MKL_MACOS_ACCELERATE_FALLBACK: only CBLAS and LAPACK are resolved, \
+         and the results and the performance differ from MKL"
+    );
+    for directive in accelerate_directives(config) {
+        println!("cargo:{}", directive);
+    }
+    report_stats();
+}
+
+/// Symbol of the interface layer defined by this crate with MKL_STRICT_ABI, e.g. `intel_mkl_interface_lp64`
+///
+/// A dependent expecting the other width refers to the missing one, and fails to link instead of computing wrong results.
+pub fn abi_directives(config: &MklConfig) -> Vec<Directive> {
+    vec![
+        Directive::Cfg("mkl_abi", "strict"),
+        Directive::Metadata("abi_symbol", format!("intel_mkl_interface_{}", config.interface.name())),
+    ]
+}
+
+/// Directory containing `mkl.h` next to `lib_dir`, e.g. `include` of MKLROOT,
+/// or in `install_dir`, where MKL_HEADERS_ONLY provisions the headers
+pub fn find_include_dir(lib_dir: &Path, install_dir: &Path) -> Option<PathBuf> {
+    let mut candidates: Vec<PathBuf> = lib_dir.ancestors().skip(1).take(2).map(|dir| dir.join("include")).collect();
+    candidates.push(install_dir.join(platform().include_path));
+    candidates.into_iter().find(|dir| dir.join("mkl.h").exists())
+}
+
+/// `intel-mkl.pc` linking the same libraries as `directives`, for the C and C++ parts of a project, see MKL_PKG_CONFIG
+///
+/// The static libraries are named as files on Linux, since `-l` prefers the shared ones in the same directory.
+pub fn pkg_config(
+    directives: &[Directive],
+    include_dir: Option<&Path>,
+    config: &MklConfig,
+    version: Option<&str>,
+    target_os: &str,
+) -> String {
+    let statics: Vec<usize> = directives
+        .iter()
+        .enumerate()
+        .filter(|(_, directive)| matches!(directive, Directive::LinkLib("static", _)))
+        .map(|(i, _)| i)
+        .collect();
+    // Static MKL libraries depend on each other
+    let group = target_os == "linux" && statics.len() > 1;
+    let mut libs = Vec::new();
+    for (i, directive) in directives.iter().enumerate() {
+        if group && statics.first() == Some(&i) {
+            libs.push("-Wl,--start-group".to_string());
+        }
+        match *directive {
+            Directive::Search(ref dir) => libs.push(format!("-L{}", dir.display())),
+            Directive::LinkLib("static", ref name) if target_os == "linux" => libs.push(format!("-l:lib{}.a", name)),
+            Directive::LinkLib("framework", ref name) => libs.push(format!("-framework {}", name)),
+            Directive::LinkLib(_, ref name) if target_os == "windows" => libs.push(format!("{}.lib", name)),
+            Directive::LinkLib(_, ref name) => libs.push(format!("-l{}", name)),
+            Directive::LinkArg(ref arg) => libs.push(arg.clone()),
+            _ => {}
+        }
+        if group && statics.last() == Some(&i) {
+            libs.push("-Wl,--end-group".to_string());
+        }
+    }
+    if target_os == "linux" {
+        libs.extend(["-lpthread", "-lm", "-ldl"].iter().map(|lib| lib.to_string()));
+    }
+    let mut cflags = Vec::new();
+    let mut pc = String::new();
+    if let Some(dir) = include_dir {
+        pc.push_str(&format!("includedir={}\n\n", dir.display()));
+        cflags.push("-I${includedir}".to_string());
+    }
+    if config.interface == Interface::ILP64 {
+        cflags.push("-DMKL_ILP64".to_string());
+    }
+    pc.push_str(&format!(
+        "Name: intel-mkl\n\
+         Description: Intel(R) MKL linked by intel-mkl-src ({}, {}, {})\n\
+         Version: {}\n\
+         Libs: {}\n\
+         Cflags: {}\n",
+        config.interface.name(),
+        config.threading.feature(),
+        if config.rt { "dynamic" } else { "static" },
+        version.unwrap_or("unknown"),
+        libs.join(" "),
+        cflags.join(" ")
+    ));
+    pc
+}
+
+/// Expose the selected layers as cfg and metadata
+pub fn config_directives(config: &MklConfig, lib_dir: Option<&Path>) -> Vec<Directive> {
+    // `rustc-cfg` only applies to this crate. Dependents read the metadata,
+    // i.e. `DEP_MKL_INTEL_LP64_INTERFACE`, `DEP_MKL_INTEL_LP64_THREADING`, `DEP_MKL_INTEL_LP64_LINK`,
+    // and `DEP_MKL_INTEL_LP64_LIBDIR`, in their build scripts, e.g. using intel-mkl-config crate.
+    let mut directives = vec![
+        Directive::CheckCfg("cfg(mkl_interface, values(\"lp64\", \"ilp64\"))"),
+        Directive::CheckCfg("cfg(mkl_threading, values(\"sequential\", \"intel-openmp\", \"gnu-openmp\", \"tbb\"))"),
+        Directive::CheckCfg("cfg(mkl_abi, values(\"strict\"))"),
+        Directive::Cfg("mkl_interface", config.interface.name()),
+        Directive::Cfg("mkl_threading", config.threading.feature()),
+        Directive::Metadata("interface", config.interface.name().to_string()),
+        Directive::Metadata("threading", config.threading.feature().to_string()),
+        Directive::Metadata("link", if config.rt { "dynamic" } else { "static" }.to_string()),
+    ];
+    if let Some(lib_dir) = lib_dir {
+        directives.push(Directive::Metadata("libdir", lib_dir.display().to_string()));
+    }
+    directives
 }
@@ -0,0 +1,210 @@
+// MIT License
+//
+// Copyright (c) 2017 Toshiki Teramura
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Configuration of intel-mkl-src, read in the build scripts of its direct dependents
+//!
+//! The build script of intel-mkl-src exposes it as metadata, which cargo passes to dependents as
+//! `DEP_MKL_INTEL_LP64_INTERFACE`, `DEP_MKL_INTEL_LP64_THREADING`, and `DEP_MKL_INTEL_LP64_LINK`.
+//!
+//! ```no_run
+//! // build.rs of a crate depending on intel-mkl-src
+//! extern crate intel_mkl_config;
+//!
+//! fn main() {
+//!     let config = intel_mkl_config::Config::from_env().unwrap();
+//!     println!("cargo:rustc-cfg=mkl_interface=\"{}\"", config.interface.name());
+//! }
+//! ```
+
+use std::env;
+use std::fmt;
+
+/// Integer width of the interface layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    LP64,
+    ILP64,
+}
+
+impl Interface {
+    /// Name in the metadata, same as the feature of intel-mkl-src
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Interface::LP64 => "lp64",
+            Interface::ILP64 => "ilp64",
+        }
+    }
+}
+
+/// Threading layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threading {
+    Sequential,
+    IntelOpenMP,
+    GnuOpenMP,
+    Tbb,
+}
+
+impl Threading {
+    /// Name in the metadata, same as the feature of intel-mkl-src
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Threading::Sequential => "sequential",
+            Threading::IntelOpenMP => "intel-openmp",
+            Threading::GnuOpenMP => "gnu-openmp",
+            Threading::Tbb => "tbb",
+        }
+    }
+}
+
+/// How MKL is linked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    /// The static libraries of the layers
+    Static,
+    /// The single dynamic library `mkl_rt`, i.e. `rt` feature
+    Dynamic,
+}
+
+impl Link {
+    /// Name in the metadata
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Link::Static => "static",
+            Link::Dynamic => "dynamic",
+        }
+    }
+}
+
+/// Selected configuration of intel-mkl-src
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub interface: Interface,
+    pub threading: Threading,
+    pub link: Link,
+}
+
+/// Invalid metadata, e.g. of another version of intel-mkl-src
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub key: &'static str,
+    pub value: Option<String>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Some(ref value) => write!(f, "Unknown value of {}: {}", self.key, value),
+            None => write!(f, "{} is not set. Is intel-mkl-src a direct dependency?", self.key),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Threading layer other than the expected ones, see `Config::expect_threading()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadingMismatch {
+    pub expected: Vec<Threading>,
+    pub found: Threading,
+}
+
+impl fmt::Display for ThreadingMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let expected: Vec<&str> = self.expected.iter().map(Threading::name).collect();
+        write!(
+            f,
+            "intel-mkl-src links the `{}` threading layer, but {} is expected",
+            self.found.name(),
+            expected.join(" or ")
+        )
+    }
+}
+
+impl std::error::Error for ThreadingMismatch {}
+
+fn find<T: Copy>(
+    key: &'static str,
+    value: Option<&str>,
+    values: &[T],
+    name: fn(&T) -> &'static str,
+) -> Result<T, ParseError> {
+    values
+        .iter()
+        .find(|v| Some(name(v)) == value)
+        .cloned()
+        .ok_or_else(|| ParseError {
+            key,
+            value: value.map(str::to_string),
+        })
+}
+
+impl Config {
+    /// Parse the values of the metadata, where `None` is a missing one
+    pub fn parse(interface: Option<&str>, threading: Option<&str>, link: Option<&str>) -> Result<Self, ParseError> {
+        Ok(Config {
+            interface: find(
+                "DEP_MKL_INTEL_LP64_INTERFACE",
+                interface,
+                &[Interface::LP64, Interface::ILP64],
+                Interface::name,
+            )?,
+            threading: find(
+                "DEP_MKL_INTEL_LP64_THREADING",
+                threading,
+                &[
+                    Threading::Sequential,
+                    Threading::IntelOpenMP,
+                    Threading::GnuOpenMP,
+                    Threading::Tbb,
+                ],
+                Threading::name,
+            )?,
+            link: find(
+                "DEP_MKL_INTEL_LP64_LINK",
+                link,
+                &[Link::Static, Link::Dynamic],
+                Link::name,
+            )?,
+        })
+    }
+
+    /// Fail unless the linked threading layer is one of `expected`, e.g. `&[Threading::Sequential]` for a crate
+    /// which calls MKL from its own threads, since a threaded MKL linked for another crate oversubscribes the cores
+    pub fn expect_threading(&self, expected: &[Threading]) -> Result<(), ThreadingMismatch> {
+        if expected.contains(&self.threading) {
+            return Ok(());
+        }
+        Err(ThreadingMismatch {
+            expected: expected.to_vec(),
+            found: self.threading,
+        })
+    }
+
+    /// Read the metadata from the environment of the build script
+    pub fn from_env() -> Result<Self, ParseError> {
+        let interface = env::var("DEP_MKL_INTEL_LP64_INTERFACE").ok();
+        let threading = env::var("DEP_MKL_INTEL_LP64_THREADING").ok();
+        let link = env::var("DEP_MKL_INTEL_LP64_LINK").ok();
+        Config::parse(interface.as_deref(), threading.as_deref(), link.as_deref())
+    }
+}
@@ -0,0 +1,52 @@
+extern crate intel_mkl_config;
+
+use intel_mkl_config::*;
+
+#[test]
+fn all_combinations() {
+    let interfaces = [Interface::LP64, Interface::ILP64];
+    let threadings = [
+        Threading::Sequential,
+        Threading::IntelOpenMP,
+        Threading::GnuOpenMP,
+        Threading::Tbb,
+    ];
+    let links = [Link::Static, Link::Dynamic];
+    for &interface in &interfaces {
+        for &threading in &threadings {
+            for &link in &links {
+                let config = Config::parse(Some(interface.name()), Some(threading.name()), Some(link.name())).unwrap();
+                assert_eq!(
+                    config,
+                    Config {
+                        interface,
+                        threading,
+                        link
+                    }
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn invalid() {
+    let err = Config::parse(Some("lp32"), Some("sequential"), Some("static")).unwrap_err();
+    assert_eq!(err.key, "DEP_MKL_INTEL_LP64_INTERFACE");
+    assert_eq!(err.value.as_deref(), Some("lp32"));
+    let err = Config::parse(Some("lp64"), None, Some("static")).unwrap_err();
+    assert_eq!(err.key, "DEP_MKL_INTEL_LP64_THREADING");
+    assert_eq!(err.value, None);
+}
+
+#[test]
+fn expect_threading() {
+    let config = Config::parse(Some("lp64"), Some("intel-openmp"), Some("static")).unwrap();
+    assert_eq!(config.expect_threading(&[Threading::IntelOpenMP, Threading::Tbb]), Ok(()));
+    let err = config.expect_threading(&[Threading::Sequential]).unwrap_err();
+    assert_eq!(err.found, Threading::IntelOpenMP);
+    assert_eq!(
+        err.to_string(),
+        "intel-mkl-src links the `intel-openmp` threading layer, but sequential is expected"
+    );
+}
@@ -0,0 +1,164 @@
+// MIT License
+//
+// Copyright (c) 2017 Toshiki Teramura
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Generate the checksums to add a version of MKL, for the maintainers
+//!
+//! ```text
+//! mkl-gen-checksums <version> [--build <build>] [--platform <linux|macos|windows>]... [--work-dir <dir>]
+//! ```
+//!
+//! The archive of each platform is downloaded from the channel, verified by `repodata.json`, and its libraries are hashed.
+//! The entry of MKL_MANIFEST_URL and the entries of `PLATFORMS` in build.rs are printed,
+//! followed by the MD5 and SHA-256 sums of the libraries in the format of `md5sum` and `sha256sum`.
+
+extern crate intel_mkl_tool;
+extern crate serde_json;
+
+use intel_mkl_tool::*;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{exit, Command};
+
+struct Args {
+    version: String,
+    build: Option<String>,
+    platforms: Vec<String>,
+    work_dir: PathBuf,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: mkl-gen-checksums <version> [--build <build>] [--platform <linux|macos|windows>]... [--work-dir <dir>]");
+    exit(2)
+}
+
+fn parse_args() -> Args {
+    let mut args = env::args().skip(1);
+    let mut version = None;
+    let mut build = None;
+    let mut platforms = Vec::new();
+    let mut work_dir = env::temp_dir().join("mkl-gen-checksums");
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--build" => build = Some(args.next().unwrap_or_else(|| usage())),
+            "--platform" => platforms.push(args.next().unwrap_or_else(|| usage())),
+            "--work-dir" => work_dir = PathBuf::from(args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with("--") || version.is_some() => usage(),
+            _ => version = Some(arg),
+        }
+    }
+    Args {
+        version: version.unwrap_or_else(|| usage()),
+        build,
+        platforms,
+        work_dir,
+    }
+}
+
+/// Checksums for the single platform of MKL_TARGET_OVERRIDE, written as JSON into `out`
+///
+/// The pipeline prints the directives and the progress to stdout, which the parent does not show.
+fn run_platform(args: &Args, out: &str) {
+    let checksums = checksums(&args.version, args.build.as_deref(), &args.work_dir).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+    let files: serde_json::Map<String, serde_json::Value> = checksums
+        .files
+        .iter()
+        .map(|(path, md5, sha256)| (path.clone(), serde_json::json!({ "md5": md5, "sha256": sha256 })))
+        .collect();
+    let result = serde_json::json!({
+        "manifest": checksums.manifest_entry(),
+        "builds": checksums.builds_entry(),
+        "files": files,
+    });
+    fs::write(out, result.to_string()).unwrap();
+}
+
+fn main() {
+    let args = parse_args();
+    if let Ok(out) = env::var("MKL_GEN_CHECKSUMS_OUT") {
+        run_platform(&args, &out);
+        return;
+    }
+    // The platform of the build script is fixed in a process, and each one runs in a child
+    let platforms = if args.platforms.is_empty() {
+        vec![env::consts::OS.to_string()]
+    } else {
+        args.platforms.clone()
+    };
+    let exe = env::current_exe().unwrap();
+    let mut manifest = serde_json::Map::new();
+    let mut builds = Vec::new();
+    let mut sums = Vec::new();
+    let mut sha256_sums = Vec::new();
+    for platform in &platforms {
+        let work_dir = args.work_dir.join(platform);
+        fs::create_dir_all(&work_dir).unwrap();
+        let out = work_dir.join("checksums.json");
+        let mut child = Command::new(&exe);
+        child.arg(&args.version).arg("--work-dir").arg(&work_dir);
+        if let Some(ref build) = args.build {
+            child.arg("--build").arg(build);
+        }
+        let output = child
+            .env("MKL_TARGET_OVERRIDE", platform)
+            .env("MKL_GEN_CHECKSUMS_OUT", &out)
+            .output()
+            .unwrap();
+        if !output.status.success() {
+            eprintln!(
+                "Failed to generate the checksums for {}:\n{}",
+                platform,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            exit(1);
+        }
+        let result: serde_json::Value = serde_json::from_str(&fs::read_to_string(&out).unwrap()).unwrap();
+        for (subdir, entry) in result["manifest"].as_object().unwrap() {
+            manifest.insert(subdir.clone(), entry.clone());
+        }
+        builds.push(format!("{}: {}", platform, result["builds"].as_str().unwrap()));
+        for (path, sum) in result["files"].as_object().unwrap() {
+            sums.push(format!("{}  {}/{}", sum["md5"].as_str().unwrap(), platform, path));
+            sha256_sums.push(format!("{}  {}/{}", sum["sha256"].as_str().unwrap(), platform, path));
+        }
+    }
+    println!("Entry of MKL_MANIFEST_URL:");
+    println!("{}", serde_json::to_string_pretty(&manifest).unwrap());
+    println!();
+    println!("Entries of `builds` in PLATFORMS:");
+    for line in &builds {
+        println!("{}", line);
+    }
+    println!();
+    println!("MD5 sums of the libraries:");
+    for line in &sums {
+        println!("{}", line);
+    }
+    println!();
+    println!("SHA-256 sums of the libraries:");
+    for line in &sha256_sums {
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,177 @@
+// MIT License
+//
+// Copyright (c) 2017 Toshiki Teramura
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Provisioning of intel-mkl-src as a library, for the tools and the test harnesses outside of a cargo build
+//!
+//! The pipeline is the build script of intel-mkl-src itself, which reads the same environment variables,
+//! e.g. MKL_VERSION, MKL_MIRROR_BASE, and MKL_CACHE_DIR.
+//!
+//! ```no_run
+//! extern crate intel_mkl_tool;
+//!
+//! use intel_mkl_tool::*;
+//!
+//! fn main() {
+//!     let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+//!     let provisioned = provision(&config, "/opt/mkl".as_ref()).unwrap();
+//!     for (path, md5) in &provisioned.verified {
+//!         println!("{}  {}", md5, path.display());
+//!     }
+//! }
+//! ```
+
+extern crate bzip2;
+extern crate curl;
+extern crate object;
+extern crate serde_json;
+extern crate sha2;
+extern crate tar;
+
+#[allow(dead_code)]
+#[path = "../../build.rs"]
+mod build;
+
+pub use build::{BuildError, Interface, MklConfig, Package, Provisioned, Threading};
+
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Provision MKL for `config` into `install_dir`, and returns the directory to be linked and the verified libraries
+///
+/// It is the same as the build script with MKL_INSTALL_DIR, except that the cache is only MKL_CACHE_DIR if set.
+pub fn provision(config: &MklConfig, install_dir: &Path) -> Result<Provisioned, BuildError> {
+    fs::create_dir_all(install_dir)?;
+    let package = build::select_package(config, install_dir)?;
+    build::provision_mkl(&package, config, install_dir, install_dir)
+}
+
+/// Checksums of an mkl-static archive in the channel, to add its version, see `checksums()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksums {
+    /// Subdir of the channel, e.g. `linux-64`
+    pub subdir: String,
+    /// Package with the build string and the MD5 sum of the archive
+    pub package: Package,
+    /// SHA-256 sum of the archive
+    pub sha256: String,
+    /// (path, MD5 sum, SHA-256 sum) of the libraries in the archive, as extracted by the build script
+    pub files: Vec<(String, String, String)>,
+}
+
+impl Checksums {
+    /// Entry of the manifest of MKL_MANIFEST_URL
+    ///
+    /// The build script verifies the archive by `md5`, and `sha256` and `file_sha256` are for the other tools.
+    pub fn manifest_entry(&self) -> serde_json::Value {
+        let files: Vec<&str> = self.files.iter().map(|(path, _, _)| path.as_str()).collect();
+        let file_sha256: serde_json::Map<String, serde_json::Value> = self
+            .files
+            .iter()
+            .map(|(path, _, sha256)| (path.clone(), sha256.clone().into()))
+            .collect();
+        serde_json::json!({
+            self.subdir.as_str(): {
+                self.package.version.as_str(): {
+                    "build": self.package.build,
+                    "url": self.package.uri(),
+                    "md5": self.package.md5,
+                    "sha256": self.sha256,
+                    "files": files,
+                    "file_sha256": file_sha256,
+                }
+            }
+        })
+    }
+
+    /// Entry of `builds` of `PLATFORMS` in build.rs, followed by the SHA-256 sum as a comment
+    pub fn builds_entry(&self) -> String {
+        format!(
+            "(\"{}\", \"{}\", \"{}\"), // sha256: {}",
+            self.package.version,
+            self.package.build.as_deref().unwrap_or(""),
+            self.package.md5.as_deref().unwrap_or(""),
+            self.sha256
+        )
+    }
+}
+
+/// Download the archive of `version` for the platform of MKL_TARGET_OVERRIDE, or of the host, into `work_dir`,
+/// and calculate the checksums of it and of its libraries
+///
+/// The latest build is used unless `build` is given. The MD5 sum of the archive is checked against `repodata.json`.
+pub fn checksums(version: &str, build: Option<&str>, work_dir: &Path) -> Result<Checksums, BuildError> {
+    fs::create_dir_all(work_dir)?;
+    let repodata = build::fetch_repodata(work_dir)?;
+    let (build, md5, extension) = build::find_builds(&repodata, version)
+        .into_iter()
+        .find(|(b, _, _)| build.is_none_or(|build| build == b))
+        .ok_or_else(|| BuildError::PackageNotFound {
+            version: version.to_string(),
+            build: build.map(str::to_string),
+        })?;
+    let package = Package {
+        version: version.to_string(),
+        build: Some(build),
+        md5: Some(md5.clone()),
+        url: None,
+        extension,
+    };
+    let archive = work_dir.join(package.filename());
+    if !archive.exists() || build::calc_md5(&archive) != md5 {
+        let actual = build::download(&package.uri(), &package.filename(), work_dir)?;
+        if actual != md5 {
+            return Err(BuildError::Checksum {
+                path: archive,
+                expected: md5,
+                actual,
+            });
+        }
+    }
+    let platform = build::platform();
+    let extracted = work_dir.join(format!("mkl-static-{}-{}", package.version, package.build.as_ref().unwrap()));
+    fs::create_dir_all(&extracted)?;
+    build::extract_dir(&archive, platform.lib_path.as_ref(), &extracted)?;
+    let mut files = Vec::new();
+    for entry in fs::read_dir(extracted.join(platform.lib_path))? {
+        let path: PathBuf = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name.starts_with(platform.lib_prefix) && name.ends_with(&format!(".{}", platform.lib_ext)) {
+            files.push((format!("{}/{}", build::lib_path(), name), build::calc_md5(&path), calc_sha256(&path)?));
+        }
+    }
+    files.sort();
+    Ok(Checksums {
+        subdir: build::conda_subdir().to_string(),
+        package,
+        sha256: calc_sha256(&archive)?,
+        files,
+    })
+}
+
+/// SHA-256 sum of the file at `path` in hex
+pub fn calc_sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
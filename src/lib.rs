@@ -20,3 +20,82 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 #![no_std]
+
+extern crate alloc;
+
+pub mod runtime;
+
+/// Libraries linked by the build script as (path, MD5 sum), where the sums have been verified by it
+///
+/// Empty if the libraries are taken from MKL_LIB_DIR, which are not verified.
+pub fn verified_libraries() -> &'static [(&'static str, &'static str)] {
+    include!(concat!(env!("OUT_DIR"), "/verified_libraries.rs"))
+}
+
+/// Layers provisioned by MKL_VARIANTS in addition to the linked ones, as (interface, threading, library directory),
+/// e.g. `("ilp64", "intel-openmp", ".../mkl-variants/ilp64-intel-openmp/lib")`
+///
+/// They are not linked into this crate. Only one configuration can be linked statically,
+/// and the others are linked into separate shared libraries, e.g. `cdylib` crates built against them, loaded at runtime by `dlopen`.
+pub fn variants() -> &'static [(&'static str, &'static str, &'static str)] {
+    include!(concat!(env!("OUT_DIR"), "/variants.rs"))
+}
+
+/// Fingerprint of the linked MKL configuration, i.e. the version, the platform, the layers, and the checksums of the libraries
+///
+/// Compare it across machines to check that they link identical MKL, or pin it by MKL_EXPECT_FINGERPRINT.
+/// Empty if nothing is linked, e.g. with MKL_HEADERS_ONLY.
+pub const CONFIG_FINGERPRINT: &str = include_str!(concat!(env!("OUT_DIR"), "/config_fingerprint.txt"));
+
+// Symbol of the linked interface layer with MKL_STRICT_ABI, referred to by `expect_interface!`
+// and by C code as `extern const int intel_mkl_interface_lp64;`
+#[cfg(all(mkl_abi = "strict", not(mkl_interface = "ilp64")))]
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static intel_mkl_interface_lp64: i32 = 1;
+#[cfg(all(mkl_abi = "strict", mkl_interface = "ilp64"))]
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static intel_mkl_interface_ilp64: i32 = 1;
+
+/// Declare the integer width the calling crate passes to MKL, `lp64` or `ilp64`
+///
+/// With MKL_STRICT_ABI=1, it refers to the symbol of the width, and the link fails by an undefined reference
+/// to `intel_mkl_interface_lp64` or `intel_mkl_interface_ilp64` if the other interface layer is linked.
+/// Otherwise it expands to nothing.
+///
+/// ```
+/// intel_mkl_src::expect_interface!(lp64);
+/// ```
+#[cfg(mkl_abi = "strict")]
+#[macro_export]
+macro_rules! expect_interface {
+    (lp64) => {
+        $crate::__expect_symbol!(intel_mkl_interface_lp64);
+    };
+    (ilp64) => {
+        $crate::__expect_symbol!(intel_mkl_interface_ilp64);
+    };
+}
+
+#[cfg(not(mkl_abi = "strict"))]
+#[macro_export]
+macro_rules! expect_interface {
+    (lp64) => {};
+    (ilp64) => {};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __expect_symbol {
+    ($symbol:ident) => {
+        const _: () = {
+            extern "C" {
+                static $symbol: i32;
+            }
+            // Kept by the linker even if nothing calls it
+            #[used]
+            static EXPECTED: &i32 = unsafe { &$symbol };
+        };
+    };
+}
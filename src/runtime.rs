@@ -0,0 +1,220 @@
+// MIT License
+//
+// Copyright (c) 2017 Toshiki Teramura
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Queries to the linked MKL library itself
+
+use alloc::string::String;
+use core::ffi::{c_char, c_int, CStr};
+
+/// Version information reported by `MKL_Get_Version`
+#[repr(C)]
+#[allow(non_snake_case)]
+struct MKLVersion {
+    MajorVersion: c_int,
+    MinorVersion: c_int,
+    UpdateVersion: c_int,
+    ProductStatus: *const c_char,
+    Build: *const c_char,
+    Processor: *const c_char,
+    Platform: *const c_char,
+}
+
+extern "C" {
+    fn MKL_Get_Version(version: *mut MKLVersion);
+    fn MKL_Get_Version_String(buffer: *mut c_char, len: c_int);
+    fn MKL_Enable_Instructions(isa: c_int) -> c_int;
+}
+
+#[cfg(feature = "rt")]
+extern "C" {
+    fn MKL_Set_Interface_Layer(layer: c_int) -> c_int;
+    fn MKL_Set_Threading_Layer(layer: c_int) -> c_int;
+}
+
+/// Version of the linked MKL, e.g. 2019.0.1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub major: i32,
+    pub minor: i32,
+    pub update: i32,
+    /// e.g. "Product"
+    pub product_status: String,
+    /// Build date, e.g. "20180928"
+    pub build: String,
+    /// Processor optimization, e.g. "Intel(R) Advanced Vector Extensions 2 (Intel(R) AVX2) enabled processors"
+    pub processor: String,
+    /// e.g. "Intel(R) 64 architecture"
+    pub platform: String,
+}
+
+// The strings in `MKLVersion` are owned by MKL and live for the whole process
+unsafe fn to_string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(ptr).to_string_lossy().into_owned()
+}
+
+/// Version reported by `MKL_Get_Version`
+pub fn version() -> Version {
+    let mut v = MKLVersion {
+        MajorVersion: 0,
+        MinorVersion: 0,
+        UpdateVersion: 0,
+        ProductStatus: core::ptr::null(),
+        Build: core::ptr::null(),
+        Processor: core::ptr::null(),
+        Platform: core::ptr::null(),
+    };
+    unsafe {
+        MKL_Get_Version(&mut v);
+        Version {
+            major: v.MajorVersion,
+            minor: v.MinorVersion,
+            update: v.UpdateVersion,
+            product_status: to_string(v.ProductStatus),
+            build: to_string(v.Build),
+            processor: to_string(v.Processor),
+            platform: to_string(v.Platform),
+        }
+    }
+}
+
+/// Human readable version string reported by `MKL_Get_Version_String`,
+/// e.g. "Intel(R) Math Kernel Library Version 2019.0.1 Product Build 20180928 for Intel(R) 64 architecture applications"
+pub fn version_string() -> String {
+    // Keep the last byte zero so that the string is terminated even if MKL truncates it
+    let mut buf = [0 as c_char; 256];
+    unsafe {
+        MKL_Get_Version_String(buf.as_mut_ptr(), buf.len() as c_int - 1);
+    }
+    let bytes: &[u8] = unsafe { core::slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len()) };
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).trim_end().into()
+}
+
+/// Instruction set of the kernels dispatched by MKL, `MKL_ENABLE_*` in `mkl_service.h`
+///
+/// The static `mkl_core` contains the kernels of all of them, and MKL dispatches one by the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Isa {
+    Sse42 = 0,
+    Avx = 1,
+    Avx2 = 2,
+    Avx512Mic = 3,
+    Avx512 = 4,
+    Avx512MicE1 = 5,
+    /// Intel AVX-512 with Vector Neural Network Instructions
+    Avx512E1 = 6,
+}
+
+/// Allow MKL to dispatch the kernels of `isa` and older ones, as `MKL_ENABLE_INSTRUCTIONS` environment variable does
+///
+/// This must be called before any other MKL function. Returns `false` if MKL rejects the request,
+/// e.g. if it has already dispatched a kernel.
+pub fn enable_instructions(isa: Isa) -> bool {
+    unsafe { MKL_Enable_Instructions(isa as c_int) == 1 }
+}
+
+/// Interface layer of `mkl_rt`, `MKL_INTERFACE_*` in `mkl_service.h`
+#[cfg(feature = "rt")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterfaceLayer {
+    LP64 = 0,
+    ILP64 = 1,
+    /// Use GNU Fortran conventions for complex return values
+    GNU = 2,
+}
+
+/// Threading layer of `mkl_rt`, `MKL_THREADING_*` in `mkl_service.h`
+#[cfg(feature = "rt")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadingLayer {
+    Intel = 0,
+    Sequential = 1,
+    PGI = 2,
+    GNU = 3,
+    TBB = 4,
+}
+
+/// Select the interface layer of `mkl_rt`. This must be called before any other MKL function.
+///
+/// Returns the layer actually used, which differs from `layer` when MKL has already been initialized.
+#[cfg(feature = "rt")]
+pub fn set_interface_layer(layer: InterfaceLayer) -> Option<InterfaceLayer> {
+    match unsafe { MKL_Set_Interface_Layer(layer as c_int) } {
+        0 => Some(InterfaceLayer::LP64),
+        1 => Some(InterfaceLayer::ILP64),
+        2 => Some(InterfaceLayer::GNU),
+        _ => None,
+    }
+}
+
+/// Select the threading layer of `mkl_rt`. This must be called before any other MKL function.
+///
+/// Returns the layer actually used, which differs from `layer` when MKL has already been initialized.
+#[cfg(feature = "rt")]
+pub fn set_threading_layer(layer: ThreadingLayer) -> Option<ThreadingLayer> {
+    match unsafe { MKL_Set_Threading_Layer(layer as c_int) } {
+        0 => Some(ThreadingLayer::Intel),
+        1 => Some(ThreadingLayer::Sequential),
+        2 => Some(ThreadingLayer::PGI),
+        3 => Some(ThreadingLayer::GNU),
+        4 => Some(ThreadingLayer::TBB),
+        _ => None,
+    }
+}
+
+/// Layers selected by the features of this crate, e.g. `ilp64` and `intel-openmp`
+#[cfg(feature = "rt")]
+pub fn default_layers() -> (InterfaceLayer, ThreadingLayer) {
+    let interface = if cfg!(mkl_interface = "ilp64") {
+        InterfaceLayer::ILP64
+    } else {
+        InterfaceLayer::LP64
+    };
+    let threading = if cfg!(mkl_threading = "intel-openmp") {
+        ThreadingLayer::Intel
+    } else if cfg!(mkl_threading = "gnu-openmp") {
+        ThreadingLayer::GNU
+    } else if cfg!(mkl_threading = "tbb") {
+        ThreadingLayer::TBB
+    } else {
+        ThreadingLayer::Sequential
+    };
+    (interface, threading)
+}
+
+/// Apply `default_layers()` to `mkl_rt` once. This must be called before any other MKL function.
+///
+/// Calls after the first one do nothing.
+#[cfg(feature = "rt")]
+pub fn init() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static INITIALIZED: AtomicBool = AtomicBool::new(false);
+    if INITIALIZED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let (interface, threading) = default_layers();
+    set_interface_layer(interface);
+    set_threading_layer(threading);
+}
@@ -0,0 +1,1098 @@
+// Download, extract, and verify against a local HTTP server
+//
+// The server serves a synthetic archive containing fixture "libraries",
+// so that these tests do not access the conda channel.
+
+extern crate bzip2;
+extern crate curl;
+extern crate md5;
+extern crate object;
+extern crate serde_json;
+extern crate tar;
+extern crate zip;
+extern crate zstd;
+
+#[allow(dead_code)]
+#[path = "../build.rs"]
+mod build;
+
+use build::*;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn config() -> MklConfig {
+    MklConfig::new(Interface::LP64, Threading::Sequential)
+}
+
+/// `.tar.bz2` archive containing the files of `config()`
+fn fixture_archive() -> Vec<u8> {
+    fixture_archive_with("", "fixture")
+}
+
+/// Fixture archive whose entries are nested under `prefix`, and contain `content`
+fn fixture_archive_with(prefix: &str, content: &str) -> Vec<u8> {
+    fixture_archive_of(&mkl_files(&config()), prefix, content)
+}
+
+/// Fixture archive containing `files`
+fn fixture_archive_of(files: &[PathBuf], prefix: &str, content: &str) -> Vec<u8> {
+    let encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::Fastest);
+    let mut builder = tar::Builder::new(encoder);
+    builder
+        .append_data(&mut header(9), "info/index.json", &b"{\"a\": 1}\n"[..])
+        .unwrap();
+    for file in files {
+        let content = format!("{} of {}\n", content, file.display()).into_bytes();
+        builder
+            .append_data(&mut header(content.len()), PathBuf::from(prefix).join(file), &content[..])
+            .unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+fn header(size: usize) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    header
+}
+
+/// Serve `/mkl.tar.bz2` and its sidecar checksum `/mkl.tar.bz2.md5`, `/truncated.tar.bz2`,
+/// `/loop` redirected to itself, `/forbidden` as an expired signed URL, and 404 for others
+struct Server {
+    port: u16,
+    requests: Arc<AtomicUsize>,
+}
+
+impl Server {
+    fn start(archive: Vec<u8>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let sidecar = format!("{:x}  mkl.tar.bz2\n", md5::compute(&archive)).into_bytes();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                }
+                let (status, length, body) = match request.split_whitespace().nth(1) {
+                    Some("/mkl.tar.bz2") => ("200 OK", archive.len(), &archive[..]),
+                    Some("/mkl.tar.bz2.md5") => ("200 OK", sidecar.len(), &sidecar[..]),
+                    Some("/truncated.tar.bz2") => ("200 OK", archive.len(), &archive[..archive.len() / 2]),
+                    Some("/loop") => ("302 Found\r\nLocation: /loop", 0, &[][..]),
+                    Some("/forbidden") => ("403 Forbidden", 0, &[][..]),
+                    _ => ("404 Not Found", 0, &[][..]),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status, length
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+        Server { port, requests }
+    }
+
+    fn uri(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+fn work_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("download").join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn success() {
+    let archive = fixture_archive();
+    let md5 = format!("{:x}", md5::compute(&archive));
+    let server = Server::start(archive);
+    let dir = work_dir("success");
+    let files = mkl_files(&config());
+
+    // Extracted while downloading
+    download_archive(&server.uri("/mkl.tar.bz2"), "mkl.tar.bz2", &md5, &dir, &files).unwrap();
+    assert_eq!(server.requests(), 1);
+    assert!(check_files(&dir, &files));
+    assert!(!dir.join("info/index.json").exists());
+    assert!(!dir.join("mkl-staging").exists());
+    assert_eq!(calc_md5(&dir.join("mkl.tar.bz2")), md5);
+    // The time in the archive is kept for `cargo:rerun-if-changed`
+    let modified = fs::metadata(dir.join(&files[0])).unwrap().modified().unwrap();
+    assert_eq!(modified, UNIX_EPOCH);
+
+    // Extracted again from the archive
+    fs::remove_dir_all(dir.join(&files[0]).parent().unwrap()).unwrap();
+    extract(dir.join("mkl.tar.bz2"), &dir, &files).unwrap();
+    assert!(check_files(&dir, &files));
+
+    // Modified library is detected using the manifest
+    fs::write(dir.join(&files[0]), "modified").unwrap();
+    assert!(!check_files(&dir, &files));
+}
+
+#[test]
+fn checksum_mismatch() {
+    let server = Server::start(fixture_archive());
+    let dir = work_dir("checksum_mismatch");
+    let wrong = "00000000000000000000000000000000";
+    let files = mkl_files(&config());
+    match download_archive(&server.uri("/mkl.tar.bz2"), "mkl.tar.bz2", wrong, &dir, &files) {
+        Err(BuildError::Checksum { expected, .. }) => assert_eq!(expected, wrong),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    // downloaded once again, and not more
+    assert_eq!(server.requests(), 2);
+    // files extracted from the stream are discarded
+    assert!(!dir.join(&files[0]).exists());
+    assert!(!dir.join("mkl-staging").exists());
+}
+
+#[test]
+fn forbidden_once_more() {
+    let server = Server::start(fixture_archive());
+    let dir = work_dir("forbidden_once_more");
+    let files = mkl_files(&config());
+    match download_archive(&server.uri("/forbidden"), "mkl.tar.bz2", "", &dir, &files) {
+        Err(BuildError::HttpStatus { code: 403, .. }) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    // requested again for another signed URL, and not more
+    assert_eq!(server.requests(), 2);
+    assert!(!dir.join("mkl.tar.bz2").exists());
+}
+
+#[test]
+fn not_found() {
+    let server = Server::start(fixture_archive());
+    let dir = work_dir("not_found");
+    match download(&server.uri("/missing.tar.bz2"), "mkl.tar.bz2", &dir) {
+        Err(BuildError::HttpStatus { code, .. }) => assert_eq!(code, 404),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    assert!(!dir.join("mkl.tar.bz2").exists());
+}
+
+#[test]
+fn truncated() {
+    let server = Server::start(fixture_archive());
+    let dir = work_dir("truncated");
+    match download(&server.uri("/truncated.tar.bz2"), "mkl.tar.bz2", &dir) {
+        Err(BuildError::Curl(_)) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn sidecar() {
+    let archive = fixture_archive();
+    let md5 = format!("{:x}", md5::compute(&archive));
+    let server = Server::start(archive);
+    let dir = work_dir("sidecar");
+    assert_eq!(sidecar_checksum(&server.uri("/mkl.tar.bz2"), "mkl.tar.bz2", &dir), Some(md5));
+    assert!(!dir.join("mkl.tar.bz2.md5").exists());
+    // falls back to the built-in one
+    assert_eq!(sidecar_checksum(&server.uri("/missing.tar.bz2"), "missing.tar.bz2", &dir), None);
+}
+
+#[test]
+fn redirect_loop() {
+    let server = Server::start(fixture_archive());
+    let dir = work_dir("redirect_loop");
+    match download(&server.uri("/loop"), "mkl.tar.bz2", &dir) {
+        Err(BuildError::TooManyRedirects { max, .. }) => assert_eq!(max, 10),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    // the first request and 10 redirections
+    assert_eq!(server.requests(), 11);
+    assert!(!dir.join("mkl.tar.bz2").exists());
+}
+
+#[test]
+fn prefixed_layout() {
+    let dir = work_dir("prefixed_layout");
+    fs::write(dir.join("mkl.tar.bz2"), fixture_archive_with("pkgs/mkl-static-2019.1", "fixture")).unwrap();
+    let files = mkl_files(&config());
+    extract(dir.join("mkl.tar.bz2"), &dir, &files).unwrap();
+    for file in &files {
+        assert!(dir.join(file).exists(), "{} is not extracted", file.display());
+    }
+    assert!(!dir.join("pkgs").exists());
+    assert!(check_files(&dir, &files));
+}
+
+#[test]
+fn shared_cache() {
+    let archive = fixture_archive();
+    let md5 = format!("{:x}", md5::compute(&archive));
+    let server = Server::start(archive);
+    let dir = work_dir("shared_cache");
+    let (debug, release, cache) = (dir.join("debug"), dir.join("release"), dir.join("cache"));
+    for d in &[&debug, &release, &cache] {
+        fs::create_dir_all(d).unwrap();
+    }
+    let files = mkl_files(&config());
+
+    download_archive(&server.uri("/mkl.tar.bz2"), "mkl.tar.bz2", &md5, &debug, &files).unwrap();
+    link_files(&debug, &cache, &files).unwrap();
+    assert!(check_files(&cache, &files));
+
+    link_files(&cache, &release, &files).unwrap();
+    assert!(check_files(&release, &files));
+    assert_eq!(server.requests(), 1);
+
+    // Extraction into a directory must not modify the cache through hard links
+    fs::write(release.join("mkl.tar.bz2"), fixture_archive_with("", "other")).unwrap();
+    extract(release.join("mkl.tar.bz2"), &release, &files).unwrap();
+    assert!(check_files(&release, &files));
+    assert!(check_files(&cache, &files));
+    assert_ne!(
+        fs::read(release.join(&files[0])).unwrap(),
+        fs::read(cache.join(&files[0])).unwrap()
+    );
+}
+
+#[test]
+fn cache_key_depends_on_layers() {
+    let lp64 = config();
+    let ilp64 = MklConfig {
+        interface: Interface::ILP64,
+        ..config()
+    };
+    let package = Package::select(None, None);
+    assert_ne!(cache_key(&package, &lp64), cache_key(&package, &ilp64));
+
+    let out_dir = PathBuf::from("/work/target/release/build/intel-mkl-src-0123/out");
+    assert_eq!(
+        cache_dir(&out_dir, None, &package, &lp64).unwrap(),
+        PathBuf::from("/work/target/mkl-cache")
+            .join(conda_subdir())
+            .join("2019.1-intel_144-lp64-sequential")
+    );
+}
+
+#[test]
+fn use_cache_directly() {
+    let dir = work_dir("use_cache_directly");
+    let (out, cache) = (dir.join("out"), dir.join("cache"));
+    fs::create_dir_all(&out).unwrap();
+    fs::write(out.join("mkl.tar.bz2"), fixture_archive()).unwrap();
+    let files = mkl_files(&config());
+    extract(out.join("mkl.tar.bz2"), &out, &files).unwrap();
+    link_files(&out, &cache, &files).unwrap();
+    fs::remove_dir_all(&out).unwrap();
+    fs::create_dir_all(&out).unwrap();
+
+    // The server is not started, so any download fails
+    let package = Package::select(None, None);
+    assert_eq!(provision(&package, true, &config(), &out, Some(&cache)), cache);
+    assert!(!check_files(&out, &files));
+
+    // Found by `Source::Cache`, and provisioned without verifying them again
+    assert_eq!(find_provisioned(&package, &config(), &out, Some(&cache)), Some(cache.clone()));
+    assert_eq!(provision(&package, true, &config(), &out, Some(&cache)), cache);
+}
+
+#[test]
+fn switch_cached_versions() {
+    let dir = work_dir("switch_cached_versions");
+    let (out, root) = (dir.join("out"), dir.join("cache"));
+    let files = mkl_files(&config());
+    let a = Package::select(Some("2019.1"), None);
+    let b = Package {
+        version: "2020.0".to_string(),
+        build: Some("intel_166".to_string()),
+        md5: None,
+        url: None,
+        extension: ".tar.bz2",
+    };
+    for package in &[&a, &b] {
+        let cache = cache_dir(&out, Some(&root), package, &config()).unwrap();
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("mkl.tar.bz2"), fixture_archive_with("", &package.version)).unwrap();
+        extract(cache.join("mkl.tar.bz2"), &cache, &files).unwrap();
+    }
+    // The libraries of another version in `out` are not reused
+    fs::create_dir_all(&out).unwrap();
+    fs::write(out.join("mkl.tar.bz2"), fixture_archive()).unwrap();
+    extract(out.join("mkl.tar.bz2"), &out, &files).unwrap();
+
+    // The server is not started, so any download fails
+    for package in &[&a, &b, &a] {
+        let cache = cache_dir(&out, Some(&root), package, &config()).unwrap();
+        let lib_dir = provision(package, true, &config(), &out, Some(&cache));
+        assert_eq!(lib_dir, cache);
+        let content = fs::read_to_string(lib_dir.join(&files[0])).unwrap();
+        assert!(content.starts_with(&package.version));
+    }
+}
+
+#[test]
+fn extract_headers() {
+    let dir = work_dir("extract_headers");
+    let encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::Fastest);
+    let mut builder = tar::Builder::new(encoder);
+    for path in &["include/mkl.h", "include/mkl_cblas.h", "info/index.json"] {
+        builder.append_data(&mut header(4), path, &b"test"[..]).unwrap();
+    }
+    fs::write(dir.join("mkl-include.tar.bz2"), builder.into_inner().unwrap().finish().unwrap()).unwrap();
+
+    let count = extract_dir(&dir.join("mkl-include.tar.bz2"), "include".as_ref(), &dir).unwrap();
+    assert_eq!(count, 2);
+    assert!(dir.join("include/mkl.h").exists());
+    assert!(dir.join("include/mkl_cblas.h").exists());
+    assert!(!dir.join("info").exists());
+
+    // Reused by the marker without fetching repodata.json
+    fs::write(dir.join("mkl-include.txt"), "2019.1 intel_144 0123456789abcdef").unwrap();
+    if cfg!(not(target_os = "windows")) {
+        assert_eq!(provision_headers("2019.1", &dir).unwrap(), dir.join("include"));
+    }
+}
+
+#[test]
+fn extract_only_bad_files() {
+    let dir = work_dir("extract_only_bad_files");
+    let files = mkl_files(&config());
+    fs::write(dir.join("mkl.tar.bz2"), fixture_archive()).unwrap();
+    extract(dir.join("mkl.tar.bz2"), &dir, &files).unwrap();
+
+    fs::write(dir.join(&files[1]), "modified").unwrap();
+    let bad = verify_files(&dir, &files);
+    assert_eq!(bad, vec![files[1].clone()]);
+
+    fs::write(dir.join("other.tar.bz2"), fixture_archive_with("", "other")).unwrap();
+    extract(dir.join("other.tar.bz2"), &dir, &bad).unwrap();
+    assert!(verify_files(&dir, &files).is_empty());
+    let content = |file: &PathBuf| fs::read_to_string(dir.join(file)).unwrap();
+    assert!(content(&files[0]).starts_with("fixture"));
+    assert!(content(&files[1]).starts_with("other"));
+}
+
+#[test]
+fn size_before_checksum() {
+    let dir = work_dir("size_before_checksum");
+    let path = dir.join("libmkl_core.a");
+    fs::write(&path, "fixture").unwrap();
+    let md5 = calc_md5(&path);
+    assert_eq!(verify_file(&path, &md5, 7), Ok(()));
+
+    fs::write(&path, "").unwrap();
+    assert!(verify_file(&path, &md5, 7).unwrap_err().contains("file truncated?"));
+    fs::write(&path, "changed").unwrap();
+    assert!(verify_file(&path, &md5, 7).unwrap_err().starts_with("checksum mismatch"));
+    assert!(verify_file(&dir.join("missing.a"), &md5, 7).unwrap_err().starts_with("not found"));
+}
+
+#[test]
+fn copy_dlls() {
+    let dir = work_dir("copy_dlls");
+    let (bin, profile) = (dir.join("Library/bin"), dir.join("debug"));
+    fs::create_dir_all(&bin).unwrap();
+    fs::create_dir_all(&profile).unwrap();
+    for name in &["mkl_rt.dll", "mkl_core.dll", "libiomp5md.dll", "python.dll", "mkl_rt.pdb"] {
+        fs::write(bin.join(name), name).unwrap();
+    }
+    let copied = copy_runtime_dlls(&bin, &profile).unwrap();
+    let names: Vec<_> = copied.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+    assert_eq!(names, vec!["libiomp5md.dll", "mkl_core.dll", "mkl_rt.dll"]);
+    assert_eq!(fs::read_to_string(profile.join("mkl_rt.dll")).unwrap(), "mkl_rt.dll");
+    assert!(!profile.join("python.dll").exists());
+
+    // MKL_RUNTIME_DIR is only checked
+    assert_eq!(find_runtime_lib(&bin, "windows"), Some(bin.join("mkl_rt.dll")));
+    assert_eq!(find_runtime_lib(&bin, "linux"), None);
+    assert_eq!(find_runtime_lib(&dir.join("missing"), "windows"), None);
+}
+
+#[test]
+fn mklroot_layout() {
+    let root = work_dir("mklroot_layout");
+    let files = mkl_files(&config());
+    assert_eq!(
+        probe_lib_dir(&root, &config(), "linux"),
+        Err(vec![root.join("lib"), root.join("lib/intel64"), root.join("lib/intel64_lin")])
+    );
+
+    // The first layout containing all libraries
+    fs::create_dir_all(root.join("lib")).unwrap();
+    fs::write(root.join("lib").join(files[0].file_name().unwrap()), "partial").unwrap();
+    let legacy = root.join("lib/intel64_lin");
+    fs::create_dir_all(&legacy).unwrap();
+    for file in &files {
+        fs::write(legacy.join(file.file_name().unwrap()), "legacy").unwrap();
+    }
+    assert_eq!(probe_lib_dir(&root, &config(), "linux"), Ok(legacy));
+}
+
+#[test]
+fn pip_layout() {
+    let venv = work_dir("pip_layout");
+    let files = mkl_files(&config());
+    let site_packages = venv.join("lib/python3.11/site-packages/mkl/lib");
+    assert_eq!(pip_lib_dirs(&venv, "linux"), vec![venv.join("lib")]);
+    fs::create_dir_all(&site_packages).unwrap();
+    assert_eq!(pip_lib_dirs(&venv, "linux"), vec![venv.join("lib"), site_packages.clone()]);
+    assert!(find_lib_dir(pip_lib_dirs(&venv, "linux"), &config(), "linux").is_err());
+
+    // only `mkl` without the static libraries of `mkl-static`
+    fs::write(venv.join("lib/libmkl_rt.so.2"), "shared").unwrap();
+    assert!(find_lib_dir(pip_lib_dirs(&venv, "linux"), &config(), "linux").is_err());
+    for file in &files {
+        fs::write(site_packages.join(file.file_name().unwrap()), "static").unwrap();
+    }
+    assert_eq!(find_lib_dir(pip_lib_dirs(&venv, "linux"), &config(), "linux"), Ok(site_packages));
+}
+
+#[test]
+fn reuse_leftover_archive() {
+    let dir = work_dir("reuse_leftover_archive");
+    let archive = fixture_archive();
+    let package = Package {
+        version: "2019.1".to_string(),
+        build: Some("test_0".to_string()),
+        md5: Some(format!("{:x}", md5::compute(&archive))),
+        url: None,
+        extension: ".tar.bz2",
+    };
+    fs::write(dir.join(package.filename()), &archive).unwrap();
+    let files = mkl_files(&config());
+
+    // The server is not started, so any download fails
+    let path = fetch_archive(&package, true, &dir, &files).unwrap();
+    assert_eq!(path, dir.join(package.filename()));
+    assert!(check_files(&dir, &files));
+}
+
+#[test]
+fn fall_through_broken_archive() {
+    let dir = work_dir("fall_through_broken_archive");
+    let server = Server::start(fixture_archive());
+    // Matches the checksum, e.g. corrupted before it was published, and fails to extract
+    let broken = b"not a tarball".to_vec();
+    let package = Package {
+        version: "2019.1".to_string(),
+        build: Some("test_0".to_string()),
+        md5: Some(format!("{:x}", md5::compute(&broken))),
+        url: Some(server.uri("/missing.tar.bz2")),
+        extension: ".tar.bz2",
+    };
+    fs::write(dir.join(package.filename()), &broken).unwrap();
+    let files = mkl_files(&config());
+
+    let result = fetch_archive(&package, true, &dir, &files);
+    assert!(matches!(result, Err(BuildError::HttpStatus { code: 404, .. })), "{:?}", result);
+    assert_eq!(server.requests(), 1);
+    assert!(!dir.join(package.filename()).exists());
+    assert!(attempts_summary().contains("failed to extract"));
+}
+
+/// `.conda` package containing the files of `config()`
+fn conda_archive() -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for file in mkl_files(&config()) {
+        let content = format!("conda of {}\n", file.display()).into_bytes();
+        builder.append_data(&mut header(content.len()), &file, &content[..]).unwrap();
+    }
+    let pkg = zstd::encode_all(&builder.into_inner().unwrap()[..], 3).unwrap();
+
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    writer.start_file("metadata.json", options).unwrap();
+    writer.write_all(b"{\"conda_pkg_format_version\": 2}").unwrap();
+    writer.start_file("info-mkl.tar.zst", options).unwrap();
+    writer.write_all(&zstd::encode_all(&b""[..], 3).unwrap()).unwrap();
+    writer.start_file("pkg-mkl.tar.zst", options).unwrap();
+    writer.write_all(&pkg).unwrap();
+    writer.finish().unwrap().into_inner()
+}
+
+#[test]
+fn extract_conda() {
+    let dir = work_dir("extract_conda");
+    fs::write(dir.join("mkl.conda"), conda_archive()).unwrap();
+
+    let files = mkl_files(&config());
+    extract(dir.join("mkl.conda"), &dir, &files).unwrap();
+    assert!(check_files(&dir, &files));
+    assert!(fs::read_to_string(dir.join(&files[0])).unwrap().starts_with("conda"));
+
+    // The whole directory, as the headers
+    let extracted = dir.join("extracted");
+    fs::create_dir_all(&extracted).unwrap();
+    let count = extract_dir(&dir.join("mkl.conda"), platform().lib_path.as_ref(), &extracted).unwrap();
+    assert_eq!(count, files.len());
+}
+
+#[test]
+fn download_conda() {
+    let archive = conda_archive();
+    let md5 = format!("{:x}", md5::compute(&archive));
+    let server = Server::start(archive);
+    let dir = work_dir("download_conda");
+    let files = mkl_files(&config());
+    // Extracted after the download, not from the stream
+    download_archive(&server.uri("/mkl.tar.bz2"), "mkl.conda", &md5, &dir, &files).unwrap();
+    assert!(check_files(&dir, &files));
+    assert!(fs::read_to_string(dir.join(&files[0])).unwrap().starts_with("conda"));
+    assert!(!dir.join("mkl-staging").exists());
+}
+
+#[test]
+fn strip_debug_sections() {
+    if !cfg!(target_os = "linux") {
+        return;
+    }
+    let dir = work_dir("strip_debug_sections");
+    fs::write(dir.join("fixture.c"), "int fixture_function(int x) { return x + 1; }\n").unwrap();
+    let run = |program: &str, args: &[&str]| {
+        let status = std::process::Command::new(program)
+            .args(args)
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} failed", program);
+    };
+    run("cc", &["-g", "-c", "fixture.c", "-o", "fixture.o"]);
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    run("ar", &["rcs", "lib/libfixture.a", "fixture.o"]);
+    let files = vec![PathBuf::from("lib/libfixture.a")];
+    fs::write(dir.join("mkl-manifest.txt"), "").unwrap();
+
+    let (before, after) = strip_files(&dir, &files).unwrap();
+    assert!(after < before, "{} -> {}", before, after);
+    assert!(check_files(&dir, &files));
+    let symbols = std::process::Command::new("nm").arg(dir.join(&files[0])).output().unwrap();
+    assert!(String::from_utf8_lossy(&symbols.stdout).contains("fixture_function"));
+}
+
+#[test]
+fn offline_archive_dir() {
+    let dir = work_dir("offline_archive_dir");
+    let archive = fixture_archive();
+    let md5 = format!("{:x}", md5::compute(&archive));
+    fs::write(dir.join("mkl-static-2019.1-intel_144.tar.bz2"), &archive).unwrap();
+    fs::write(dir.join("mkl-static-2020.0-intel_166.tar.bz2"), "broken").unwrap();
+
+    assert_eq!(
+        find_offline_archive(&dir, "mkl-static-2019.1-intel_144.tar.bz2", &md5),
+        Some(dir.join("mkl-static-2019.1-intel_144.tar.bz2"))
+    );
+    assert_eq!(find_offline_archive(&dir, "mkl-static-2020.0-intel_166.tar.bz2", &md5), None);
+    assert_eq!(find_offline_archive(&dir, "mkl-static-2021.1-intel_52.tar.bz2", &md5), None);
+
+    // Summarized when provisioning fails
+    let summary = attempts_summary();
+    let line = |archive: &str, result: &str| format!("  {} -> {}", dir.join(archive).display(), result);
+    assert!(summary.contains(&line("mkl-static-2020.0-intel_166.tar.bz2", "checksum mismatch")), "{}", summary);
+    assert!(summary.contains(&line("mkl-static-2021.1-intel_52.tar.bz2", "not found")), "{}", summary);
+}
+
+#[test]
+fn archive_cache_dir() {
+    let dir = work_dir("archive_cache_dir");
+    let (out, cache) = (dir.join("out"), dir.join("archives"));
+    fs::create_dir_all(&out).unwrap();
+    let archive = fixture_archive();
+    let md5 = format!("{:x}", md5::compute(&archive));
+    let name = "mkl-static-2019.1-intel_144.tar.bz2";
+    fs::write(out.join(name), &archive).unwrap();
+
+    // Moved, and not kept twice
+    assert_eq!(store_archive(&out.join(name), &cache).unwrap(), cache.join(name));
+    assert!(!out.join(name).exists());
+    assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+
+    // Reused by another install
+    let cached = find_offline_archive(&cache, name, &md5).unwrap();
+    let files = mkl_files(&config());
+    extract(&cached, &out, &files).unwrap();
+    assert!(check_files(&out, &files));
+}
+
+#[test]
+fn provision_without_archive() {
+    let dir = work_dir("provision_without_archive");
+    let archive = fixture_archive();
+    let package = Package {
+        version: "2019.1".to_string(),
+        build: Some("test_0".to_string()),
+        md5: Some(format!("{:x}", md5::compute(&archive))),
+        url: None,
+        extension: ".tar.bz2",
+    };
+    fs::write(dir.join(package.filename()), &archive).unwrap();
+    let files = mkl_files(&config());
+    assert_eq!(provision(&package, true, &config(), &dir, None), dir);
+
+    // The extracted libraries are verified without the archive, and the server is not started
+    fs::remove_file(dir.join(package.filename())).unwrap();
+    assert_eq!(provision(&package, true, &config(), &dir, None), dir);
+    assert!(check_files(&dir, &files));
+}
+
+#[test]
+fn retry_transient_errors() {
+    use std::io;
+    let attempts = AtomicUsize::new(0);
+    let result = retry_io(|| match attempts.fetch_add(1, Ordering::SeqCst) {
+        0 | 1 => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+        _ => Ok(42),
+    });
+    assert_eq!(result.unwrap(), 42);
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+    // not retried
+    let attempts = AtomicUsize::new(0);
+    let result: io::Result<()> = retry_io(|| {
+        attempts.fetch_add(1, Ordering::SeqCst);
+        Err(io::Error::from(io::ErrorKind::StorageFull))
+    });
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::StorageFull);
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    assert!(!is_retryable(&io::Error::from(io::ErrorKind::InvalidData)));
+}
+
+#[test]
+fn explain_sources() {
+    let dir = work_dir("explain_sources");
+    let (out, cache) = (dir.join("out"), dir.join("cache"));
+    fs::create_dir_all(&out).unwrap();
+    let archive = fixture_archive();
+    let package = Package {
+        version: "2019.1".to_string(),
+        build: Some("test_0".to_string()),
+        md5: Some(format!("{:x}", md5::compute(&archive))),
+        url: Some("https://mirror.example.com/mkl.tar.bz2".to_string()),
+        extension: ".tar.bz2",
+    };
+    let explain = || explain_provision(&package, &config(), &out, Some(&cache));
+    assert!(explain().starts_with("download https://mirror.example.com/mkl.tar.bz2"), "{}", explain());
+
+    fs::write(out.join(package.filename()), &archive).unwrap();
+    assert!(explain().starts_with(&format!("extract {}", out.join(package.filename()).display())), "{}", explain());
+
+    // nothing has been downloaded or extracted
+    assert!(!out.join(&mkl_files(&config())[0]).exists());
+
+    provision(&package, true, &config(), &out, Some(&cache));
+    assert!(explain().starts_with(&format!("use the libraries extracted in {}", out.display())), "{}", explain());
+    fs::remove_dir_all(&out).unwrap();
+    fs::create_dir_all(&out).unwrap();
+    assert!(explain().starts_with(&format!("use the shared cache at {}", cache.display())), "{}", explain());
+}
+
+#[test]
+fn separate_interfaces() {
+    let dir = work_dir("separate_interfaces");
+    let (debug, release, root) = (dir.join("debug"), dir.join("release"), dir.join("cache"));
+    let lp64 = config();
+    let ilp64 = MklConfig::new(Interface::ILP64, Threading::Sequential);
+    // mkl_core and mkl_sequential are shared by both
+    let mut files = mkl_files(&lp64);
+    files.extend(mkl_files(&ilp64));
+    files.sort();
+    files.dedup();
+    let archive = fixture_archive_of(&files, "", "fixture");
+    let package = Package {
+        version: "2019.1".to_string(),
+        build: Some("test_0".to_string()),
+        md5: Some(format!("{:x}", md5::compute(&archive))),
+        url: None,
+        extension: ".tar.bz2",
+    };
+
+    // The server is not started, and both are extracted from the archive left in each directory
+    let mut caches = Vec::new();
+    for (out, config) in &[(&debug, &lp64), (&release, &ilp64)] {
+        fs::create_dir_all(out).unwrap();
+        fs::write(out.join(package.filename()), &archive).unwrap();
+        let cache = cache_dir(out, Some(&root), &package, config).unwrap();
+        assert_eq!(provision(&package, true, config, out, Some(&cache)), **out);
+        caches.push(cache);
+    }
+    assert_ne!(caches[0], caches[1]);
+    assert!(check_files(&caches[0], &mkl_files(&lp64)));
+    assert!(check_files(&caches[1], &mkl_files(&ilp64)));
+    // Neither contains the interface layer of the other
+    assert!(!check_files(&caches[0], &mkl_files(&ilp64)));
+    assert!(!check_files(&caches[1], &mkl_files(&lp64)));
+
+    // Switching the interface in the same directory extracts the other layer, and never reuses the cache of lp64
+    fs::remove_dir_all(&caches[1]).unwrap();
+    assert_eq!(provision(&package, true, &ilp64, &debug, Some(&caches[1])), debug);
+    assert!(check_files(&debug, &mkl_files(&ilp64)));
+    assert_eq!(fs::read_to_string(debug.join("mkl-package.txt")).unwrap(), cache_key(&package, &ilp64));
+}
+
+#[test]
+fn heal_corrupt_cache() {
+    let dir = work_dir("heal_corrupt_cache");
+    let archive = fixture_archive();
+    let server = Server::start(archive.clone());
+    let package = Package {
+        version: "2019.1".to_string(),
+        build: Some("test_0".to_string()),
+        md5: Some(format!("{:x}", md5::compute(&archive))),
+        url: Some(server.uri("/mkl.tar.bz2")),
+        extension: ".tar.bz2",
+    };
+    let files = mkl_files(&config());
+    assert_eq!(provision(&package, true, &config(), &dir, None), dir);
+    assert_eq!(server.requests(), 1);
+
+    // The intact libraries are marked by the modification time, and are never written again
+    let marked = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let mark = |file: &PathBuf| File::options().write(true).open(dir.join(file)).unwrap().set_modified(marked).unwrap();
+    let modified = |file: &PathBuf| fs::metadata(dir.join(file)).unwrap().modified().unwrap();
+    let corrupt = |file: &PathBuf| {
+        let mut content = fs::read(dir.join(file)).unwrap();
+        content[0] ^= 0xff;
+        fs::write(dir.join(file), content).unwrap();
+    };
+    files.iter().for_each(mark);
+
+    // Repaired from the archive left in OUT_DIR
+    corrupt(&files[1]);
+    assert_eq!(verify_files(&dir, &files), vec![files[1].clone()]);
+    assert_eq!(provision(&package, true, &config(), &dir, None), dir);
+    assert_eq!(server.requests(), 1);
+    assert!(verify_files(&dir, &files).is_empty());
+    assert_ne!(modified(&files[1]), marked);
+    assert_eq!(modified(&files[0]), marked);
+    assert_eq!(modified(&files[2]), marked);
+
+    // Downloaded again only when the archive is gone
+    mark(&files[1]);
+    fs::remove_file(dir.join(package.filename())).unwrap();
+    corrupt(&files[2]);
+    assert_eq!(provision(&package, true, &config(), &dir, None), dir);
+    assert_eq!(server.requests(), 2);
+    assert!(verify_files(&dir, &files).is_empty());
+    assert!(files[..2].iter().all(|file| modified(file) == marked));
+    assert_ne!(modified(&files[2]), marked);
+}
+
+#[test]
+fn content_store() {
+    let dir = work_dir("content_store");
+    let store = dir.join("store");
+    let files = mkl_files(&config());
+    let (old, new) = (&dir.join("2019.1"), &dir.join("2019.2"));
+    for out in &[old, new] {
+        fs::create_dir_all(out).unwrap();
+        fs::write(out.join("mkl.tar.bz2"), fixture_archive()).unwrap();
+        extract(out.join("mkl.tar.bz2"), out, &files).unwrap();
+    }
+    // mkl_core differs between the versions
+    fs::write(new.join("other.tar.bz2"), fixture_archive_with("", "other")).unwrap();
+    extract(new.join("other.tar.bz2"), new, &files[2..]).unwrap();
+
+    assert_eq!(store_files(old, &store, &files).unwrap(), 0);
+    let shared: u64 = files[..2].iter().map(|file| fs::metadata(old.join(file)).unwrap().len()).sum();
+    assert_eq!(store_files(new, &store, &files).unwrap(), shared);
+    for (path, md5) in manifest_checksums(new, &files).into_iter().chain(manifest_checksums(old, &files)) {
+        assert_eq!(calc_md5(&store.join(&md5[..2]).join(&md5)), md5);
+        assert_eq!(calc_md5(&path), md5);
+    }
+    assert!(check_files(old, &files));
+    assert!(check_files(new, &files));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |dir: &PathBuf, file: &PathBuf| fs::metadata(dir.join(file)).unwrap().ino();
+        assert_eq!(inode(old, &files[0]), inode(new, &files[0]));
+        assert_ne!(inode(old, &files[2]), inode(new, &files[2]));
+    }
+
+    // An object changed in the store is replaced
+    let md5 = calc_md5(&old.join(&files[0]));
+    let object = store.join(&md5[..2]).join(&md5);
+    fs::remove_file(&object).unwrap();
+    fs::write(&object, "changed").unwrap();
+    assert_eq!(store_files(old, &store, &files[..1]).unwrap(), 0);
+    assert_eq!(calc_md5(&object), md5);
+}
+
+/// `ar` archive of the objects `members`, after the symbol table
+fn static_library(members: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut library = b"!<arch>\n".to_vec();
+    for (name, data) in [("/", &b"\0\0\0\0"[..])].iter().chain(members) {
+        library.extend(format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 644, data.len()).into_bytes());
+        library.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            library.push(b'\n');
+        }
+    }
+    library
+}
+
+/// Header of ELF64 of `machine` and the byte order
+fn elf(machine: u16, little: bool) -> Vec<u8> {
+    let mut header = b"\x7fELF\x02".to_vec();
+    header.push(if little { 1 } else { 2 });
+    header.push(1);
+    header.resize(18, 0);
+    header.extend_from_slice(&if little { machine.to_le_bytes() } else { machine.to_be_bytes() });
+    header.resize(64, 0);
+    header
+}
+
+/// Header of a Mach-O 64-bit object of `cpu`
+fn macho(cpu: u32) -> Vec<u8> {
+    let mut header = vec![0xcf, 0xfa, 0xed, 0xfe];
+    header.extend_from_slice(&cpu.to_le_bytes());
+    header.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0]);
+    header.resize(32, 0);
+    header
+}
+
+#[test]
+fn object_architecture() {
+    assert_eq!(object_arch(&elf(0x3e, true)[..]).as_deref(), Some(EXPECTED_ARCH));
+    assert_eq!(object_arch(&elf(0xb7, true)[..]).as_deref(), Some("aarch64 little-endian"));
+    assert_eq!(object_arch(&elf(0x15, false)[..]).as_deref(), Some("powerpc big-endian"));
+    // Mach-O, COFF, and the import object of a DLL
+    assert_eq!(object_arch(&macho(0x0100_0007)[..]).as_deref(), Some(EXPECTED_ARCH));
+    assert_eq!(object_arch(&macho(0x0100_000c)[..]).as_deref(), Some("aarch64 little-endian"));
+    let mut coff = vec![0x64, 0x86];
+    coff.resize(20, 0);
+    assert_eq!(object_arch(&coff[..]).as_deref(), Some(EXPECTED_ARCH));
+    let mut import = vec![0, 0, 0xff, 0xff, 0, 0, 0x64, 0x86, 0, 0, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0];
+    import.extend_from_slice(b"dgemm\0mkl.dll\0");
+    assert_eq!(object_arch(&import[..]).as_deref(), Some(EXPECTED_ARCH));
+    assert_eq!(object_arch(&b"fixture"[..]), None);
+
+    let dir = work_dir("object_architecture");
+    let files = mkl_files(&config());
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    for file in &files {
+        fs::write(dir.join(file), static_library(&[("core.o/", &elf(0x3e, true))])).unwrap();
+    }
+    assert_eq!(library_archs(&dir.join(&files[0])).unwrap(), vec![EXPECTED_ARCH]);
+    assert_eq!(check_arch("mkl.tar.bz2", &dir, &files), Ok(()));
+
+    // Every object, not only the first one
+    let mixed = static_library(&[("core.o/", &elf(0x3e, true)), ("arm.o/", &elf(0xb7, true))]);
+    fs::write(dir.join(&files[1]), mixed).unwrap();
+    assert_eq!(library_archs(&dir.join(&files[1])).unwrap(), vec![EXPECTED_ARCH, "aarch64 little-endian"]);
+    assert_eq!(
+        check_arch("mkl.tar.bz2", &dir, &files).unwrap_err(),
+        format!(
+            "archive mkl.tar.bz2 contains aarch64 little-endian objects, expected x86_64 little-endian ({})",
+            files[1].display()
+        )
+    );
+
+    // Not static libraries, e.g. of the fixture archives
+    fs::write(dir.join(&files[1]), "fixture").unwrap();
+    assert!(library_archs(&dir.join(&files[1])).unwrap().is_empty());
+    assert_eq!(check_arch("mkl.tar.bz2", &dir, &files), Ok(()));
+}
+
+/// x86_64 relocatable of `elf()` with a relocation of `r_type` against a section loaded by `alloc`
+fn elf_object(r_type: u8, alloc: bool) -> Vec<u8> {
+    // The RELA entry, the empty names, and the null, target, RELA, and STRTAB section headers
+    let (rela, names, headers) = (64, 88, 96);
+    let mut object = elf(0x3e, true);
+    object[16] = 1;
+    object[0x28] = headers as u8;
+    object[0x3a] = 64;
+    object[0x3c] = 4;
+    object[0x3e] = 3;
+    object.resize(headers + 4 * 64, 0);
+    object[rela + 8] = r_type;
+    object[headers + 64 + 8] = if alloc { 2 } else { 0 };
+    let section = headers + 2 * 64;
+    object[section + 4] = 4;
+    object[section + 0x18] = rela as u8;
+    object[section + 0x20] = 24;
+    object[section + 0x2c] = 1;
+    let section = headers + 3 * 64;
+    object[section + 4] = 3;
+    object[section + 0x18] = names as u8;
+    object[section + 0x20] = 1;
+    object
+}
+
+#[test]
+fn position_independent() {
+    let vars = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    };
+    assert_eq!(relocation_model(vars(&[("CARGO_ENCODED_RUSTFLAGS", "")])), None);
+    assert_eq!(
+        relocation_model(vars(&[("CARGO_ENCODED_RUSTFLAGS", "-C\x1frelocation-model=static\x1f-Ctarget-cpu=native")])),
+        Some("static".to_string())
+    );
+    assert_eq!(
+        relocation_model(vars(&[("CARGO_ENCODED_RUSTFLAGS", "-Crelocation-model=static\x1f-Crelocation-model=pie")])),
+        Some("pie".to_string())
+    );
+    assert_eq!(relocation_model(vars(&[("RUSTFLAGS", "-C relocation-model=dynamic-no-pic")])), Some("dynamic-no-pic".to_string()));
+    assert_eq!(
+        relocation_model(vars(&[("CARGO_CFG_RELOCATION_MODEL", "pic"), ("RUSTFLAGS", "-Crelocation-model=static")])),
+        Some("pic".to_string())
+    );
+
+    // R_X86_64_32 and R_X86_64_32S, but not R_X86_64_PC32 nor the ones of the debug sections
+    assert!(object_non_pic(&elf_object(10, true)[..]));
+    assert!(object_non_pic(&elf_object(11, true)[..]));
+    assert!(!object_non_pic(&elf_object(2, true)[..]));
+    assert!(!object_non_pic(&elf_object(10, false)[..]));
+    assert!(!object_non_pic(&b"\x7fELF"[..]));
+
+    let dir = work_dir("position_independent");
+    let names = b"a_long_name_of_the_object.o/\n";
+    let lib = dir.join("libmkl_nonpic.a");
+    fs::write(
+        &lib,
+        static_library(&[
+            ("//", &names[..]),
+            ("pic.o/", &elf_object(2, true)),
+            ("/0", &elf_object(10, true)),
+            ("debug.o/", &elf_object(10, false)),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(non_pic_members(&lib).unwrap(), vec!["a_long_name_of_the_object.o"]);
+    let pic = dir.join("libmkl_pic.a");
+    fs::write(&pic, static_library(&[("pic.o/", &elf_object(2, true))])).unwrap();
+    assert_eq!(non_pic_members(&pic).unwrap(), Vec::<String>::new());
+
+    let libs = vec![pic, lib];
+    let e = check_pic(&libs, None, "linux").unwrap_err();
+    assert!(e.contains("a_long_name_of_the_object.o"), "{}", e);
+    assert!(e.contains("-C relocation-model=static"), "{}", e);
+    check_pic(&libs, Some("static"), "linux").unwrap();
+    check_pic(&libs, Some("dynamic-no-pic"), "linux").unwrap();
+    check_pic(&libs, None, "macos").unwrap();
+    assert!(check_pic(&libs, Some("pie"), "linux").is_err());
+}
+
+#[test]
+fn package_index() {
+    let dir = work_dir("package_index");
+    let index = br#"{"name": "mkl-static", "version": "2019.1", "build": "intel_144", "build_number": 144}"#;
+    let encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::Fastest);
+    let mut builder = tar::Builder::new(encoder);
+    builder.append_data(&mut header(index.len()), "info/index.json", &index[..]).unwrap();
+    fs::write(dir.join("mkl.tar.bz2"), builder.into_inner().unwrap().finish().unwrap()).unwrap();
+
+    let index = read_package_index(&dir.join("mkl.tar.bz2")).unwrap();
+    assert_eq!(check_package_index(&index, "mkl-static-2019.1-intel_144.tar.bz2", "2019.1"), Ok(()));
+    assert_eq!(check_package_index(&index, "mkl-static-2019.1-intel_144.conda", "2019.1"), Ok(()));
+    assert_eq!(
+        check_package_index(&index, "mkl-static-2019.1-intel_304.tar.bz2", "2019.1"),
+        Err("mkl-static-2019.1-intel_304.tar.bz2 is mkl-static 2019.1 intel_144 by info/index.json, \
+             expected mkl-static-2019.1-intel_304 2019.1"
+            .to_string())
+    );
+    assert!(check_package_index(&index, "mkl-static-2019.1-intel_144.tar.bz2", "2020.0").is_err());
+    assert!(check_package_index(&index, "mkl-2019.1-intel_144.tar.bz2", "2019.1").is_err());
+
+    // no metadata in the archive
+    fs::write(dir.join("empty.tar.bz2"), fixture_archive_of(&[], "", "")).unwrap();
+    assert!(read_package_index(&dir.join("empty.tar.bz2")).unwrap().get("name").is_none());
+    fs::write(dir.join("libs.tar.bz2"), {
+        let encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::Fastest);
+        tar::Builder::new(encoder).into_inner().unwrap().finish().unwrap()
+    })
+    .unwrap();
+    assert!(read_package_index(&dir.join("libs.tar.bz2")).is_err());
+}
+
+#[test]
+fn oci_reference() {
+    let reference = OciRef::parse("ghcr.io/org/mkl/mkl-static:{version}-{build}", "2019.1", "intel_144").unwrap();
+    assert_eq!(reference.registry, "ghcr.io");
+    assert_eq!(reference.repository, "org/mkl/mkl-static");
+    assert_eq!(reference.reference, "2019.1-intel_144");
+    assert_eq!(
+        reference.url("manifests", &reference.reference),
+        "https://ghcr.io/v2/org/mkl/mkl-static/manifests/2019.1-intel_144"
+    );
+    let pinned = OciRef::parse("http://localhost:5000/mkl@sha256:0123", "2019.1", "intel_144").unwrap();
+    assert_eq!((pinned.scheme.as_str(), pinned.registry.as_str()), ("http", "localhost:5000"));
+    assert_eq!(pinned.reference, "sha256:0123");
+    assert_eq!(OciRef::parse("registry.local:5000/mkl", "", "").unwrap().reference, "latest");
+    assert_eq!(OciRef::parse("docker.io/org/mkl:1", "", "").unwrap().registry, "registry-1.docker.io");
+    assert!(OciRef::parse("org/mkl:1", "", "").is_err());
+    assert!(OciRef::parse("ftp://ghcr.io/org/mkl:1", "", "").is_err());
+
+    let manifest = serde_json::json!({
+        "layers": [
+            { "digest": "sha256:aaaa", "annotations": { "org.opencontainers.image.title": "mkl-include.tar.bz2" } },
+            { "digest": "sha256:bbbb", "annotations": { "org.opencontainers.image.title": "mkl-static.tar.bz2" } }
+        ]
+    });
+    assert_eq!(oci_layer(&manifest, "mkl-static.tar.bz2"), Ok("sha256:bbbb".to_string()));
+    assert!(oci_layer(&manifest, "mkl.tar.bz2").unwrap_err().contains("mkl-include.tar.bz2, mkl-static.tar.bz2"));
+    let single = serde_json::json!({ "layers": [{ "digest": "sha256:cccc" }] });
+    assert_eq!(oci_layer(&single, "mkl-static.tar.bz2"), Ok("sha256:cccc".to_string()));
+    assert!(oci_layer(&serde_json::json!({ "manifests": [] }), "mkl-static.tar.bz2").is_err());
+}
+
+#[test]
+fn oci_credentials() {
+    for data in ["", "u", "us", "user:password"] {
+        assert_eq!(base64_decode(&base64_encode(data.as_bytes())).unwrap(), data.as_bytes());
+    }
+    assert_eq!(base64_encode(b"user:password"), "dXNlcjpwYXNzd29yZA==");
+    assert_eq!(base64_decode("not base64!"), None);
+
+    let config = serde_json::json!({
+        "auths": {
+            "ghcr.io": { "auth": "dXNlcjpwYXNzd29yZA==" },
+            "https://index.docker.io/v1/": { "auth": base64_encode(b"hub:secret") }
+        }
+    });
+    assert_eq!(docker_credentials(&config, "ghcr.io"), Some(("user".to_string(), "password".to_string())));
+    assert_eq!(
+        docker_credentials(&config, "registry-1.docker.io"),
+        Some(("hub".to_string(), "secret".to_string()))
+    );
+    assert_eq!(docker_credentials(&config, "registry.local"), None);
+
+    assert_eq!(
+        bearer_token_url(r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:org/mkl:pull""#).unwrap(),
+        "https://ghcr.io/token?service=ghcr.io&scope=repository%3Aorg%2Fmkl%3Apull"
+    );
+    // Commas and escapes in the quoted values
+    assert_eq!(
+        bearer_token_url(r#"Bearer realm="https://auth.example.com/token", scope="repository:org/mkl:pull,push",service=registry"#)
+            .unwrap(),
+        "https://auth.example.com/token?scope=repository%3Aorg%2Fmkl%3Apull%2Cpush&service=registry"
+    );
+    assert_eq!(
+        parse_auth_params(r#"realm="a\"b", error="insufficient_scope""#),
+        Some(vec![("realm".to_string(), "a\"b".to_string()), ("error".to_string(), "insufficient_scope".to_string())])
+    );
+    assert_eq!(parse_auth_params(r#"realm="unterminated"#), None);
+    assert_eq!(bearer_token_url(r#"Bearer service="ghcr.io""#), None);
+    assert_eq!(bearer_token_url(r#"Basic realm="registry""#), None);
+}
@@ -0,0 +1,668 @@
+// Libraries extracted from the archive for each configuration
+
+extern crate bzip2;
+extern crate curl;
+extern crate md5;
+extern crate object;
+extern crate tar;
+
+#[allow(dead_code)]
+#[path = "../build.rs"]
+mod build;
+
+use build::*;
+use std::path::{Path, PathBuf};
+
+fn lib(name: &str) -> PathBuf {
+    if cfg!(target_os = "windows") {
+        PathBuf::from("lib").join(format!("{}.lib", name))
+    } else {
+        PathBuf::from("lib").join(format!("lib{}.a", name))
+    }
+}
+
+#[test]
+fn sequential_lp64() {
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    assert_eq!(
+        mkl_files(&config),
+        vec![lib("mkl_intel_lp64"), lib("mkl_sequential"), lib("mkl_core")]
+    );
+}
+
+#[test]
+fn sequential_ilp64() {
+    let config = MklConfig::new(Interface::ILP64, Threading::Sequential);
+    assert_eq!(
+        mkl_files(&config),
+        vec![lib("mkl_intel_ilp64"), lib("mkl_sequential"), lib("mkl_core")]
+    );
+}
+
+#[test]
+fn openmp_lp64() {
+    let config = MklConfig::new(Interface::LP64, Threading::IntelOpenMP);
+    assert_eq!(
+        mkl_files(&config),
+        vec![lib("mkl_intel_lp64"), lib("mkl_intel_thread"), lib("mkl_core")]
+    );
+    assert!(config.openmp_files().is_empty());
+    let config = MklConfig {
+        static_openmp: true,
+        ..config
+    };
+    assert_eq!(config.openmp_files(), vec![lib("iomp5")]);
+
+    // MKL_THREADING_FALLBACK
+    let fallback = config.sequential();
+    assert_eq!(fallback, MklConfig::new(Interface::LP64, Threading::Sequential));
+    assert!(fallback.openmp_files().is_empty());
+}
+
+#[test]
+fn tbb_ilp64() {
+    let config = MklConfig::new(Interface::ILP64, Threading::Tbb);
+    assert_eq!(
+        mkl_files(&config),
+        vec![lib("mkl_intel_ilp64"), lib("mkl_tbb_thread"), lib("mkl_core")]
+    );
+}
+
+#[test]
+fn default_config() {
+    let config = MklConfig::from_features(false);
+    assert_eq!(config.interface, Interface::LP64);
+    assert_eq!(config.threading, Threading::Sequential);
+    assert_eq!(MklConfig::from_features(true), config);
+}
+
+#[test]
+fn lib_names() {
+    let names = parse_lib_names("intel_lp64=mkl_intel_lp64_patched, core=mkl_core_patched").unwrap();
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    assert_eq!(
+        rename_libraries(&config.libraries(), &names),
+        vec!["mkl_intel_lp64_patched", "mkl_sequential", "mkl_core_patched"]
+    );
+    assert!(parse_lib_names("lapack=mkl_lapack").is_err());
+    assert!(parse_lib_names("core").is_err());
+}
+
+#[test]
+fn find_platform() {
+    assert_eq!(Platform::find("linux").unwrap().subdir, "linux-64");
+    assert_eq!(Platform::find("win-64").unwrap().name, "windows");
+    assert_eq!(Platform::find("windows").unwrap().lib_ext, "lib");
+    assert!(Platform::find("android").is_none());
+}
+
+#[test]
+fn cross_compilation() {
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    // every target other than the host, as cargo sets CARGO_CFG_TARGET_OS for the build script running on the host
+    for target in PLATFORMS.iter().filter(|p| p.name != std::env::consts::OS) {
+        let selected = select_platform(None, Some(target.name)).unwrap();
+        assert_eq!(selected.subdir, target.subdir);
+        assert_ne!(selected.subdir, select_platform(None, None).unwrap().subdir);
+        let files = platform_files(selected, "lib", &config);
+        assert_eq!(files.len(), 3);
+        for file in &files {
+            let name = file.file_name().unwrap().to_str().unwrap();
+            assert!(name.starts_with(target.lib_prefix) && name.ends_with(&format!(".{}", target.lib_ext)));
+        }
+    }
+
+    let windows = select_platform(None, Some("windows")).unwrap();
+    assert_eq!(platform_files(windows, "lib", &config)[2], PathBuf::from("lib").join("mkl_core.lib"));
+    assert_eq!(windows.lib_path, "Library\\lib");
+    let macos = select_platform(None, Some("macos")).unwrap();
+    assert_eq!(platform_files(macos, "lib", &config)[2], PathBuf::from("lib").join("libmkl_core.a"));
+    assert_eq!(macos.builds[0].2, "74a186a5e325146c7de7e1e1c8fc3bc3");
+
+    // MKL_TARGET_OVERRIDE wins over the target
+    assert_eq!(select_platform(Some("win-64"), Some("linux")).unwrap().name, "windows");
+    assert!(select_platform(Some("android"), Some("linux")).is_err());
+    assert_eq!(select_platform(None, Some("android")).unwrap_err(), "MKL is not available for android");
+}
+
+#[test]
+fn verified_libraries_source() {
+    let verified = vec![(PathBuf::from("/out/lib/libmkl_core.a"), "0123".to_string())];
+    assert_eq!(verified_source(&verified), "&[(\"/out/lib/libmkl_core.a\", \"0123\")]\n");
+    assert_eq!(verified_source(&[]), "&[]\n");
+}
+
+#[test]
+fn otool_output() {
+    let id = "/opt/conda/lib/libmkl_rt.dylib:\n/opt/conda/lib/libmkl_rt.dylib\n";
+    assert_eq!(parse_otool_id(id).as_deref(), Some("/opt/conda/lib/libmkl_rt.dylib"));
+    assert_eq!(parse_otool_id("libmkl_rt.dylib:\n"), None);
+
+    let deps = "libmkl_rt.dylib:\n\
+                \t@rpath/libmkl_rt.dylib (compatibility version 0.0.0, current version 0.0.0)\n\
+                \t/opt/conda/lib/libiomp5.dylib (compatibility version 5.0.0, current version 5.0.0)\n\
+                \t/usr/lib/libSystem.B.dylib (compatibility version 1.0.0, current version 1252.0.0)\n";
+    assert_eq!(
+        parse_otool_deps(deps),
+        vec![
+            "@rpath/libmkl_rt.dylib",
+            "/opt/conda/lib/libiomp5.dylib",
+            "/usr/lib/libSystem.B.dylib"
+        ]
+    );
+}
+
+#[test]
+fn link_order() {
+    let lib_dir = PathBuf::from("/mkl/lib");
+    let libs = |config: &MklConfig| -> Vec<String> { config.libraries().iter().map(|l| l.to_string()).collect() };
+    let link_lines = |config: &MklConfig, target_os: &str, extra_args: Option<&str>| -> Vec<String> {
+        link_directives(config, &lib_dir, None, None, &libs(config), target_os, extra_args)
+            .iter()
+            .filter(|d| d.is_link())
+            .map(|d| d.to_string())
+            .collect()
+    };
+
+    let config = MklConfig::new(Interface::ILP64, Threading::IntelOpenMP);
+    assert_eq!(
+        link_lines(&config, "linux", Some("-Wl,--no-as-needed")),
+        vec![
+            "rustc-link-search=native=/mkl/lib",
+            "rustc-link-lib=static=mkl_intel_ilp64",
+            "rustc-link-lib=static=mkl_intel_thread",
+            "rustc-link-lib=static=mkl_core",
+            "rustc-link-lib=dylib=iomp5",
+            "rustc-link-arg=-Wl,--no-as-needed",
+        ]
+    );
+
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    assert_eq!(
+        link_lines(&config, "linux", None),
+        vec![
+            "rustc-link-search=native=/mkl/lib",
+            "rustc-link-lib=static=mkl_intel_lp64",
+            "rustc-link-lib=static=mkl_sequential",
+            "rustc-link-lib=static=mkl_core",
+        ]
+    );
+
+    // The static layers are bundled into the rlib, which the linker rescans as one archive,
+    // so that no group is emitted, and `rustc-link-arg` would not reach the dependents anyway
+    for threading in [Threading::Sequential, Threading::IntelOpenMP, Threading::GnuOpenMP, Threading::Tbb] {
+        let config = MklConfig::new(Interface::LP64, threading);
+        let lines = link_lines(&config, "linux", None);
+        assert!(lines.iter().all(|line| !line.contains("-group")), "{:?}", lines);
+        let layers: Vec<_> = lines.iter().filter(|line| line.contains("=mkl_")).collect();
+        assert_eq!(layers.len(), 3, "{:?}", lines);
+        assert!(layers.iter().all(|line| line.starts_with("rustc-link-lib=static=")), "{:?}", lines);
+    }
+
+    // blas-only
+    let config = MklConfig {
+        blas_only: true,
+        ..MklConfig::new(Interface::LP64, Threading::Sequential)
+    };
+    let gc_arg = |target_os| link_lines(&config, target_os, Some("-Wl,--no-as-needed"))[4..].to_vec();
+    assert_eq!(gc_arg("linux"), vec!["rustc-link-arg=-Wl,--gc-sections", "rustc-link-arg=-Wl,--no-as-needed"]);
+    assert_eq!(gc_arg("macos")[0], "rustc-link-arg=-Wl,-dead_strip");
+    assert_eq!(gc_arg("windows")[0], "rustc-link-arg=/OPT:REF");
+
+    let config = MklConfig {
+        rt: true,
+        ..MklConfig::new(Interface::LP64, Threading::Tbb)
+    };
+    assert_eq!(
+        link_lines(&config, "macos", None),
+        vec![
+            "rustc-link-search=native=/mkl/lib",
+            "rustc-link-lib=dylib=mkl_rt",
+            "rustc-link-arg=-Wl,-rpath,/mkl/lib",
+        ]
+    );
+
+    // MKL_RUNTIME_DIR
+    let runtime_dir = PathBuf::from("/opt/intel/mkl/lib");
+    let rpath = |target_os: &str| -> Vec<String> {
+        link_directives(&config, &lib_dir, None, Some(&runtime_dir), &libs(&config), target_os, None)
+            .iter()
+            .filter(|d| matches!(d, Directive::LinkArg(_)))
+            .map(|d| d.to_string())
+            .collect()
+    };
+    assert_eq!(rpath("linux"), vec!["rustc-link-arg=-Wl,-rpath,/opt/intel/mkl/lib"]);
+    assert_eq!(rpath("macos"), vec!["rustc-link-arg=-Wl,-rpath,/opt/intel/mkl/lib"]);
+    assert!(rpath("windows").is_empty());
+}
+
+#[test]
+fn config_after_link() {
+    let lib_dir = PathBuf::from("/mkl/lib");
+    let config = MklConfig::new(Interface::ILP64, Threading::Sequential);
+    let libs: Vec<String> = config.libraries().iter().map(|l| l.to_string()).collect();
+    let directives = link_directives(&config, &lib_dir, None, None, &libs, "linux", None);
+    // the configuration follows the link directives
+    let first_config = directives.iter().position(|d| !d.is_link()).unwrap();
+    assert!(directives[first_config..].iter().all(|d| !d.is_link()));
+    assert_eq!(
+        directives[first_config..]
+            .iter()
+            .filter(|d| !matches!(d, Directive::CheckCfg(_)))
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>(),
+        vec![
+            "rustc-cfg=mkl_interface=\"ilp64\"",
+            "rustc-cfg=mkl_threading=\"sequential\"",
+            "interface=ilp64",
+            "threading=sequential",
+            "link=static",
+            "libdir=/mkl/lib",
+        ]
+    );
+    // no libdir without libraries, e.g. MKL_HEADERS_ONLY
+    assert!(!config_directives(&config, None).iter().any(|d| matches!(d, Directive::Metadata("libdir", _))));
+}
+
+#[test]
+fn supported_targets() {
+    validate_target("linux", "x86_64", "64");
+    validate_target("macos", "x86_64", "64");
+    validate_target("windows", "x86_64", "64");
+}
+
+#[test]
+#[should_panic(expected = "32-bit targets are not supported")]
+fn reject_i686() {
+    validate_target("linux", "x86", "32");
+}
+
+#[test]
+#[should_panic(expected = "32-bit targets are not supported")]
+fn reject_x32() {
+    validate_target("linux", "x86_64", "32");
+}
+
+#[test]
+fn other_blas() {
+    let vars = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    };
+    assert!(other_blas_signals(vars(&[
+        ("DEP_MKL_INTEL_LP64_INTERFACE", "lp64"),
+        ("RUSTFLAGS", "-C target-cpu=native -lm"),
+        ("MKL_EXTRA_LINK_ARGS", "-Wl,--no-as-needed"),
+    ]))
+    .is_empty());
+    assert_eq!(
+        other_blas_signals(vars(&[
+            ("DEP_OPENBLAS_INCLUDE", "/usr/include"),
+            ("CARGO_ENCODED_RUSTFLAGS", "-C\x1flink-arg=-s\x1f-l\x1fdylib=openblas"),
+            ("RUSTFLAGS", "-lframework=Accelerate"),
+        ])),
+        vec!["CARGO_ENCODED_RUSTFLAGS", "DEP_OPENBLAS_INCLUDE", "RUSTFLAGS"]
+    );
+}
+
+#[test]
+fn insecure_tls_only_for_mirror() {
+    let mirror = Some("https://mirror.internal/intel/");
+    let uri = "https://mirror.internal/intel/linux-64/repodata.json";
+    assert!(insecure_tls(uri, mirror, true));
+    assert!(!insecure_tls(uri, mirror, false));
+    assert!(!insecure_tls(uri, None, true));
+    assert!(!insecure_tls("https://mirror.internal/intel-other/repodata.json", Some("https://mirror.internal/intel"), true));
+    // never for the default channel
+    let channel = "https://conda.anaconda.org/intel";
+    assert!(!insecure_tls(&format!("{}/linux-64/repodata.json", channel), Some(channel), true));
+    assert!(!insecure_tls("https://conda.anaconda.org/intel/linux-64/repodata.json", mirror, true));
+}
+
+#[test]
+fn fingerprint() {
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    let sums = |files: &[(&str, &str)]| -> Vec<(String, String)> {
+        files.iter().map(|(f, s)| (f.to_string(), s.to_string())).collect()
+    };
+    let a = sums(&[("libmkl_core.a", "aaaa"), ("libmkl_sequential.a", "bbbb")]);
+    let b = sums(&[("libmkl_sequential.a", "bbbb"), ("libmkl_core.a", "aaaa")]);
+    let fingerprint = config_fingerprint(Some("2019.1"), "linux", &config, &a);
+    assert_eq!(fingerprint.len(), 32);
+    // independent of the order
+    assert_eq!(config_fingerprint(Some("2019.1"), "linux", &config, &b), fingerprint);
+
+    let changed = sums(&[("libmkl_core.a", "cccc"), ("libmkl_sequential.a", "bbbb")]);
+    assert_ne!(config_fingerprint(Some("2019.1"), "linux", &config, &changed), fingerprint);
+    assert_ne!(config_fingerprint(Some("2020.0"), "linux", &config, &a), fingerprint);
+    assert_ne!(config_fingerprint(None, "linux", &config, &a), fingerprint);
+    let ilp64 = MklConfig::new(Interface::ILP64, Threading::Sequential);
+    assert_ne!(config_fingerprint(Some("2019.1"), "linux", &ilp64, &a), fingerprint);
+}
+
+#[test]
+fn phase_stats() {
+    use std::time::Duration;
+    assert_eq!(
+        format_phase("download", Duration::from_secs(4), 100 << 20),
+        "download: 4.0 s, 100 MiB, 25.0 MiB/s"
+    );
+    // nothing done in the phase
+    assert_eq!(format_phase("verify", Duration::from_secs(0), 0), "verify: 0.0 s, 0 MiB, 0.0 MiB/s");
+}
+
+#[test]
+fn extra_static_libs() {
+    assert_eq!(
+        parse_extra_static_libs("mykernels, mkl_patch").unwrap(),
+        vec!["mykernels", "mkl_patch"]
+    );
+    assert!(parse_extra_static_libs("").unwrap().is_empty());
+    assert!(parse_extra_static_libs("libfoo.a").is_err());
+    assert!(parse_extra_static_libs("/opt/foo").is_err());
+    assert!(parse_extra_static_libs("static=foo").is_err());
+}
+
+#[test]
+fn redirect_hosts() {
+    let allowed = "conda.anaconda.org, *.blob.core.windows.net";
+    assert!(host_allowed("https://conda.anaconda.org/intel/linux-64/repodata.json", allowed));
+    assert!(host_allowed("https://mkl.blob.core.windows.net/x.tar.bz2?sig=abc", allowed));
+    assert!(host_allowed("https://user@MKL.blob.core.windows.net:443/x", allowed));
+    assert!(!host_allowed("https://blob.core.windows.net.evil.com/x", allowed));
+    assert!(!host_allowed("https://evilblob.core.windows.net/x", allowed));
+    assert!(!host_allowed("https://example.com/?conda.anaconda.org", allowed));
+}
+
+#[test]
+fn config_file() {
+    let path = PathBuf::from("/repo/build.mkl.toml");
+    let content = r#"
+# MKL of the project
+version = "2020.4"  # pinned
+interface = "ilp64"
+cache_dir = "target/mkl"
+"#;
+    let file = ConfigFile::parse(&path, content).unwrap();
+    assert_eq!(file.get("version").as_deref(), Some("2020.4"));
+    assert_eq!(file.get("cache_dir"), Some(PathBuf::from("/repo/target/mkl").display().to_string()));
+    assert_eq!(file.get("threading"), None);
+
+    assert!(ConfigFile::parse(&path, "[mkl]\nversion = \"2020.4\"").is_err());
+    assert!(ConfigFile::parse(&path, "channel = \"https://example.com\"").is_err());
+    assert!(ConfigFile::parse(&path, "version = 2020.4").is_err());
+    assert!(ConfigFile::parse(&path, "version = \"2020.4\"\nversion = \"2021.1\"").is_err());
+    // Any string of TOML
+    let file = ConfigFile::parse(&path, "version = '2020.4'\nmirror = \"https://example.com/conda\\u002Fintel\"").unwrap();
+    assert_eq!(file.get("version").as_deref(), Some("2020.4"));
+    assert_eq!(file.get("mirror").as_deref(), Some("https://example.com/conda/intel"));
+}
+
+#[test]
+fn config_file_path() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("config_file_path");
+    std::fs::create_dir_all(dir.join("vendor")).unwrap();
+    std::fs::write(dir.join("vendor").join("mkl.toml"), "version = \"2020.4\"\n").unwrap();
+
+    // Relative to the manifest directory, not to the working directory
+    let file = ConfigFile::load(Some("vendor/mkl.toml"), &dir).unwrap().unwrap();
+    assert_eq!(file.path, dir.join("vendor").join("mkl.toml"));
+    let absolute = dir.join("vendor").join("mkl.toml");
+    let file = ConfigFile::load(absolute.to_str(), Path::new("/nonexistent")).unwrap().unwrap();
+    assert_eq!(file.get("version").as_deref(), Some("2020.4"));
+
+    assert_eq!(ConfigFile::load(None, &dir), Ok(None));
+    assert!(ConfigFile::load(Some("missing.toml"), &dir).is_err());
+}
+
+#[test]
+fn config_defaults() {
+    let default = MklConfig::new(Interface::LP64, Threading::Sequential);
+    assert_eq!(default.with_defaults(None, None, (false, false)), Ok(default.clone()));
+    assert_eq!(
+        default.with_defaults(Some("ilp64"), Some("tbb"), (false, false)),
+        Ok(MklConfig::new(Interface::ILP64, Threading::Tbb))
+    );
+    assert!(default.with_defaults(Some("i64"), None, (false, false)).is_err());
+
+    // The features take precedence, and must agree
+    assert_eq!(default.with_defaults(Some("lp64"), Some("sequential"), (true, true)), Ok(default.clone()));
+    assert!(default.with_defaults(Some("ilp64"), None, (true, false)).is_err());
+    assert!(default.with_defaults(None, Some("intel-openmp"), (false, true)).is_err());
+}
+
+#[test]
+fn combined_lib() {
+    let name = |path: &str| combined_lib_name(path.as_ref(), "lib", "a");
+    assert_eq!(name("/opt/mkl/libmkl_combined.a").as_deref(), Ok("mkl_combined"));
+    assert_eq!(combined_lib_name("mkl_combined.lib".as_ref(), "", "lib").as_deref(), Ok("mkl_combined"));
+    assert!(name("/opt/mkl/libmkl_combined.so").is_err());
+    assert!(name("/opt/mkl/mkl_combined.a").is_err());
+    assert!(name("/opt/mkl/lib.a").is_err());
+}
+
+#[test]
+fn fortran_runtime() {
+    assert_eq!(FortranRuntime::parse("gnu"), Ok(Some(FortranRuntime::Gnu)));
+    assert_eq!(FortranRuntime::parse("none"), Ok(None));
+    assert!(FortranRuntime::parse("flang").is_err());
+
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("fortran_runtime");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    assert!(fortran_directives(FortranRuntime::Gnu, &dir, "linux").unwrap_err().contains("MKL_FORTRAN_RT_DIR"));
+    std::fs::write(dir.join("libgfortran.so.5"), "").unwrap();
+    assert!(fortran_directives(FortranRuntime::Gnu, &dir, "linux").is_err());
+    std::fs::write(dir.join("libgfortran.so"), "").unwrap();
+    let lines: Vec<String> = fortran_directives(FortranRuntime::Gnu, &dir, "linux")
+        .unwrap()
+        .iter()
+        .map(|d| d.to_string())
+        .collect();
+    assert_eq!(lines, vec![format!("rustc-link-search=native={}", dir.display()), "rustc-link-lib=dylib=gfortran".to_string()]);
+    assert!(fortran_directives(FortranRuntime::Intel, &dir, "linux").is_err());
+    std::fs::write(dir.join("libifcoremd.lib"), "").unwrap();
+    assert!(fortran_directives(FortranRuntime::Intel, &dir, "windows").is_ok());
+}
+
+#[test]
+fn lib_dir_exists() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("lib_dir_exists");
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    let libs: Vec<String> = config.libraries().iter().map(|lib| lib.to_string()).collect();
+    let check = || check_lib_dir(&dir, &libs, false, "linux");
+    assert_eq!(check(), Err(format!("expected libraries at {} but directory is missing", dir.display())));
+    std::fs::create_dir_all(&dir).unwrap();
+    assert_eq!(check(), Err(format!("expected libraries at {} but directory is empty", dir.display())));
+
+    for file in mkl_files(&config).iter().skip(1) {
+        std::fs::write(dir.join(file.file_name().unwrap()), "").unwrap();
+    }
+    assert!(check().unwrap_err().ends_with("mkl_intel_lp64 is not found"));
+    std::fs::write(dir.join(mkl_files(&config)[0].file_name().unwrap()), "").unwrap();
+    assert_eq!(check(), Ok(()));
+}
+
+#[test]
+fn variants() {
+    assert_eq!(
+        parse_variants("lp64-sequential, ilp64-intel-openmp,lp64-sequential"),
+        Ok(vec![
+            MklConfig::new(Interface::LP64, Threading::Sequential),
+            MklConfig::new(Interface::ILP64, Threading::IntelOpenMP),
+        ])
+    );
+    assert_eq!(parse_variants(""), Ok(Vec::new()));
+    assert!(parse_variants("ilp64").is_err());
+    assert!(parse_variants("ilp64-openmp").is_err());
+
+    let config = MklConfig::new(Interface::ILP64, Threading::Tbb);
+    assert_eq!(variants_source(&[]), "&[]\n");
+    assert_eq!(
+        variants_source(&[(config, PathBuf::from("/out/mkl-variants/ilp64-tbb/lib"))]),
+        "&[(\"ilp64\", \"tbb\", \"/out/mkl-variants/ilp64-tbb/lib\")]\n"
+    );
+}
+
+#[test]
+fn source_priority() {
+    assert_eq!(parse_source_priority("system,pip,cache,download").unwrap(), DEFAULT_SOURCE_PRIORITY);
+    assert_eq!(parse_source_priority(" cache, system ,").unwrap(), vec![Source::Cache, Source::System]);
+    assert!(parse_source_priority("system,conda").unwrap_err().contains("`conda`"));
+    assert!(parse_source_priority("cache,cache").is_err());
+    assert!(parse_source_priority("").is_err());
+}
+
+#[test]
+fn normalized_dir() {
+    assert_eq!(strip_verbatim(r"\\?\C:\target\out".as_ref()), PathBuf::from(r"C:\target\out"));
+    assert_eq!(strip_verbatim(r"\\?\UNC\server\share\out".as_ref()), PathBuf::from(r"\\server\share\out"));
+    assert_eq!(strip_verbatim(r"\\?\GLOBALROOT\Device".as_ref()), PathBuf::from(r"\\?\GLOBALROOT\Device"));
+    assert_eq!(strip_verbatim("/target/out".as_ref()), PathBuf::from("/target/out"));
+
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("normalized_dir");
+    std::fs::create_dir_all(dir.join("out")).unwrap();
+    let normalized = normalize_dir("OUT_DIR", &dir.join("out/../out")).unwrap();
+    assert!(normalized.is_absolute());
+    assert_eq!(normalized.file_name().unwrap(), "out");
+    assert!(!normalized.components().any(|c| c == std::path::Component::ParentDir));
+
+    std::fs::write(dir.join("file"), "").unwrap();
+    assert!(normalize_dir("OUT_DIR", &dir.join("file")).unwrap_err().ends_with("is not a directory"));
+    assert!(normalize_dir("OUT_DIR", &dir.join("missing")).unwrap_err().contains("is not found"));
+}
+
+#[test]
+fn link_kinds() {
+    assert_eq!(
+        parse_link_kinds("core=static, sequential=dynamic").unwrap(),
+        vec![("mkl_core".to_string(), "static"), ("mkl_sequential".to_string(), "dylib")]
+    );
+    assert!(parse_link_kinds("core").is_err());
+    assert!(parse_link_kinds("core=shared").unwrap_err().contains("`shared`"));
+    assert!(parse_link_kinds("rt=dynamic").is_err());
+    assert!(parse_link_kinds("core=static,core=dynamic").is_err());
+    assert_eq!(shared_lib_name("mkl_sequential", "linux"), "libmkl_sequential.so");
+    assert_eq!(shared_lib_name("mkl_sequential", "macos"), "libmkl_sequential.dylib");
+
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    let libs = |config: &MklConfig| -> Vec<String> { config.libraries().iter().map(|l| l.to_string()).collect() };
+    let lib_dir = PathBuf::from("/opt/mkl/lib");
+    let mut directives = link_directives(&config, &lib_dir, None, None, &libs(&config), "linux", Some("-Wl,--as-needed"));
+    apply_link_kinds(&mut directives, &[("mkl_sequential".to_string(), "dylib")], &lib_dir);
+    let link: Vec<String> = directives.iter().filter(|d| d.is_link()).map(|d| d.to_string()).collect();
+    assert_eq!(
+        link,
+        vec![
+            "rustc-link-search=native=/opt/mkl/lib",
+            "rustc-link-lib=static=mkl_intel_lp64",
+            "rustc-link-lib=dylib=mkl_sequential",
+            "rustc-link-lib=static=mkl_core",
+            "rustc-link-arg=-Wl,-rpath,/opt/mkl/lib",
+            "rustc-link-arg=-Wl,--as-needed",
+        ]
+    );
+
+    // all static by default
+    let mut unchanged = link_directives(&config, &lib_dir, None, None, &libs(&config), "linux", None);
+    let expected = unchanged.clone();
+    apply_link_kinds(&mut unchanged, &[("mkl_core".to_string(), "static")], &lib_dir);
+    assert_eq!(unchanged, expected);
+}
+
+#[test]
+fn curl_protocols() {
+    let uri = "https://conda.anaconda.org/intel/linux-64/mkl-static-2019.1-intel_144.tar.bz2";
+    assert_eq!(unsupported_protocol(uri, vec!["http", "https"]), None);
+    assert_eq!(unsupported_protocol(uri, vec!["file", "http"]), Some("https".to_string()));
+    assert_eq!(unsupported_protocol("HTTP://mirror/mkl.tar.bz2", vec!["http"]), None);
+    // the linked libcurl serves the local test server
+    assert_eq!(unsupported_protocol("http://127.0.0.1/mkl.tar.bz2", curl::Version::get().protocols()), None);
+}
+
+#[test]
+fn expected_threading() {
+    assert_eq!(check_expected_threading("sequential", Threading::Sequential), Ok(()));
+    assert_eq!(check_expected_threading("intel-openmp, tbb", Threading::Tbb), Ok(()));
+    assert_eq!(check_expected_threading("", Threading::Tbb), Ok(()));
+    assert!(check_expected_threading("sequential", Threading::IntelOpenMP)
+        .unwrap_err()
+        .starts_with("The `intel-openmp` threading layer is linked, but MKL_EXPECT_THREADING=sequential"));
+    assert!(check_expected_threading("openmp", Threading::Sequential).unwrap_err().contains("`openmp`"));
+}
+
+#[test]
+fn http_version() {
+    assert_eq!(HttpProtocol::parse("auto"), Ok(HttpProtocol::Auto));
+    assert_eq!(HttpProtocol::parse("1.1"), Ok(HttpProtocol::V11));
+    assert_eq!(HttpProtocol::parse("2"), Ok(HttpProtocol::V2));
+    assert_eq!(HttpProtocol::parse("3"), Ok(HttpProtocol::V3));
+    assert!(HttpProtocol::parse("1.0").is_err());
+
+    assert_eq!(HttpProtocol::V3.supported(true, true), HttpProtocol::V3);
+    assert_eq!(HttpProtocol::V3.supported(true, false), HttpProtocol::V2);
+    assert_eq!(HttpProtocol::V3.supported(false, false), HttpProtocol::Auto);
+    assert_eq!(HttpProtocol::V2.supported(false, false), HttpProtocol::Auto);
+    assert_eq!(HttpProtocol::V11.supported(false, false), HttpProtocol::V11);
+}
+
+#[test]
+fn accelerate_fallback() {
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    let lines: Vec<String> = accelerate_directives(&config).iter().map(|d| d.to_string()).collect();
+    assert_eq!(lines[0], "rustc-link-lib=framework=Accelerate");
+    assert!(lines.contains(&"rustc-cfg=mkl_fallback=\"accelerate\"".to_string()));
+    assert!(lines.contains(&"fallback=accelerate".to_string()));
+    // MKL itself is not searched
+    assert!(!lines.iter().any(|line| line.starts_with("rustc-link-search") || line.starts_with("libdir=")));
+}
+
+#[test]
+fn pkg_config_file() {
+    let config = MklConfig::new(Interface::ILP64, Threading::IntelOpenMP);
+    let libs: Vec<String> = config.libraries().iter().map(|l| l.to_string()).collect();
+    let lib_dir = PathBuf::from("/opt/mkl/lib");
+    let directives = link_directives(&config, &lib_dir, None, None, &libs, "linux", None);
+    let pc = pkg_config(&directives, Some("/opt/mkl/include".as_ref()), &config, Some("2019.1"), "linux");
+    assert_eq!(
+        pc,
+        "includedir=/opt/mkl/include\n\n\
+         Name: intel-mkl\n\
+         Description: Intel(R) MKL linked by intel-mkl-src (ilp64, intel-openmp, static)\n\
+         Version: 2019.1\n\
+         Libs: -L/opt/mkl/lib -Wl,--start-group -l:libmkl_intel_ilp64.a -l:libmkl_intel_thread.a -l:libmkl_core.a \
+         -Wl,--end-group -liomp5 -lpthread -lm -ldl\n\
+         Cflags: -I${includedir} -DMKL_ILP64\n"
+    );
+
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    let libs: Vec<String> = config.libraries().iter().map(|l| l.to_string()).collect();
+    let directives = link_directives(&config, &lib_dir, None, None, &libs, "macos", None);
+    let pc = pkg_config(&directives, None, &config, None, "macos");
+    assert!(pc.contains("Version: unknown\n"));
+    assert!(pc.contains("Libs: -L/opt/mkl/lib -lmkl_intel_lp64 -lmkl_sequential -lmkl_core\n"));
+    assert!(pc.ends_with("Cflags: \n"));
+
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("pkg_config_file");
+    std::fs::create_dir_all(dir.join("lib/intel64")).unwrap();
+    std::fs::create_dir_all(dir.join("include")).unwrap();
+    let _ = std::fs::remove_file(dir.join("include/mkl.h"));
+    assert_eq!(find_include_dir(&dir.join("lib/intel64"), &dir.join("out")), None);
+    std::fs::write(dir.join("include/mkl.h"), "").unwrap();
+    assert_eq!(find_include_dir(&dir.join("lib/intel64"), &dir.join("out")), Some(dir.join("include")));
+}
+
+#[test]
+fn strict_abi() {
+    let lines = |interface| -> Vec<String> {
+        abi_directives(&MklConfig::new(interface, Threading::Sequential))
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    };
+    assert_eq!(lines(Interface::LP64), vec!["rustc-cfg=mkl_abi=\"strict\"", "abi_symbol=intel_mkl_interface_lp64"]);
+    assert_eq!(lines(Interface::ILP64)[1], "abi_symbol=intel_mkl_interface_ilp64");
+    // declared even without MKL_STRICT_ABI
+    let config = MklConfig::new(Interface::LP64, Threading::Sequential);
+    assert!(config_directives(&config, None).contains(&Directive::CheckCfg("cfg(mkl_abi, values(\"strict\"))")));
+}
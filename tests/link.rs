@@ -38,3 +38,58 @@ fn link2lapacke() {
         );
     }
 }
+
+// MKL_INT and MKL_UINT of the interface layer
+#[cfg(not(mkl_interface = "ilp64"))]
+type MklInt = i32;
+#[cfg(not(mkl_interface = "ilp64"))]
+type MklUint = u32;
+#[cfg(mkl_interface = "ilp64")]
+type MklInt = i64;
+#[cfg(mkl_interface = "ilp64")]
+type MklUint = u64;
+
+// VSL_BRNG_MCG31 and VSL_RNG_METHOD_UNIFORM_STD in `mkl_vsl_defines.h`
+const VSL_BRNG_MCG31: MklInt = 0x100000;
+const VSL_RNG_METHOD_UNIFORM_STD: MklInt = 0;
+
+extern "C" {
+    fn vdAdd(n: MklInt, a: *const c_double, b: *const c_double, y: *mut c_double);
+    fn vslNewStream(stream: *mut *mut c_void, brng: MklInt, seed: MklUint) -> c_int;
+    fn vdRngUniform(method: MklInt, stream: *mut c_void, n: MklInt, r: *mut c_double, a: c_double, b: c_double) -> c_int;
+    fn vslDeleteStream(stream: *mut *mut c_void) -> c_int;
+}
+
+#[test]
+fn link2vml() {
+    let a = [1.0, 2.0, 3.0];
+    let b = [0.5, -2.0, 4.0];
+    let mut y = [0.0; 3];
+    unsafe {
+        vdAdd(3, a.as_ptr(), b.as_ptr(), y.as_mut_ptr());
+    }
+    assert_eq!(y, [1.5, 0.0, 7.0]);
+}
+
+fn uniform(seed: MklUint) -> Vec<f64> {
+    let mut stream = std::ptr::null_mut();
+    let mut r = vec![0.0; 100];
+    unsafe {
+        assert_eq!(vslNewStream(&mut stream, VSL_BRNG_MCG31, seed), 0);
+        assert_eq!(
+            vdRngUniform(VSL_RNG_METHOD_UNIFORM_STD, stream, r.len() as MklInt, r.as_mut_ptr(), 2.0, 3.0),
+            0
+        );
+        assert_eq!(vslDeleteStream(&mut stream), 0);
+    }
+    r
+}
+
+#[test]
+fn link2vsl() {
+    let r = uniform(777);
+    assert!(r.iter().all(|x| (2.0..3.0).contains(x)));
+    // The same sequence by the same seed
+    assert_eq!(uniform(777), r);
+    assert_ne!(uniform(778), r);
+}
@@ -0,0 +1,177 @@
+// Resolution of the archives using repodata.json of the conda channel
+
+extern crate bzip2;
+extern crate curl;
+extern crate md5;
+extern crate object;
+extern crate serde_json;
+extern crate tar;
+
+#[allow(dead_code)]
+#[path = "../build.rs"]
+mod build;
+
+use build::*;
+use std::fs;
+use std::path::PathBuf;
+
+fn repodata() -> serde_json::Value {
+    serde_json::from_str(
+        r#"{
+  "packages": {
+    "mkl-static-2019.1-intel_144.tar.bz2": {
+      "name": "mkl-static", "version": "2019.1", "build": "intel_144", "build_number": 144,
+      "md5": "37e3a60ff2643cf40b5cf9d2c183588c"
+    },
+    "mkl-static-2019.1-intel_304.tar.bz2": {
+      "name": "mkl-static", "version": "2019.1", "build": "intel_304", "build_number": 304,
+      "md5": "0123456789abcdef0123456789abcdef"
+    },
+    "mkl-static-2019.1-intel_200.tar.bz2": {
+      "name": "mkl-static", "version": "2019.1", "build": "intel_200", "build_number": 200,
+      "md5": "fedcba9876543210fedcba9876543210"
+    },
+    "mkl-static-2019.2-intel_187.tar.bz2": {
+      "name": "mkl-static", "version": "2019.2", "build": "intel_187", "build_number": 187,
+      "md5": "00000000000000000000000000000000"
+    },
+    "mkl-include-2019.1-intel_144.tar.bz2": {
+      "name": "mkl-include", "version": "2019.1", "build": "intel_144", "build_number": 144,
+      "md5": "22222222222222222222222222222222"
+    },
+    "mkl-2019.1-intel_144.tar.bz2": {
+      "name": "mkl", "version": "2019.1", "build": "intel_144", "build_number": 144,
+      "md5": "11111111111111111111111111111111"
+    }
+  },
+  "packages.conda": {
+    "mkl-static-2019.1-intel_144.conda": {
+      "name": "mkl-static", "version": "2019.1", "build": "intel_144", "build_number": 144,
+      "md5": "33333333333333333333333333333333"
+    },
+    "mkl-static-2019.2-intel_200.conda": {
+      "name": "mkl-static", "version": "2019.2", "build": "intel_200", "build_number": 200,
+      "md5": "44444444444444444444444444444444"
+    }
+  }
+}"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn builds_from_latest() {
+    assert_eq!(
+        find_builds(&repodata(), "2019.1"),
+        vec![
+            ("intel_304".to_string(), "0123456789abcdef0123456789abcdef".to_string(), ".tar.bz2"),
+            ("intel_200".to_string(), "fedcba9876543210fedcba9876543210".to_string(), ".tar.bz2"),
+            ("intel_144".to_string(), "37e3a60ff2643cf40b5cf9d2c183588c".to_string(), ".tar.bz2"),
+        ]
+    );
+    assert!(find_builds(&repodata(), "2019.3").is_empty());
+    assert_eq!(
+        find_package_builds(&repodata(), "mkl-include", "2019.1"),
+        vec![("intel_144".to_string(), "22222222222222222222222222222222".to_string(), ".tar.bz2")]
+    );
+    // Only in `packages.conda`, and preceded by the `.tar.bz2` of the same build
+    assert_eq!(
+        find_builds(&repodata(), "2019.2"),
+        vec![
+            ("intel_200".to_string(), "44444444444444444444444444444444".to_string(), ".conda"),
+            ("intel_187".to_string(), "00000000000000000000000000000000".to_string(), ".tar.bz2"),
+        ]
+    );
+}
+
+#[test]
+fn select_package() {
+    // built-in
+    let default = Package::select(None, None);
+    assert_eq!(default.version, "2019.1");
+    assert_eq!(default.build.as_deref(), Some("intel_144"));
+    assert!(default.md5.is_some());
+    assert_eq!(Package::select(Some("2019.1"), Some("intel_144")), default);
+
+    // resolved using repodata.json
+    let pinned = Package::select(Some("2019.1"), Some("intel_304"));
+    assert_eq!(pinned.build.as_deref(), Some("intel_304"));
+    assert_eq!(pinned.md5, None);
+    assert_eq!(pinned.filename(), "mkl-static-2019.1-intel_304.tar.bz2");
+    let latest = Package::select(Some("2020.0"), None);
+    assert_eq!(latest.build, None);
+}
+
+#[test]
+fn version_order() {
+    use std::cmp::Ordering::*;
+    assert_eq!(compare_versions("2021.4", "2020.4"), Some(Greater));
+    assert_eq!(compare_versions("2019.1", "2019.10"), Some(Less));
+    assert_eq!(compare_versions("2021.4.0", "2021.4"), Some(Equal));
+    assert_eq!(compare_versions("2019", "2019.1"), Some(Less));
+    assert_eq!(compare_versions("2019.1", "latest"), None);
+}
+
+#[test]
+fn reuse_recent_repodata() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("reuse_recent_repodata");
+    fs::create_dir_all(&dir).unwrap();
+    let filename = format!("repodata-{}.json", conda_subdir());
+    fs::write(dir.join(filename), repodata().to_string()).unwrap();
+    // does not access the channel
+    assert_eq!(fetch_repodata(&dir).unwrap(), repodata());
+}
+
+/// Manifest of MKL_MANIFEST_URL, keyed by the subdir of the platform
+fn manifest() -> serde_json::Value {
+    serde_json::json!({
+        conda_subdir(): {
+            "2019.1": {
+                "build": "intel_144",
+                "url": "https://mirror.example.com/mkl/mkl-static-2019.1-intel_144.tar.bz2",
+                "md5": "37e3a60ff2643cf40b5cf9d2c183588c",
+                "files": ["lib/libmkl_core.a"]
+            },
+            "2020.0": { "build": "intel_166", "md5": "00000000000000000000000000000000" }
+        }
+    })
+}
+
+#[test]
+fn select_from_manifest() {
+    let core = vec![PathBuf::from("lib/libmkl_core.a")];
+    let listed = Package::from_manifest(&manifest(), "2019.1", None, &core).unwrap().unwrap();
+    assert_eq!(listed.build.as_deref(), Some("intel_144"));
+    assert_eq!(listed.md5.as_deref(), Some("37e3a60ff2643cf40b5cf9d2c183588c"));
+    assert_eq!(listed.uri(), "https://mirror.example.com/mkl/mkl-static-2019.1-intel_144.tar.bz2");
+    assert_eq!(listed.filename(), "mkl-static-2019.1-intel_144.tar.bz2");
+    let mut conda = manifest();
+    conda[conda_subdir()]["2019.1"]["url"] = "https://mirror.example.com/mkl/mkl-static-2019.1-intel_144.conda?sig=1".into();
+    let listed_conda = Package::from_manifest(&conda, "2019.1", None, &core).unwrap().unwrap();
+    assert_eq!(listed_conda.filename(), "mkl-static-2019.1-intel_144.conda");
+    assert_eq!(Package::from_manifest(&manifest(), "2019.1", Some("intel_144"), &core).unwrap(), Some(listed));
+
+    // falls back to the built-ins
+    assert_eq!(Package::from_manifest(&manifest(), "2019.1", Some("intel_304"), &core), Ok(None));
+    assert_eq!(Package::from_manifest(&manifest(), "2019.2", None, &core), Ok(None));
+
+    // broken entries
+    let sequential = vec![PathBuf::from("lib/libmkl_sequential.a")];
+    assert!(Package::from_manifest(&manifest(), "2019.1", None, &sequential).is_err());
+    assert!(Package::from_manifest(&manifest(), "2020.0", None, &core).is_err());
+}
+
+#[test]
+fn reuse_recent_manifest() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("reuse_recent_manifest");
+    fs::create_dir_all(&dir).unwrap();
+    // unreachable, and does not match the source of the previous one
+    let url = "http://127.0.0.1:9/manifest.json";
+    fs::write(dir.join("mkl-manifest-url.json"), manifest().to_string()).unwrap();
+    fs::write(dir.join("mkl-manifest-url.txt"), "http://127.0.0.1:9/other.json").unwrap();
+    assert!(fetch_manifest(url, &dir).is_err());
+
+    fs::write(dir.join("mkl-manifest-url.json"), manifest().to_string()).unwrap();
+    fs::write(dir.join("mkl-manifest-url.txt"), url).unwrap();
+    assert_eq!(fetch_manifest(url, &dir).unwrap(), manifest());
+}
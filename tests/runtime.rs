@@ -0,0 +1,27 @@
+// Runtime queries to the linked MKL
+
+extern crate intel_mkl_src;
+
+use intel_mkl_src::runtime;
+
+#[test]
+fn version() {
+    let v = runtime::version();
+    assert!(v.major >= 2019, "{:?}", v);
+    let s = runtime::version_string();
+    assert!(s.contains(&v.major.to_string()), "{}", s);
+}
+
+#[test]
+fn verified_libraries() {
+    // MKL_LIB_DIR is not verified
+    if std::env::var_os("MKL_LIB_DIR").is_some() {
+        return;
+    }
+    let verified = intel_mkl_src::verified_libraries();
+    assert!(!verified.is_empty());
+    for (path, md5) in verified {
+        assert!(std::path::Path::new(path).exists(), "{}", path);
+        assert_eq!(md5.len(), 32);
+    }
+}